@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Result};
+
+/// The Adobe APP14 segment, which records the color transform the encoder applied to the
+/// components.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Adobe {
+    /// The version of the segment, such as 100 or 101.
+    pub version: u16,
+    pub flags0: u16,
+    pub flags1: u16,
+
+    /// 0 when the components were coded as is (RGB or CMYK), 1 when converted to YCbCr, and 2
+    /// when converted from CMYK to YCCK.
+    pub transform: u8,
+}
+
+impl Adobe {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"Adobe";
+
+    /// Parses the payload of an APP14 segment that starts with the Adobe identifier.
+    pub(crate) fn parse(segment: &[u8]) -> Result<Self> {
+        let data = segment
+            .strip_prefix(Self::IDENTIFIER)
+            .ok_or(anyhow!("expected an Adobe identifier"))?;
+
+        let [v0, v1, f0, f1, f2, f3, transform, ..] = *data else {
+            return Err(anyhow!(format!(
+                "expected at least 7 bytes in the Adobe segment. Got {}",
+                data.len()
+            )));
+        };
+
+        Ok(Adobe {
+            version: u16::from_be_bytes([v0, v1]),
+            flags0: u16::from_be_bytes([f0, f1]),
+            flags1: u16::from_be_bytes([f2, f3]),
+            transform,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let segment = [b"Adobe".as_slice(), &[0, 100, 0x40, 0, 0, 0, 2]].concat();
+        assert_eq!(
+            Adobe::parse(&segment)?,
+            Adobe {
+                version: 100,
+                flags0: 0x4000,
+                flags1: 0,
+                transform: 2,
+            }
+        );
+
+        assert!(Adobe::parse(&segment[..segment.len() - 1]).is_err());
+        assert!(Adobe::parse(b"Photoshop 3.0\0").is_err());
+
+        Ok(())
+    }
+}
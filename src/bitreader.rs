@@ -1,11 +1,18 @@
-pub struct BitReader<'a> {
-    pub(crate) data: &'a [u8],
+use anyhow::{anyhow, Result};
+
+use crate::decoder::SharedBuffer;
+
+/// Reads the entropy-coded segment most significant bit first, without expanding it. The zero
+/// byte stuffed after every 0xFF data byte (F.1.2.3) is skipped as the reader passes it, while
+/// markers are read as data, so the entropy decoder can resynchronize on restart markers.
+pub struct BitReader {
+    pub(crate) data: SharedBuffer,
     pub(crate) bit_cur: usize,
     pub(crate) byte_cur: usize,
 }
 
-impl<'a> BitReader<'a> {
-    pub(crate) fn new(data: &'a [u8]) -> Self {
+impl BitReader {
+    pub(crate) fn new(data: SharedBuffer) -> Self {
         BitReader {
             data,
             bit_cur: 0,
@@ -13,20 +20,44 @@ impl<'a> BitReader<'a> {
         }
     }
 
-    fn u8_to_bits(&mut self, byte: u8) -> [u8; 8] {
-        let mut bits = [0; 8];
-        for i in 0..8 {
-            bits[i] = (byte >> (7 - i)) & 1;
+    pub(crate) fn read_bit(&mut self) -> Result<u8> {
+        let byte = *self
+            .data
+            .get(self.byte_cur)
+            .ok_or(anyhow!("unexpected end of entropy-coded data"))?;
+
+        let bit = (byte >> (7 - self.bit_cur)) & 1;
+
+        self.bit_cur += 1;
+        if self.bit_cur == 8 {
+            self.next_byte();
+        }
+
+        Ok(bit)
+    }
+
+    pub(crate) fn read_bits(&mut self, num_bits: u8) -> Result<u32> {
+        let mut bits = 0u32;
+        for _ in 0..num_bits {
+            bits = (bits << 1) | self.read_bit()? as u32;
         }
-        bits
+
+        Ok(bits)
     }
 
-    pub(crate) fn slice_to_bits(&mut self) -> Vec<u8> {
-        let mut bits = Vec::with_capacity(self.data.len() * 8);
-        for &byte in self.data {
-            bits.extend_from_slice(&self.u8_to_bits(byte));
+    /// Skips the bits left in the current byte, as done before a marker.
+    pub(crate) fn align(&mut self) {
+        if self.bit_cur > 0 {
+            self.next_byte();
         }
-        bits
+    }
+
+    fn next_byte(&mut self) {
+        let stuffed =
+            self.data[self.byte_cur] == 0xFF && self.data.get(self.byte_cur + 1) == Some(&0);
+
+        self.bit_cur = 0;
+        self.byte_cur += if stuffed { 2 } else { 1 };
     }
 }
 
@@ -37,36 +68,46 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_u8_to_bits() -> Result<()> {
-        let test_cases = vec![
-            (4, vec![0, 0, 0, 0, 0, 1, 0, 0]),
-            (21, vec![0, 0, 0, 1, 0, 1, 0, 1]),
-            (69, vec![0, 1, 0, 0, 0, 1, 0, 1]),
-        ];
+    fn test_read_bit() -> Result<()> {
+        let mut bit_reader = BitReader::new(SharedBuffer::new(vec![4, 21]));
 
-        for (num, expected) in test_cases {
-            let data = vec![num];
-            let mut bit_reader = BitReader::new(&data);
+        let mut got = vec![];
+        for _ in 0..16 {
+            got.push(bit_reader.read_bit()?);
+        }
 
-            let got = bit_reader.u8_to_bits(num);
+        assert_eq!(got, vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 0, 1]);
+        assert!(bit_reader.read_bit().is_err());
 
-            assert_eq!(got.to_vec(), expected)
-        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_bits() -> Result<()> {
+        let mut bit_reader = BitReader::new(SharedBuffer::new(vec![4, 21, 69]));
+
+        assert_eq!(bit_reader.read_bits(3)?, 0);
+        assert_eq!(bit_reader.read_bits(6)?, 0b001000);
+
+        bit_reader.align();
+        assert_eq!(bit_reader.read_bits(8)?, 69);
 
         Ok(())
     }
 
     #[test]
-    fn test_slice_to_bits() -> Result<()> {
-        let data = vec![4, 21, 69];
+    fn test_stuffed_bytes() -> Result<()> {
+        let data = SharedBuffer::new(vec![0xFF, 0x00, 0x12, 0xFF, 0xFF, 0xD0, 0x34]);
+        let mut bit_reader = BitReader::new(data);
 
-        let mut bit_reader = BitReader::new(&data);
-        let got = bit_reader.slice_to_bits();
+        assert_eq!(bit_reader.read_bits(4)?, 0xF);
+        assert_eq!(bit_reader.read_bits(8)?, 0xF1);
+        bit_reader.align();
+        assert_eq!(bit_reader.byte_cur, 3);
 
-        assert_eq!(
-            got,
-            vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 0, 1, 0, 1, 0, 0, 0, 1, 0, 1]
-        );
+        // the fill byte and the restart marker are read as they are.
+        assert_eq!(bit_reader.read_bits(24)?, 0xFFFFD0);
+        assert_eq!(bit_reader.read_bits(8)?, 0x34);
 
         Ok(())
     }
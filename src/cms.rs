@@ -0,0 +1,595 @@
+use anyhow::{anyhow, Result};
+
+use crate::color_spaces;
+use crate::icc::{ColorSpace, IccProfile};
+
+/// (ICC.1 7.2.16) The illuminant of the profile connection space, D50.
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// The number of linear levels the output curves are tabulated at.
+const OUTPUT_LEVELS: usize = 16384;
+
+/// A tone reproduction curve, which maps encoded device values in [0, 1] to linear ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    /// Y = X^g
+    Gamma(f64),
+
+    /// (ICC.1 10.18) One of the five parametric functions, with its parameters g, a, b, c, d,
+    /// e and f. Unused parameters are zero.
+    Parametric { function: u16, parameters: [f64; 7] },
+
+    /// (ICC.1 10.6) Samples spaced evenly over [0, 1], linearly interpolated in between. Without
+    /// samples, the curve is the identity, and a single sample is a constant.
+    Sampled(Vec<f64>),
+}
+
+impl Curve {
+    /// The sRGB curve, as parametric function 3.
+    pub fn srgb() -> Self {
+        Curve::Parametric {
+            function: 3,
+            parameters: [
+                2.4,
+                1.0 / 1.055,
+                0.055 / 1.055,
+                1.0 / 12.92,
+                0.04045,
+                0.0,
+                0.0,
+            ],
+        }
+    }
+
+    /// The linear value of `x`. Parametric functions other than the five known ones, which
+    /// `Transform::new` and `MatrixTrc::to_icc` reject, are taken as the identity.
+    pub fn eval(&self, x: f64) -> f64 {
+        let x = x.clamp(0.0, 1.0);
+
+        let y = match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Parametric {
+                function,
+                parameters: [g, a, b, c, d, e, f],
+            } => {
+                let power = |x: f64| (a * x + b).max(0.0).powf(*g);
+
+                match function {
+                    0 => x.powf(*g),
+                    1 if x >= -b / a => power(x),
+                    1 => 0.0,
+                    2 if x >= -b / a => power(x) + c,
+                    2 => *c,
+                    3 if x >= *d => power(x),
+                    3 => c * x,
+                    4 if x >= *d => power(x) + e,
+                    4 => c * x + f,
+                    _ => x,
+                }
+            }
+            Curve::Sampled(samples) if samples.is_empty() => x,
+            Curve::Sampled(samples) => {
+                let position = x * (samples.len() - 1) as f64;
+                let (index, fraction) = (position.floor() as usize, position.fract());
+
+                match samples.get(index + 1) {
+                    Some(next) => samples[index] + (next - samples[index]) * fraction,
+                    None => samples[index],
+                }
+            }
+        };
+
+        y.clamp(0.0, 1.0)
+    }
+
+    /// Rejects parametric functions other than the five of ICC.1 10.18, which cannot be
+    /// evaluated or written out.
+    fn check(&self) -> Result<()> {
+        match self {
+            Curve::Parametric {
+                function: function @ 5..,
+                ..
+            } => Err(anyhow!(format!(
+                "unknown parametric curve function {function}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finds the encoded value that maps to the linear value `y` by bisection, as the curves
+    /// rise monotonically.
+    fn invert(&self, y: f64) -> f64 {
+        let (mut low, mut high) = (0.0, 1.0);
+
+        for _ in 0..24 {
+            let middle = (low + high) / 2.0;
+            match self.eval(middle) < y {
+                true => low = middle,
+                false => high = middle,
+            }
+        }
+
+        (low + high) / 2.0
+    }
+
+    /// (ICC.1 10.6, 10.18) Reads a curveType or a parametricCurveType tag.
+    fn parse(tag: &[u8]) -> Result<Self> {
+        let truncated = || anyhow!("the ICC curve is cut short");
+        let u16_at = |offset: usize| -> Result<u16> {
+            Ok(u16::from_be_bytes(
+                tag.get(offset..offset + 2)
+                    .ok_or_else(truncated)?
+                    .try_into()?,
+            ))
+        };
+
+        match tag.get(..4) {
+            Some(b"curv") => {
+                let count = u32::from_be_bytes(tag.get(8..12).ok_or_else(truncated)?.try_into()?);
+
+                match count {
+                    0 => Ok(Curve::Gamma(1.0)),
+                    1 => Ok(Curve::Gamma(u16_at(12)? as f64 / 256.0)),
+                    _ => Ok(Curve::Sampled(
+                        (0..count as usize)
+                            .map(|i| Ok(u16_at(12 + 2 * i)? as f64 / 65535.0))
+                            .collect::<Result<_>>()?,
+                    )),
+                }
+            }
+            Some(b"para") => {
+                let function = u16_at(8)?;
+                let num_parameters = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => {
+                        return Err(anyhow!(format!(
+                            "unknown parametric curve function {function}"
+                        )))
+                    }
+                };
+
+                let mut parameters = [0.0; 7];
+                for (i, parameter) in parameters.iter_mut().take(num_parameters).enumerate() {
+                    *parameter =
+                        s15_fixed16(tag.get(12 + 4 * i..16 + 4 * i).ok_or_else(truncated)?);
+                }
+
+                Ok(Curve::Parametric {
+                    function,
+                    parameters,
+                })
+            }
+            Some(signature) => Err(anyhow!(format!(
+                "unsupported ICC curve type {:?}",
+                String::from_utf8_lossy(signature)
+            ))),
+            None => Err(truncated()),
+        }
+    }
+
+    /// Writes the curve as a parametricCurveType, or a curveType when it is sampled.
+    fn encode(&self) -> Result<Vec<u8>> {
+        self.check()?;
+
+        match self {
+            Curve::Sampled(samples) => {
+                let mut tag = b"curv\0\0\0\0".to_vec();
+                tag.extend((samples.len() as u32).to_be_bytes());
+                for sample in samples {
+                    tag.extend(((sample * 65535.0).round() as u16).to_be_bytes());
+                }
+
+                Ok(tag)
+            }
+            Curve::Gamma(g) => Curve::Parametric {
+                function: 0,
+                parameters: [*g, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            }
+            .encode(),
+            Curve::Parametric {
+                function,
+                parameters,
+            } => {
+                let num_parameters = [1, 3, 4, 5, 7][*function as usize];
+
+                let mut tag = b"para\0\0\0\0".to_vec();
+                tag.extend(function.to_be_bytes());
+                tag.extend([0, 0]);
+                for parameter in &parameters[..num_parameters] {
+                    tag.extend(((parameter * 65536.0).round() as i32).to_be_bytes());
+                }
+
+                Ok(tag)
+            }
+        }
+    }
+}
+
+/// (ICC.1 4.6) A signed fixed point number with 16 fractional bits.
+fn s15_fixed16(bytes: &[u8]) -> f64 {
+    i32::from_be_bytes(bytes.try_into().unwrap()) as f64 / 65536.0
+}
+
+/// (ICC.1 F.3) A matrix/TRC profile: a curve per channel that linearizes it, followed by a
+/// matrix whose columns are the XYZ colorants of red, green and blue in the D50 connection
+/// space. Gray profiles are read as three equal channels sharing the gray curve.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixTrc {
+    /// The XYZ of the red, green and blue colorants.
+    pub colorants: [[f64; 3]; 3],
+    pub curves: [Curve; 3],
+}
+
+impl MatrixTrc {
+    /// (IEC 61966-2-1) sRGB, with its primaries adapted to D50.
+    pub fn srgb() -> Self {
+        MatrixTrc {
+            colorants: [
+                [0.4360747, 0.2225045, 0.0139322],
+                [0.3850649, 0.7168786, 0.0971045],
+                [0.1430804, 0.0606169, 0.7141733],
+            ],
+            curves: [Curve::srgb(), Curve::srgb(), Curve::srgb()],
+        }
+    }
+
+    /// Reads the colorant and curve tags of an RGB or gray profile. Profiles built from lookup
+    /// tables, or with a Lab connection space, are rejected.
+    pub fn parse(profile: &IccProfile) -> Result<Self> {
+        if profile.connection_space != ColorSpace::XYZ {
+            return Err(anyhow!(format!(
+                "ICC profiles with a {:?} connection space are not supported",
+                profile.connection_space
+            )));
+        }
+
+        let tag = |signature: &[u8; 4]| {
+            profile.tag(signature).ok_or(anyhow!(format!(
+                "the ICC profile has no {} tag. Only matrix/TRC profiles are supported",
+                String::from_utf8_lossy(signature)
+            )))
+        };
+
+        match profile.color_space {
+            ColorSpace::RGB => {
+                let xyz = |signature: &[u8; 4]| -> Result<[f64; 3]> {
+                    match tag(signature)? {
+                        [b'X', b'Y', b'Z', b' ', _, _, _, _, values @ ..] if values.len() >= 12 => {
+                            Ok([0, 4, 8].map(|offset| s15_fixed16(&values[offset..offset + 4])))
+                        }
+                        _ => Err(anyhow!(format!(
+                            "expected an XYZ type in the {} tag",
+                            String::from_utf8_lossy(signature)
+                        ))),
+                    }
+                };
+
+                Ok(MatrixTrc {
+                    colorants: [xyz(b"rXYZ")?, xyz(b"gXYZ")?, xyz(b"bXYZ")?],
+                    curves: [
+                        Curve::parse(tag(b"rTRC")?)?,
+                        Curve::parse(tag(b"gTRC")?)?,
+                        Curve::parse(tag(b"bTRC")?)?,
+                    ],
+                })
+            }
+            ColorSpace::Gray => {
+                let curve = Curve::parse(tag(b"kTRC")?)?;
+
+                Ok(MatrixTrc {
+                    colorants: [D50.map(|white| white / 3.0); 3],
+                    curves: [curve.clone(), curve.clone(), curve],
+                })
+            }
+            color_space => Err(anyhow!(format!(
+                "ICC profiles of {color_space:?} data are not supported"
+            ))),
+        }
+    }
+
+    /// Writes the profile out as a version 4 RGB display profile.
+    pub fn to_icc(&self) -> Result<Vec<u8>> {
+        let mut tags: Vec<(&[u8; 4], Vec<u8>)> = vec![];
+        for (signature, colorant) in [b"rXYZ", b"gXYZ", b"bXYZ"].into_iter().zip(&self.colorants) {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for value in colorant {
+                tag.extend(((value * 65536.0).round() as i32).to_be_bytes());
+            }
+            tags.push((signature, tag));
+        }
+        for (signature, curve) in [b"rTRC", b"gTRC", b"bTRC"].into_iter().zip(&self.curves) {
+            tags.push((signature, curve.encode()?));
+        }
+
+        let mut data = vec![0u8; 128];
+        data[8..10].copy_from_slice(&[4, 0x30]);
+        data[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        data[36..40].copy_from_slice(b"acsp");
+
+        data.extend((tags.len() as u32).to_be_bytes());
+        let mut offset = data.len() + 12 * tags.len();
+        let mut elements = vec![];
+
+        for (signature, tag) in &tags {
+            data.extend(*signature);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((tag.len() as u32).to_be_bytes());
+
+            // every tag starts on a 4-byte boundary.
+            let padded = tag.len().next_multiple_of(4);
+            elements.extend(tag);
+            elements.resize(elements.len() + padded - tag.len(), 0);
+            offset += padded;
+        }
+        data.extend(elements);
+
+        let size = data.len() as u32;
+        data[..4].copy_from_slice(&size.to_be_bytes());
+
+        Ok(data)
+    }
+}
+
+/// Converts interleaved RGB samples from one matrix/TRC profile to another: the source curves
+/// linearize them, the matrices map them through the connection space, and the inverse of the
+/// target curves encodes them again. Colors outside the target gamut are clipped.
+#[derive(Debug, Clone)]
+pub struct Transform {
+    input: [[f32; 256]; 3],
+    matrix: [[f32; 3]; 3],
+    output: [Vec<u8>; 3],
+}
+
+impl Transform {
+    pub fn new(source: &MatrixTrc, target: &MatrixTrc) -> Result<Self> {
+        for curve in source.curves.iter().chain(&target.curves) {
+            curve.check()?;
+        }
+
+        let inverse = invert(&target.colorants).ok_or(anyhow!(
+            "the colorants of the target profile do not span the connection space"
+        ))?;
+
+        // the colorants are columns, so the product is taken over their transposes.
+        let mut matrix = [[0.0; 3]; 3];
+        for (row, output) in matrix.iter_mut().enumerate() {
+            for (column, coefficient) in output.iter_mut().enumerate() {
+                *coefficient = (0..3)
+                    .map(|k| inverse[k][row] * source.colorants[column][k])
+                    .sum::<f64>() as f32;
+            }
+        }
+
+        let input = [0, 1, 2].map(|c| {
+            let mut table = [0.0; 256];
+            for (x, linear) in table.iter_mut().enumerate() {
+                *linear = source.curves[c].eval(x as f64 / 255.0) as f32;
+            }
+            table
+        });
+
+        let output = [0, 1, 2].map(|c| {
+            (0..OUTPUT_LEVELS)
+                .map(|level| {
+                    let linear = level as f64 / (OUTPUT_LEVELS - 1) as f64;
+                    color_spaces::clamp_to_u8((target.curves[c].invert(linear) * 255.0) as f32)
+                })
+                .collect()
+        });
+
+        Ok(Transform {
+            input,
+            matrix,
+            output,
+        })
+    }
+
+    /// Converts interleaved RGB samples in place.
+    pub fn apply(&self, data: &mut [u8]) {
+        let scale = (OUTPUT_LEVELS - 1) as f32;
+
+        data.chunks_exact_mut(3).for_each(|pixel| {
+            let linear = [0, 1, 2].map(|c| self.input[c][pixel[c] as usize]);
+
+            for (c, sample) in pixel.iter_mut().enumerate() {
+                let row = &self.matrix[c];
+                let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+
+                *sample = self.output[c][(value.clamp(0.0, 1.0) * scale).round() as usize];
+            }
+        });
+    }
+
+    /// Converts gray samples in place, as the neutral pixels whose three channels are equal to
+    /// them. Only the green channel of the result is kept, which the others match to within
+    /// rounding when both profiles map their white to the white of the connection space.
+    pub fn apply_gray(&self, data: &mut [u8]) {
+        let scale = (OUTPUT_LEVELS - 1) as f32;
+        let gain: f32 = self.matrix[1].iter().sum();
+
+        data.iter_mut().for_each(|sample| {
+            let value = self.input[1][*sample as usize] * gain;
+            *sample = self.output[1][(value.clamp(0.0, 1.0) * scale).round() as usize];
+        });
+    }
+}
+
+/// Inverts a matrix, given as its columns, returning the inverse in the same layout. Returns
+/// `None` when the matrix is singular.
+fn invert(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+
+    let determinant: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+
+    let mut inverse = [[0.0; 3]; 3];
+    for (r, row) in inverse.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = cofactor(c, r) / determinant;
+        }
+    }
+
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display_p3() -> MatrixTrc {
+        MatrixTrc {
+            colorants: [
+                [0.515121, 0.241196, -0.001053],
+                [0.291977, 0.692245, 0.041885],
+                [0.157104, 0.066574, 0.784073],
+            ],
+            curves: [Curve::srgb(), Curve::srgb(), Curve::srgb()],
+        }
+    }
+
+    #[test]
+    fn test_curves() -> Result<()> {
+        let srgb = Curve::srgb();
+        assert!((srgb.eval(0.5) - 0.214041).abs() < 1e-5);
+        assert!((srgb.eval(0.02) - 0.02 / 12.92).abs() < 1e-9);
+        assert!((srgb.invert(srgb.eval(0.3)) - 0.3).abs() < 1e-6);
+
+        let gamma = Curve::parse(b"curv\0\0\0\0\0\0\0\x01\x02\x33")?;
+        assert_eq!(gamma, Curve::Gamma(2.19921875));
+
+        let sampled = Curve::parse(b"curv\0\0\0\0\0\0\0\x03\0\0\x40\0\xFF\xFF")?;
+        assert!((sampled.eval(0.25) - 0.125).abs() < 1e-4);
+        assert!((sampled.eval(0.75) - 0.625).abs() < 1e-4);
+
+        // every parametric function writes out and reads back.
+        for function in 0..5 {
+            let Curve::Parametric { parameters, .. } = Curve::srgb() else {
+                unreachable!()
+            };
+            let curve = Curve::Parametric {
+                function,
+                parameters,
+            };
+            let parsed = Curve::parse(&curve.encode()?)?;
+
+            for x in [0.0, 0.01, 0.2, 0.7, 1.0] {
+                assert!((parsed.eval(x) - curve.eval(x)).abs() < 1e-4);
+            }
+        }
+
+        assert!(Curve::parse(b"para\0\0\0\0\0\x05\0\0").is_err());
+        assert!(Curve::parse(b"curv\0\0\0\0\0\0\0\x02\0\0").is_err());
+
+        // curves built by hand are evaluated without panicking, or rejected where they are used.
+        assert_eq!(Curve::Sampled(vec![]).eval(0.3), 0.3);
+        assert_eq!(Curve::Sampled(vec![0.6]).eval(0.3), 0.6);
+
+        let unknown = Curve::Parametric {
+            function: 5,
+            parameters: [1.0; 7],
+        };
+        assert_eq!(unknown.eval(0.3), 0.3);
+        assert!(unknown.encode().is_err());
+
+        let profile = MatrixTrc {
+            curves: [Curve::srgb(), unknown.clone(), Curve::srgb()],
+            ..MatrixTrc::srgb()
+        };
+        assert!(profile.to_icc().is_err());
+        assert!(Transform::new(&profile, &MatrixTrc::srgb()).is_err());
+        assert!(Transform::new(&MatrixTrc::srgb(), &profile).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_profile() -> Result<()> {
+        let p3 = display_p3();
+        let profile = IccProfile::parse(p3.to_icc()?)?;
+        let parsed = MatrixTrc::parse(&profile)?;
+
+        for (parsed, colorant) in parsed.colorants.iter().zip(&p3.colorants) {
+            for (parsed, value) in parsed.iter().zip(colorant) {
+                assert!((parsed - value).abs() < 1e-4);
+            }
+        }
+
+        // the colorants are missing, as in a profile built from lookup tables.
+        let mut data = p3.to_icc()?;
+        data[132..136].copy_from_slice(b"A2B0");
+        let error = MatrixTrc::parse(&IccProfile::parse(data)?).unwrap_err();
+        assert!(error.to_string().contains("rXYZ"));
+
+        // CMYK data, and a Lab connection space.
+        let mut data = p3.to_icc()?;
+        data[16..20].copy_from_slice(b"CMYK");
+        assert!(MatrixTrc::parse(&IccProfile::parse(data)?).is_err());
+
+        let mut data = p3.to_icc()?;
+        data[20..24].copy_from_slice(b"Lab ");
+        assert!(MatrixTrc::parse(&IccProfile::parse(data)?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform() -> Result<()> {
+        let identity = Transform::new(&MatrixTrc::srgb(), &MatrixTrc::srgb())?;
+        let mut data: Vec<u8> = (0..=255).flat_map(|x| [x, 255 - x, x / 2]).collect();
+        let original = data.clone();
+        identity.apply(&mut data);
+
+        for (sample, original) in data.iter().zip(&original) {
+            assert!(sample.abs_diff(*original) <= 1);
+        }
+
+        // sRGB red is well inside the gamut of Display P3, while P3 red is clipped in sRGB.
+        let mut red = [255, 0, 0];
+        Transform::new(&MatrixTrc::srgb(), &display_p3())?.apply(&mut red);
+        for (sample, expected) in red.iter().zip([234, 51, 35]) {
+            assert!(sample.abs_diff(expected) <= 1, "{red:?}");
+        }
+
+        let to_srgb = Transform::new(&display_p3(), &MatrixTrc::srgb())?;
+        let mut pixels = [255, 0, 0, 128, 128, 128];
+        to_srgb.apply(&mut pixels);
+        assert_eq!(pixels[..3], [255, 0, 0]);
+        assert!(pixels[3..].iter().all(|sample| sample.abs_diff(128) <= 1));
+
+        // a gray profile can only be converted from.
+        let gray = MatrixTrc {
+            colorants: [D50.map(|white| white / 3.0); 3],
+            curves: [Curve::Gamma(2.2), Curve::Gamma(2.2), Curve::Gamma(2.2)],
+        };
+        let mut pixel = [100, 100, 100];
+        Transform::new(&gray, &MatrixTrc::srgb())?.apply(&mut pixel);
+        assert!(pixel[0].abs_diff(pixel[1]) <= 1 && pixel[1].abs_diff(pixel[2]) <= 1);
+        assert!(Transform::new(&MatrixTrc::srgb(), &gray).is_err());
+
+        // gray samples are converted as neutral pixels.
+        let mut samples: Vec<u8> = (0..=255).collect();
+        let mut pixels: Vec<u8> = samples.iter().flat_map(|x| [*x; 3]).collect();
+        to_srgb.apply_gray(&mut samples);
+        to_srgb.apply(&mut pixels);
+        for (sample, pixel) in samples.iter().zip(pixels.chunks_exact(3)) {
+            assert!(sample.abs_diff(pixel[1]) <= 1);
+        }
+
+        let mut samples = [0, 100, 255];
+        Transform::new(&gray, &MatrixTrc::srgb())?.apply_gray(&mut samples);
+        assert_eq!([samples[0], samples[2]], [0, 255]);
+        assert!(samples[1].abs_diff(pixel[1]) <= 1);
+
+        Ok(())
+    }
+}
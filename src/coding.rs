@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use crate::huffman_tree::{HuffmanClass, HuffmanTree, NPtr};
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum Operation {
     Sequential,
     Progressive,
@@ -9,7 +10,7 @@ pub(crate) enum Operation {
 
 pub(crate) enum EntropyCoding {
     Huffman(Vec<HuffmanTree>),
-    Arithmetic(Vec<()>),
+    Arithmetic,
 }
 
 /// (table_class, destination_id)
@@ -29,10 +30,12 @@ impl EntropyCoding {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum CodingProcess {
     BaselineDCT,
     ExtendedSequentialDCT,
+    ProgressiveDCT,
+    Lossless,
 }
 
 #[derive(PartialEq)]
@@ -61,10 +64,29 @@ impl CodingProcess {
             },
             CodingProcess::ExtendedSequentialDCT => ProcessSchema {
                 precisions: [true, true],
-                operations: [true, true],
+                operations: [true, false],
+                entropy_coding: [true, true],
+                entropy_table_count: (4, 4),
+            },
+            CodingProcess::ProgressiveDCT => ProcessSchema {
+                precisions: [true, true],
+                operations: [false, true],
                 entropy_coding: [true, true],
                 entropy_table_count: (4, 4),
             },
+            CodingProcess::Lossless => ProcessSchema {
+                precisions: [true, true],
+                operations: [true, false],
+                entropy_coding: [true, true],
+                entropy_table_count: (0, 4),
+            },
+        }
+    }
+
+    pub(crate) fn operation(&self) -> Operation {
+        match self.schema().operations {
+            [_, true] => Operation::Progressive,
+            _ => Operation::Sequential,
         }
     }
 }
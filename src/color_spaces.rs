@@ -1,36 +1,443 @@
-use std::simd::Simd;
+use std::simd::prelude::*;
+use std::simd::{SimdElement, StdFloat};
 
-use crate::color_spaces::ColorSpace::RGB;
+use anyhow::{anyhow, Result};
 
-pub(crate) type MCU = (Simd<f32, 64>, Simd<f32, 64>, Simd<f32, 64>);
+use crate::options::{YCbCrMatrix, YCbCrRange};
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub(crate) enum ColorSpace {
-    YCbCr(f32, f32, f32),
-    RGB(f32, f32, f32),
+/// The number of pixels converted at once.
+const LANES: usize = 16;
+
+/// Rounds a reconstructed sample and clamps it into the range of an 8-bit sample.
+pub(crate) fn clamp_to_u8(sample: f32) -> u8 {
+    sample.round().clamp(0.0, 255.0) as u8
+}
+
+/// A type that converted samples are written out as.
+pub(crate) trait Sample: SimdElement + Default {
+    /// Clamps a sample into [0, max], rounding it first for integer types.
+    fn clamp_from(sample: f32, max: f32) -> Self;
+
+    fn clamp_lanes(samples: Simd<f32, LANES>, max: f32) -> Simd<Self, LANES>;
+}
+
+impl Sample for u8 {
+    fn clamp_from(sample: f32, max: f32) -> Self {
+        sample.round().clamp(0.0, max) as u8
+    }
+
+    fn clamp_lanes(samples: Simd<f32, LANES>, max: f32) -> Simd<Self, LANES> {
+        samples
+            .round()
+            .simd_clamp(Simd::splat(0.0), Simd::splat(max))
+            .cast()
+    }
+}
+
+impl Sample for u16 {
+    fn clamp_from(sample: f32, max: f32) -> Self {
+        sample.round().clamp(0.0, max) as u16
+    }
+
+    fn clamp_lanes(samples: Simd<f32, LANES>, max: f32) -> Simd<Self, LANES> {
+        samples
+            .round()
+            .simd_clamp(Simd::splat(0.0), Simd::splat(max))
+            .cast()
+    }
+}
+
+impl Sample for f32 {
+    fn clamp_from(sample: f32, max: f32) -> Self {
+        sample.clamp(0.0, max)
+    }
+
+    fn clamp_lanes(samples: Simd<f32, LANES>, max: f32) -> Simd<Self, LANES> {
+        samples.simd_clamp(Simd::splat(0.0), Simd::splat(max))
+    }
+}
+
+/// Multiplies every sample by `scale` and writes it out clamped into [0, max], `LANES` samples at
+/// a time.
+pub(crate) fn scale_samples<T: Sample>(samples: &[f32], scale: f32, max: f32, output: &mut [T]) {
+    let len = samples.len().min(output.len());
+    let vectorized = len - len % LANES;
+
+    for x in (0..vectorized).step_by(LANES) {
+        let scaled = Simd::<f32, LANES>::from_slice(&samples[x..]) * Simd::splat(scale);
+        output[x..x + LANES].copy_from_slice(T::clamp_lanes(scaled, max).as_array());
+    }
+
+    output[vectorized..len]
+        .iter_mut()
+        .zip(&samples[vectorized..])
+        .for_each(|(sample, value)| *sample = T::clamp_from(value * scale, max));
+}
+
+/// The multipliers that turn YCbCr samples into RGB ones, derived from the weights of a matrix
+/// and the levels of a range:
+///
+/// R = Y' + cr_r * Cr', G = Y' - cb_g * Cb' - cr_g * Cr', B = Y' + cb_b * Cb'
+///
+/// where Y' = (Y - y_offset) * y_scale, and Cb' and Cr' are centered on zero with the scale of
+/// the range folded into the multipliers. Samples of more than 8 bits have their chroma centered
+/// on 2^(P-1), and the levels of the limited range scaled by 2^(P-8).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct ConversionMatrix {
+    kr: f32,
+    kb: f32,
+    center: f32,
+    max: f32,
+    y_offset: f32,
+    y_scale: f32,
+    cr_r: f32,
+    cb_g: f32,
+    cr_g: f32,
+    cb_b: f32,
+}
+
+impl ConversionMatrix {
+    pub(crate) fn new(matrix: YCbCrMatrix, range: YCbCrRange, bits: u8) -> Result<Self> {
+        let (kr, kb) = matrix.coefficients();
+        if !(kr > 0.0 && kb > 0.0 && kr + kb < 1.0) {
+            return Err(anyhow!(format!(
+                "the luma weights {kr} and {kb} do not leave a positive weight for green"
+            )));
+        }
+
+        let (kr, kb) = (kr as f64, kb as f64);
+        let kg = 1.0 - kr - kb;
+
+        let max = ((1u32 << bits) - 1) as f64;
+        let levels = (1u32 << bits) as f64 / 256.0;
+        let (y_offset, y_scale, c_scale) = match range {
+            YCbCrRange::Full => (0.0, 1.0, 1.0),
+            YCbCrRange::Limited => (
+                16.0 * levels,
+                max / (219.0 * levels),
+                max / (224.0 * levels),
+            ),
+        };
+
+        Ok(ConversionMatrix {
+            kr: kr as f32,
+            kb: kb as f32,
+            center: (1u32 << (bits - 1)) as f32,
+            max: max as f32,
+            y_offset: y_offset as f32,
+            y_scale: y_scale as f32,
+            cr_r: (2.0 * (1.0 - kr) * c_scale) as f32,
+            cb_g: (2.0 * kb * (1.0 - kb) / kg * c_scale) as f32,
+            cr_g: (2.0 * kr * (1.0 - kr) / kg * c_scale) as f32,
+            cb_b: (2.0 * (1.0 - kb) * c_scale) as f32,
+        })
+    }
+
+    /// Expands a luma sample to the full range.
+    pub(crate) fn luma(&self, y: f32) -> f32 {
+        (y - self.y_offset) * self.y_scale
+    }
+
+    /// The luma of full range RGB samples, weighed as in the matrix.
+    pub(crate) fn rgb_luma(&self, r: f32, g: f32, b: f32) -> f32 {
+        self.kr * r + (1.0 - self.kr - self.kb) * g + self.kb * b
+    }
+
+    /// (T.871 7) Converts full range RGB samples into full range YCbCr ones.
+    pub(crate) fn rgb_to_ycbcr(&self, r: f32, g: f32, b: f32) -> [f32; 3] {
+        let y = self.rgb_luma(r, g, b);
+
+        [
+            y,
+            (b - y) / (2.0 * (1.0 - self.kb)) + self.center,
+            (r - y) / (2.0 * (1.0 - self.kr)) + self.center,
+        ]
+    }
+
+    /// The value of chroma without color, 2^(P-1).
+    pub(crate) fn center(&self) -> f32 {
+        self.center
+    }
+
+    /// The largest sample value, 2^P - 1.
+    pub(crate) fn max(&self) -> f32 {
+        self.max
+    }
+
+    pub(crate) fn convert<T: Sample>(&self, y: f32, cb: f32, cr: f32) -> [T; 3] {
+        let (y, cb, cr) = (self.luma(y), cb - self.center, cr - self.center);
+
+        [
+            T::clamp_from(y + self.cr_r * cr, self.max),
+            T::clamp_from(y - self.cb_g * cb - self.cr_g * cr, self.max),
+            T::clamp_from(y + self.cb_b * cb, self.max),
+        ]
+    }
 }
 
-impl ColorSpace {
-    pub(crate) fn convert_ycbcr_to_rgb(image_data: Vec<MCU>) -> Vec<Self> {
-        let mut rgbs = vec![];
+/// Converts a row of YCbCr samples into interleaved RGB samples. The row is converted `LANES`
+/// pixels at a time, and the pixels left over at its end one by one.
+pub(crate) fn convert_ycbcr_to_rgb<T: Sample>(
+    ys: &[f32],
+    cbs: &[f32],
+    crs: &[f32],
+    matrix: &ConversionMatrix,
+    output: &mut [T],
+) {
+    let width = (output.len() / 3)
+        .min(ys.len())
+        .min(cbs.len())
+        .min(crs.len());
+    let vectorized = width - width % LANES;
 
-        image_data.iter().for_each(|(ys, cbs, crs)| {
-            let cbs = cbs - Simd::splat(128.0);
-            let crs = crs - Simd::splat(128.0);
+    let clamp = |samples: Simd<f32, LANES>| T::clamp_lanes(samples, matrix.max).to_array();
+    let center = Simd::splat(matrix.center);
 
-            let rs = ys + Simd::splat(1.402) * crs;
-            let gs = ys - Simd::splat(0.344136) * cbs - Simd::splat(0.714136) * crs;
-            let bs = ys + Simd::splat(1.772) * cbs;
+    for x in (0..vectorized).step_by(LANES) {
+        let y = (Simd::<f32, LANES>::from_slice(&ys[x..]) - Simd::splat(matrix.y_offset))
+            * Simd::splat(matrix.y_scale);
+        let cb = Simd::<f32, LANES>::from_slice(&cbs[x..]) - center;
+        let cr = Simd::<f32, LANES>::from_slice(&crs[x..]) - center;
+
+        let r = clamp(y + Simd::splat(matrix.cr_r) * cr);
+        let g = clamp(y - Simd::splat(matrix.cb_g) * cb - Simd::splat(matrix.cr_g) * cr);
+        let b = clamp(y + Simd::splat(matrix.cb_b) * cb);
+
+        output[x * 3..(x + LANES) * 3]
+            .chunks_exact_mut(3)
+            .enumerate()
+            .for_each(|(lane, pixel)| pixel.copy_from_slice(&[r[lane], g[lane], b[lane]]));
+    }
 
-            rgbs = rs
-                .to_array()
+    output[vectorized * 3..width * 3]
+        .chunks_exact_mut(3)
+        .zip(
+            ys[vectorized..]
                 .iter()
-                .zip(gs.to_array().iter())
-                .zip(bs.to_array().iter())
-                .map(|((r, g), b)| RGB(*r, *g, *b))
-                .collect();
-        });
+                .zip(cbs[vectorized..].iter().zip(&crs[vectorized..])),
+        )
+        .for_each(|(pixel, (y, (cb, cr)))| pixel.copy_from_slice(&matrix.convert(*y, *cb, *cr)));
+}
+
+/// Converts the full-resolution Y, Cb and Cr planes of a `width` pixels wide image into packed
+/// RGB samples, clamped to [0, 255]. Each row of `output` starts `stride` bytes after the
+/// previous one.
+pub fn ycbcr_to_rgb(
+    [ys, cbs, crs]: [&[f32]; 3],
+    width: usize,
+    matrix: YCbCrMatrix,
+    range: YCbCrRange,
+    output: &mut [u8],
+    stride: usize,
+) -> Result<()> {
+    let matrix = ConversionMatrix::new(matrix, range, 8)?;
+
+    let row_bytes = width * 3;
+    if width == 0 || stride < row_bytes {
+        return Err(anyhow!(format!(
+            "a stride of {stride} bytes cannot hold a row of {row_bytes} bytes"
+        )));
+    }
+
+    if !ys.len().is_multiple_of(width) || cbs.len() != ys.len() || crs.len() != ys.len() {
+        return Err(anyhow!(format!(
+            "the planes of {}, {} and {} samples are not whole rows of {width} samples",
+            ys.len(),
+            cbs.len(),
+            crs.len()
+        )));
+    }
+
+    let height = ys.len() / width;
+    if height > 0 && (height - 1) * stride + row_bytes > output.len() {
+        return Err(anyhow!(format!(
+            "an output of {} bytes cannot hold {height} rows of {row_bytes} bytes",
+            output.len()
+        )));
+    }
+
+    for (y, ((ys, cbs), crs)) in ys
+        .chunks_exact(width)
+        .zip(cbs.chunks_exact(width))
+        .zip(crs.chunks_exact(width))
+        .enumerate()
+    {
+        convert_ycbcr_to_rgb(
+            ys,
+            cbs,
+            crs,
+            &matrix,
+            &mut output[y * stride..y * stride + row_bytes],
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert_pixel(y: f32, cb: f32, cr: f32) -> [u8; 3] {
+        let (cb, cr) = (cb - 128.0, cr - 128.0);
+
+        [
+            clamp_to_u8(y + 1.402 * cr),
+            clamp_to_u8(y - 0.344136 * cb - 0.714136 * cr),
+            clamp_to_u8(y + 1.772 * cb),
+        ]
+    }
+
+    #[test]
+    fn test_convert_ycbcr_to_rgb() -> Result<()> {
+        let jfif = ConversionMatrix::new(YCbCrMatrix::BT601, YCbCrRange::Full, 8)?;
+
+        // two vectorized chunks and a remainder, with samples that overshoot the range.
+        let width = 2 * LANES + 5;
+        let ys: Vec<f32> = (0..width).map(|x| (x * 37 % 300) as f32 - 20.0).collect();
+        let cbs: Vec<f32> = (0..width).map(|x| (x * 53 % 256) as f32 + 0.5).collect();
+        let crs: Vec<f32> = (0..width).map(|x| (x * 91 % 256) as f32 - 0.25).collect();
+
+        let mut output = vec![0u8; width * 3];
+        convert_ycbcr_to_rgb(&ys, &cbs, &crs, &jfif, &mut output);
+
+        let expected: Vec<u8> = (0..width)
+            .flat_map(|x| convert_pixel(ys[x], cbs[x], crs[x]))
+            .collect();
+        assert_eq!(output, expected);
+
+        let mut output = [0u8; 6];
+        convert_ycbcr_to_rgb(
+            &[300.0, -40.0],
+            &[128.0; 2],
+            &[128.0; 2],
+            &jfif,
+            &mut output,
+        );
+        assert_eq!(output, [255, 255, 255, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversion_matrices() -> Result<()> {
+        let convert = |matrix, range, pixel: [f32; 3]| -> Result<[u8; 3]> {
+            let matrix = ConversionMatrix::new(matrix, range, 8)?;
+            Ok(matrix.convert(pixel[0], pixel[1], pixel[2]))
+        };
+
+        // black and white sit at 16 and 235 in the limited range.
+        for matrix in [YCbCrMatrix::BT601, YCbCrMatrix::BT709, YCbCrMatrix::BT2020] {
+            let limited = YCbCrRange::Limited;
+            assert_eq!(convert(matrix, limited, [16.0, 128.0, 128.0])?, [0, 0, 0]);
+            assert_eq!(
+                convert(matrix, limited, [235.0, 128.0, 128.0])?,
+                [255, 255, 255]
+            );
+            assert_eq!(
+                convert(matrix, limited, [126.0, 128.0, 128.0])?,
+                [128, 128, 128]
+            );
+        }
+
+        // the primaries, as encoded by each standard.
+        let (full, limited) = (YCbCrRange::Full, YCbCrRange::Limited);
+        assert_eq!(
+            convert(YCbCrMatrix::BT601, full, [76.24, 84.97, 255.5])?,
+            [255, 0, 0]
+        );
+        assert_eq!(
+            convert(YCbCrMatrix::BT709, limited, [62.56, 102.34, 240.0])?,
+            [255, 0, 0]
+        );
+        assert_eq!(
+            convert(YCbCrMatrix::BT709, limited, [172.63, 41.66, 26.27])?,
+            [0, 255, 0]
+        );
+        assert_eq!(
+            convert(YCbCrMatrix::BT709, limited, [31.81, 240.0, 117.73])?,
+            [0, 0, 255]
+        );
+        assert_eq!(
+            convert(YCbCrMatrix::BT2020, full, [66.99, 92.39, 255.5])?,
+            [255, 0, 0]
+        );
+
+        let custom = YCbCrMatrix::Custom {
+            kr: 0.299,
+            kb: 0.114,
+        };
+        assert_eq!(
+            ConversionMatrix::new(custom, full, 8)?,
+            ConversionMatrix::new(YCbCrMatrix::BT601, full, 8)?
+        );
+
+        // RGB converted into YCbCr and back again is unchanged.
+        let jfif = ConversionMatrix::new(YCbCrMatrix::BT601, full, 8)?;
+        for rgb in [[255.0, 0.0, 0.0], [12.0, 200.0, 90.0], [128.0; 3]] {
+            let [y, cb, cr] = jfif.rgb_to_ycbcr(rgb[0], rgb[1], rgb[2]);
+            assert_eq!(
+                jfif.convert::<u8>(y, cb, cr),
+                rgb.map(|sample| sample as u8)
+            );
+        }
+        assert_eq!(jfif.rgb_luma(0.0, 255.0, 0.0).round(), 150.0);
+
+        let invalid = YCbCrMatrix::Custom { kr: 0.6, kb: 0.5 };
+        assert!(ConversionMatrix::new(invalid, full, 8).is_err());
+
+        // 12-bit samples are centered on 2048, and limited range black and white sit at 256
+        // and 3760.
+        let convert = |range, pixel: [f32; 3]| -> Result<[u16; 3]> {
+            let matrix = ConversionMatrix::new(YCbCrMatrix::BT709, range, 12)?;
+            Ok(matrix.convert(pixel[0], pixel[1], pixel[2]))
+        };
+        assert_eq!(convert(full, [1000.0, 2048.0, 2048.0])?, [1000; 3]);
+        assert_eq!(convert(limited, [256.0, 2048.0, 2048.0])?, [0; 3]);
+        assert_eq!(convert(limited, [3760.0, 2048.0, 2048.0])?, [4095; 3]);
+        assert_eq!(convert(full, [5000.0, 0.0, 4095.0])?, [4095, 4095, 1200]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ycbcr_to_rgb() -> Result<()> {
+        let (width, height, stride) = (LANES + 3, 3, 64);
+        let ys: Vec<f32> = (0..width * height).map(|i| (i * 5) as f32).collect();
+        let cbs = vec![90.0; width * height];
+        let crs = vec![170.0; width * height];
+        let (matrix, range) = (YCbCrMatrix::BT601, YCbCrRange::Full);
+
+        let mut output = vec![0xAA; (height - 1) * stride + width * 3];
+        ycbcr_to_rgb([&ys, &cbs, &crs], width, matrix, range, &mut output, stride)?;
+
+        for y in 0..height {
+            let row = &output[y * stride..];
+            for x in 0..width {
+                let i = y * width + x;
+                assert_eq!(row[x * 3..x * 3 + 3], convert_pixel(ys[i], cbs[i], crs[i]));
+            }
+
+            // the padding between rows is left alone.
+            if y + 1 < height {
+                assert!(row[width * 3..stride].iter().all(|sample| *sample == 0xAA));
+            }
+        }
+
+        let planes = [&ys[..], &cbs, &crs];
+        let short = width * 3 - 1;
+        assert!(ycbcr_to_rgb(planes, width, matrix, range, &mut output, short).is_err());
+        assert!(ycbcr_to_rgb(
+            [&ys, &cbs[1..], &crs],
+            width,
+            matrix,
+            range,
+            &mut output,
+            stride
+        )
+        .is_err());
+        assert!(ycbcr_to_rgb(planes, width, matrix, range, &mut output[1..], stride).is_err());
 
-        rgbs
+        Ok(())
     }
 }
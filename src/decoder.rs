@@ -1,31 +1,213 @@
 use std::collections::HashMap;
 use std::fs::File;
+use std::ops::{Deref, Range};
 use std::simd::prelude::*;
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
+use log::warn;
 use memmap::Mmap;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 
+use crate::adobe::Adobe;
 use crate::bitreader::BitReader;
-use crate::coding::{CodingProcess, EntropyCoding};
-use crate::color_spaces::MCU;
+use crate::cms::{MatrixTrc, Transform};
+use crate::coding::{CodingProcess, EntropyCoding, Operation};
 use crate::dequantizer::Dequantizer;
-use crate::entropy_decoder::EntropyDecoder;
-use crate::frame_header::Component;
+use crate::entropy_decoder::{ComponentBlocks, EntropyDecoder};
+use crate::exif::Exif;
+use crate::frame_header::{Component, FrameHeader};
 use crate::huffman_tree::HuffmanClass;
-use crate::idct::IDCT;
+use crate::icc::IccProfile;
+use crate::jfif::JFIF;
 use crate::marker::{Marker, MarkerType};
-use crate::parser::Parser;
+use crate::mpf::{MpEntry, MpIndex};
+use crate::options::{CodedColorSpace, DecoderOptions, OutputColorSpace, ScaleFactor};
+use crate::orientation::Orientation;
+use crate::parser::{MarlenMap, Parser};
+use crate::photoshop::Photoshop;
+use crate::planar::Planes;
+use crate::quantization_table::QuantizationTable;
+use crate::quantize::{IndexedImage, Quantizer};
 use crate::sample_precision::SamplePrecision;
 use crate::scan_header::ScanHeader;
+use crate::scanline_reader::ScanlineReader;
+use crate::tensor::{Tensor, TensorOptions};
+use crate::xmp::Xmp;
 
 type Marlen = (usize, usize); // offset, length
 
+/// Reconstructed samples stored row by row, with the channels of every pixel interleaved.
+#[derive(Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub color_space: OutputColorSpace,
+    pub data: Vec<u8>,
+
+    /// The EXIF orientation that was applied to the samples, so that they are laid out for
+    /// display. `Normal` when none was.
+    pub orientation: Orientation,
+}
+
+impl Image {
+    /// Lays the samples out with `orientation`, if there is one to apply.
+    pub(crate) fn oriented(self, orientation: Option<Orientation>) -> Image {
+        let Some(orientation) = orientation else {
+            return self;
+        };
+
+        let num_channels = self.color_space.num_channels();
+        let (width, height, data) =
+            orientation.apply(self.width, self.height, num_channels, &self.data);
+
+        Image {
+            width,
+            height,
+            data,
+            orientation,
+            ..self
+        }
+    }
+}
+
+/// Reconstructed samples laid out as in `Image`, kept at the precision the image was coded at.
+#[derive(Debug)]
+pub struct Image16 {
+    pub width: usize,
+    pub height: usize,
+    pub color_space: OutputColorSpace,
+
+    /// The nominal number of bits in every sample. Samples range from 0 to 2^bit_depth - 1.
+    pub bit_depth: u8,
+    pub data: Vec<u16>,
+    pub orientation: Orientation,
+}
+
+impl Image16 {
+    /// Lays the samples out with `orientation`, as in `Image::oriented`.
+    pub(crate) fn oriented(self, orientation: Option<Orientation>) -> Image16 {
+        let Some(orientation) = orientation else {
+            return self;
+        };
+
+        let num_channels = self.color_space.num_channels();
+        let (width, height, data) =
+            orientation.apply(self.width, self.height, num_channels, &self.data);
+
+        Image16 {
+            width,
+            height,
+            data,
+            orientation,
+            ..self
+        }
+    }
+}
+
+/// The quantized DCT coefficients of a component, along with what is needed to interpret them.
+#[derive(Debug)]
+pub struct ComponentCoefficients {
+    pub component_id: u8,
+    pub horizontal_sampling_factor: u8,
+    pub vertical_sampling_factor: u8,
+
+    /// The dimensions of the block grid, padded to a whole number of MCUs. Blocks beyond the
+    /// edge of the component that no scan codes are zero.
+    pub blocks_per_line: usize,
+    pub blocks_per_column: usize,
+
+    /// Every block row by row, with its coefficients in natural (row-major) order.
+    pub blocks: Vec<[i16; 64]>,
+
+    /// The quantization table of the component, in natural order.
+    pub quantization_table: [u16; 64],
+}
+
+impl ComponentCoefficients {
+    pub fn block(&self, row: usize, column: usize) -> &[i16; 64] {
+        &self.blocks[row * self.blocks_per_line + column]
+    }
+}
+
+/// A marker segment as it appears in the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment<'a> {
+    /// The second byte of the marker, such as 0xFE for COM.
+    pub marker: u8,
+
+    /// The offset of the marker within the file. The length field follows it.
+    pub offset: usize,
+
+    /// The length of the payload, which excludes the marker and the length field.
+    pub length: usize,
+    pub payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// (B.2.4.5) The text of a COM segment. Comments are not required to be UTF-8, and are read as
+    /// Latin-1 when they are not.
+    pub fn text(&self) -> Option<String> {
+        if self.marker != Marker::COM as u8 {
+            return None;
+        }
+
+        match std::str::from_utf8(self.payload) {
+            Ok(text) => Some(text.to_string()),
+            Err(_) => Some(self.payload.iter().map(|b| *b as char).collect()),
+        }
+    }
+}
+
+/// A window onto a mapped file or a buffer in memory, which the decoders of the images within it
+/// share, as do the parser and the entropy decoder.
+#[derive(Clone)]
+pub(crate) struct SharedBuffer {
+    data: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    range: Range<usize>,
+}
+
+impl SharedBuffer {
+    pub(crate) fn new(data: impl AsRef<[u8]> + Send + Sync + 'static) -> Self {
+        SharedBuffer {
+            range: 0..data.as_ref().len(),
+            data: Arc::new(data),
+        }
+    }
+
+    /// The offset of this window within `other`, which it has to lie in.
+    pub(crate) fn offset_from(&self, other: &SharedBuffer) -> usize {
+        debug_assert!(Arc::ptr_eq(&self.data, &other.data));
+        self.range.start - other.range.start
+    }
+
+    /// The window onto `range` of this one, without copying it.
+    pub(crate) fn slice(&self, range: Range<usize>) -> SharedBuffer {
+        debug_assert!(range.end <= self.range.len());
+
+        SharedBuffer {
+            data: Arc::clone(&self.data),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+}
+
+impl Deref for SharedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.range.clone()]
+    }
+}
+
 pub struct Decoder {
-    pub(crate) mmap: Mmap,
+    pub(crate) buffer: SharedBuffer,
     pub(crate) cursor: usize,
-    pub(crate) encoding: CodingProcess,
+    pub(crate) options: DecoderOptions,
+
+    /// Every marker up to the end of the image, located on first use.
+    markers: Option<MarlenMap>,
 }
 
 impl Decoder {
@@ -34,9 +216,10 @@ impl Decoder {
     pub fn from_file(file: File) -> Result<Self> {
         let mmap = unsafe { Mmap::map(&file)? };
         Ok(Decoder {
-            mmap,
+            buffer: SharedBuffer::new(mmap),
             cursor: 0,
-            encoding: CodingProcess::BaselineDCT,
+            options: DecoderOptions::default(),
+            markers: None,
         })
     }
 
@@ -45,33 +228,335 @@ impl Decoder {
         Decoder::from_file(file)
     }
 
-    pub fn decode(&mut self) -> Result<Vec<MCU>> {
+    /// A decoder for an image already in memory, such as one read from the network. The bytes
+    /// are shared, not copied, by the decoders of the images within them.
+    pub fn from_bytes(bytes: impl AsRef<[u8]> + Send + Sync + 'static) -> Self {
+        Decoder {
+            buffer: SharedBuffer::new(bytes),
+            cursor: 0,
+            options: DecoderOptions::default(),
+            markers: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: DecoderOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Decodes the whole image into a single buffer. With the `auto_orient` option, the image is
+    /// laid out for display according to its EXIF orientation, and with the `color_management`
+    /// option, it is converted to sRGB.
+    pub fn decode(&mut self) -> Result<Image> {
+        let target = self.options.color_management.then(MatrixTrc::srgb);
+        self.decode_into(target.as_ref())
+    }
+
+    /// Decodes the whole image as in `decode`, converting it from its embedded ICC profile to
+    /// `target` whether or not the `color_management` option is set.
+    pub fn decode_to_profile(&mut self, target: &MatrixTrc) -> Result<Image> {
+        self.decode_into(Some(target))
+    }
+
+    fn decode_into(&mut self, target: Option<&MatrixTrc>) -> Result<Image> {
+        let (scanline_reader, orientation) = self.oriented_scanlines(None)?;
+        let mut image = Self::read_image(scanline_reader)?;
+
+        if let Some(target) = target {
+            self.convert_profile(&mut image, target)?;
+        }
+
+        Ok(image.oriented(orientation))
+    }
+
+    /// Decodes the whole image as in `decode`, and maps its RGB pixels to a palette of at most 256
+    /// colors.
+    pub fn decode_indexed(&mut self, quantizer: &Quantizer) -> Result<IndexedImage> {
+        let image = self.decode()?;
+        if image.color_space != OutputColorSpace::RGB {
+            return Err(anyhow!(format!(
+                "palette quantization needs RGB output. Got {:?}",
+                image.color_space
+            )));
+        }
+
+        quantizer.quantize(image.width, image.height, &image.data)
+    }
+
+    /// Converts RGB or grayscale samples from the embedded ICC profile to `target`. Images
+    /// without a profile are taken to be sRGB, and profiles that are not matrix/TRC profiles are
+    /// rejected. Grayscale samples are converted through the curves alone, as neutral pixels.
+    fn convert_profile(&mut self, image: &mut Image, target: &MatrixTrc) -> Result<()> {
+        if image.color_space == OutputColorSpace::YCbCr {
+            return Err(anyhow!("color management needs RGB or grayscale output"));
+        }
+
+        let source = match self.icc_profile()? {
+            Some(profile) => MatrixTrc::parse(&profile)?,
+            None => MatrixTrc::srgb(),
+        };
+
+        if source != *target {
+            let transform = Transform::new(&source, target)?;
+
+            match image.color_space {
+                OutputColorSpace::Grayscale => transform.apply_gray(&mut image.data),
+                _ => transform.apply(&mut image.data),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The EXIF orientation of this decoder's image, if it has a known one.
+    fn exif_orientation(&mut self) -> Result<Option<Orientation>> {
+        let value = self.exif()?.and_then(|exif| exif.orientation());
+
+        match value.map(|value| (value, Orientation::from(value))) {
+            None => Ok(None),
+            Some((_, orientation @ Some(_))) => Ok(orientation),
+            Some((value, None)) => {
+                self.recover(format!("unknown EXIF orientation {value}"))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Prepares the rectangle (x, y, width, height) of the image, or the whole image, to be
+    /// decoded row by row. With the `auto_orient` option, the rectangle is taken in display
+    /// coordinates, and the orientation the decoded rows still have to be laid out with is
+    /// returned along with the reader.
+    fn oriented_scanlines(
+        &mut self,
+        region: Option<(usize, usize, usize, usize)>,
+    ) -> Result<(ScanlineReader, Option<Orientation>)> {
+        let orientation = match self.options.auto_orient {
+            true => self.exif_orientation()?,
+            false => None,
+        };
+
+        let scanline_reader = self.scanlines()?;
+
+        let (Some(region), Some(orientation)) = (region, orientation) else {
+            return match region {
+                Some((x, y, width, height)) => {
+                    Ok((scanline_reader.crop(x, y, width, height)?, orientation))
+                }
+                None => Ok((scanline_reader, orientation)),
+            };
+        };
+
+        let (image_width, image_height) = (scanline_reader.width(), scanline_reader.height());
+        let (display_width, display_height) = match orientation.swaps_dimensions() {
+            true => (image_height, image_width),
+            false => (image_width, image_height),
+        };
+
+        let (x, y, width, height) = region;
+        if width == 0 || height == 0 || x + width > display_width || y + height > display_height {
+            return Err(anyhow!(format!(
+                "the region {width}x{height} at ({x}, {y}) does not fit in a {display_width}x{display_height} image"
+            )));
+        }
+
+        let (x, y, width, height) = orientation.source_region(image_width, image_height, region);
+        Ok((
+            scanline_reader.crop(x, y, width, height)?,
+            Some(orientation),
+        ))
+    }
+
+    /// Decodes the `width` x `height` rectangle whose top left corner is at (`x`, `y`). The
+    /// coordinates are those of the image once scaled by the decoder options. With the
+    /// `auto_orient` option, they are those of the image laid out for display, and so is the
+    /// rectangle.
+    pub fn decode_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<Image> {
+        let (scanline_reader, orientation) =
+            self.oriented_scanlines(Some((x, y, width, height)))?;
+        let mut image = Self::read_image(scanline_reader)?;
+
+        if self.options.color_management {
+            self.convert_profile(&mut image, &MatrixTrc::srgb())?;
+        }
+
+        Ok(image.oriented(orientation))
+    }
+
+    /// Decodes the whole image as in `decode`, with every sample at the precision of the image
+    /// rather than clamped to 8 bits. Color management is only applied to 8-bit output.
+    pub fn decode_u16(&mut self) -> Result<Image16> {
+        if self.options.color_management {
+            return Err(anyhow!("color management is only applied to 8-bit output"));
+        }
+
+        let (scanline_reader, orientation) = self.oriented_scanlines(None)?;
+        Ok(Self::read_image_u16(scanline_reader)?.oriented(orientation))
+    }
+
+    /// Decodes a rectangle of the image as in `decode_region`, at the precision of the image.
+    pub fn decode_region_u16(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<Image16> {
+        if self.options.color_management {
+            return Err(anyhow!("color management is only applied to 8-bit output"));
+        }
+
+        let (scanline_reader, orientation) =
+            self.oriented_scanlines(Some((x, y, width, height)))?;
+        Ok(Self::read_image_u16(scanline_reader)?.oriented(orientation))
+    }
+
+    /// Decodes the image, or the crop of `options`, straight into f32 samples scaled to [0, 1]
+    /// and normalized per channel, without rounding them to integers first. With the
+    /// `auto_orient` option, the image is laid out for display as in `decode`, and the crop is
+    /// taken in display coordinates as in `decode_region`.
+    pub fn decode_tensor(&mut self, options: &TensorOptions) -> Result<Tensor> {
+        if self.options.color_management {
+            return Err(anyhow!("color management is only applied to 8-bit output"));
+        }
+
+        let (mut scanline_reader, orientation) = self.oriented_scanlines(options.crop)?;
+
+        let (width, height, num_channels) = (
+            scanline_reader.width(),
+            scanline_reader.height(),
+            scanline_reader.color_space().num_channels(),
+        );
+
+        // catch a mismatched normalization before decoding anything.
+        options.scales(num_channels)?;
+
+        let stride = scanline_reader.row_bytes();
+        let mut samples = vec![0f32; stride * height];
+        scanline_reader.read_scanlines_f32(&mut samples, stride)?;
+
+        match orientation {
+            Some(orientation) => {
+                let (width, height, samples) =
+                    orientation.apply(width, height, num_channels, &samples);
+                options.tensor((width, height, num_channels), &samples, orientation)
+            }
+            None => options.tensor((width, height, num_channels), &samples, Orientation::Normal),
+        }
+    }
+
+    /// Decodes every component into a plane at the resolution it was coded at, skipping the
+    /// upsampling and color conversion. Only YCbCr and grayscale images are decoded to planes.
+    pub fn decode_planes(&mut self) -> Result<Planes> {
+        self.scanlines()?.read_planes()
+    }
+
+    /// Prepares a rectangle of the image to be decoded row by row, as in `decode_region`. Like
+    /// every scanline, the rectangle is in the stored orientation.
+    pub fn scanlines_in_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<ScanlineReader> {
+        self.scanlines()?.crop(x, y, width, height)
+    }
+
+    fn read_image(mut scanline_reader: ScanlineReader) -> Result<Image> {
+        let (width, height, color_space) = (
+            scanline_reader.width(),
+            scanline_reader.height(),
+            scanline_reader.color_space(),
+        );
+
+        let stride = width * color_space.num_channels();
+        let mut data = vec![0u8; stride * height];
+        scanline_reader.read_scanlines(&mut data, stride)?;
+
+        Ok(Image {
+            width,
+            height,
+            color_space,
+            data,
+            orientation: Orientation::Normal,
+        })
+    }
+
+    fn read_image_u16(mut scanline_reader: ScanlineReader) -> Result<Image16> {
+        let (width, height, color_space) = (
+            scanline_reader.width(),
+            scanline_reader.height(),
+            scanline_reader.color_space(),
+        );
+
+        let stride = scanline_reader.row_bytes();
+        let mut data = vec![0u16; stride * height];
+        scanline_reader.read_scanlines_u16(&mut data, stride)?;
+
+        Ok(Image16 {
+            width,
+            height,
+            color_space,
+            bit_depth: scanline_reader.bit_depth(),
+            data,
+            orientation: Orientation::Normal,
+        })
+    }
+
+    /// Rejects a malformed stream when parsing strictly, otherwise logs it and carries on.
+    fn recover(&self, error: String) -> Result<()> {
+        match self.options.strict {
+            true => Err(anyhow!(error)),
+            false => {
+                warn!("{error}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Prepares the image to be decoded row by row, one MCU row at a time.
+    pub fn scanlines(&mut self) -> Result<ScanlineReader> {
         let parser = self.setup()?;
 
-        let code_schema = self.encoding.schema();
+        if let EntropyCoding::Arithmetic = parser.entropy_coding {
+            return Err(anyhow!("arithmetic coding is not supported"));
+        }
+
+        let code_schema = parser.encoding.schema();
 
-        match self.encoding {
-            CodingProcess::BaselineDCT => {
+        match (parser.encoding, parser.encoding.operation()) {
+            (CodingProcess::Lossless, _) | (_, Operation::Progressive) => Err(anyhow!(
+                "the {:?} coding process is not supported",
+                parser.encoding
+            )),
+            (_, Operation::Sequential) => {
                 let huffman_trees = parser.parse_huffman_trees()?;
                 let quantization_tables = parser.parse_quant_table()?;
                 let frame_header = parser.parse_start_of_frame()?;
                 let (scan_header, encoded_image_start_index) = parser.parse_start_of_scan()?;
-                let compressed_image_data = parser.parse_image_data(encoded_image_start_index)?;
-                let ScanHeader {
-                    scan_component_selectors,
-                    ..
-                } = &scan_header;
-
-                let scan_component_order = scan_component_selectors
-                    .iter()
-                    .map(|c| c.component_id)
-                    .collect::<Vec<_>>();
+                let (entropy_coded_data, restart_offsets) =
+                    parser.entropy_coded_data(encoded_image_start_index)?;
+                let restart_interval = parser.parse_restart_interval()?;
 
                 // validation....
                 if frame_header.component_type != scan_header.component_type {
-                    return Err(anyhow!("header component types do not align."));
+                    self.recover("header component types do not align.".to_string())?;
+                }
+
+                if scan_header.scan_component_selectors.len() != frame_header.components.len() {
+                    return Err(anyhow!(
+                        "expected every component of the frame to be coded in a single scan"
+                    ));
                 }
 
+                self.check_scan(parser.encoding, &scan_header)?;
+
                 let (num_ac_tables, num_dc_tables) =
                     huffman_trees
                         .iter()
@@ -81,228 +566,1603 @@ impl Decoder {
                         });
 
                 let (expected_ac_tables, expected_dc_tables) = code_schema.entropy_table_count;
-                if num_ac_tables != expected_ac_tables || num_dc_tables != expected_dc_tables {
-                    return Err(anyhow!(
-                        "number of ac & dc entropy tables mismatch from expected."
-                    ));
+                if num_ac_tables > expected_ac_tables || num_dc_tables > expected_dc_tables {
+                    self.recover(
+                        "number of ac & dc entropy tables exceeds the expected.".to_string(),
+                    )?;
+                }
+
+                // (A.1.1) baseline images have 8-bit samples, and the extended process adds 12-bit
+                // ones.
+                match (parser.encoding, frame_header.precision) {
+                    (_, SamplePrecision::EightBit)
+                    | (CodingProcess::ExtendedSequentialDCT, SamplePrecision::TwelveBit) => {}
+                    (encoding, precision) => {
+                        return Err(anyhow!(format!(
+                            "the {encoding:?} coding process does not code {}-bit samples",
+                            precision.bits()
+                        )))
+                    }
                 }
 
                 let precisions: Vec<SamplePrecision> =
                     quantization_tables.iter().map(|qt| qt.precision).collect();
 
-                if !precisions.iter().all(|p| *p == SamplePrecision::EightBit) {
-                    return Err(anyhow!(format!(
-                        "expected 8-bit samples within each component. Got {:?}",
-                        &precisions
-                    )));
+                if parser.encoding == CodingProcess::BaselineDCT
+                    && !precisions.iter().all(|p| *p == SamplePrecision::EightBit)
+                {
+                    self.recover(format!(
+                        "expected 8-bit quantization tables. Got {:?}",
+                        precisions
+                    ))?;
                 }
 
-                let mut bit_reader = BitReader::new(&compressed_image_data);
-                let bits = bit_reader.slice_to_bits();
+                let entropy_decoder = EntropyDecoder::new(
+                    BitReader::new(entropy_coded_data),
+                    scan_header,
+                    EntropyCoding::Huffman(huffman_trees),
+                    (restart_interval, restart_offsets),
+                    self.options.strict,
+                    &frame_header,
+                )?;
+
+                let quantization_table_map =
+                    Self::quantization_table_map(&frame_header, &quantization_tables)?;
+
+                let dequantizer = Dequantizer::new(quantization_table_map);
 
-                let mut entropy_decoder =
-                    EntropyDecoder::new(&bits, scan_header, EntropyCoding::Huffman(huffman_trees));
+                let coded_color_space = self.detect_color_space(&frame_header)?;
 
-                let decompressed_image_data = entropy_decoder.decode()?;
-                let mcus = entropy_decoder.zigzag(decompressed_image_data)?;
+                ScanlineReader::new(
+                    frame_header,
+                    entropy_decoder,
+                    dequantizer,
+                    coded_color_space,
+                    self.options,
+                )
+            }
+        }
+    }
+
+    /// Entropy decodes every scan of a sequential or progressive image, without reconstructing
+    /// it. Returns the quantized DCT coefficients of every component in frame order.
+    pub fn read_coefficients(&mut self) -> Result<Vec<ComponentCoefficients>> {
+        let parser = self.setup()?;
 
-                // todo! refactor to this format inside entropy_decoder.
-                let mcus: Vec<_> = mcus
-                    .into_iter()
-                    .map(|mcu| {
-                        let (mut res1, mut res2, mut res3) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        if let EntropyCoding::Arithmetic = parser.entropy_coding {
+            return Err(anyhow!("arithmetic coding is not supported"));
+        }
 
-                        for (idx, &(c1, c2, c3)) in mcu.iter().enumerate() {
-                            res1[idx] = c1;
-                            res2[idx] = c2;
-                            res3[idx] = c3;
-                        }
+        if parser.encoding == CodingProcess::Lossless {
+            return Err(anyhow!(
+                "the lossless coding process has no DCT coefficients"
+            ));
+        }
 
-                        (res1, res2, res3)
-                    })
-                    .collect();
+        let quantization_tables = parser.parse_quant_table()?;
+        let frame_header = parser.parse_start_of_frame()?;
+        let quantization_table_map =
+            Self::quantization_table_map(&frame_header, &quantization_tables)?;
 
-                let mut quantization_table_map = HashMap::new();
+        let mut component_blocks: Vec<_> = frame_header
+            .components
+            .iter()
+            .map(|component| ComponentBlocks::from((0, 0), component.block_grid(&frame_header)))
+            .collect();
 
-                for component in &frame_header.components {
-                    let Component {
-                        component_id,
-                        qt_table_id,
-                        ..
-                    } = component;
+        // every scan uses the tables and restart interval defined before it.
+        for (scan_header, encoded_image_start_index) in parser.parse_start_of_scans()? {
+            self.check_scan(parser.encoding, &scan_header)?;
 
-                    let qt_table = *quantization_tables
-                        .iter()
-                        .find(|qt| qt.table_id == *qt_table_id)
-                        .ok_or(anyhow!(format!(
-                            "failed to find qt table id {}. \n{:?}",
-                            qt_table_id, quantization_tables
-                        )))?;
+            let huffman_trees = parser.parse_huffman_trees_before(encoded_image_start_index)?;
+            let restart_interval =
+                parser.parse_restart_interval_before(encoded_image_start_index)?;
+            let (entropy_coded_data, restart_offsets) =
+                parser.entropy_coded_data(encoded_image_start_index)?;
 
-                    quantization_table_map.insert(*component_id, qt_table);
+            let mut entropy_decoder = EntropyDecoder::new(
+                BitReader::new(entropy_coded_data),
+                scan_header,
+                EntropyCoding::Huffman(huffman_trees),
+                (restart_interval, restart_offsets),
+                self.options.strict,
+                &frame_header,
+            )?;
+
+            entropy_decoder.decode_scan(&frame_header, &mut component_blocks)?;
+        }
+
+        Ok(frame_header
+            .components
+            .iter()
+            .zip(component_blocks)
+            .map(|(component, mut blocks)| {
+                blocks.unzigzag();
+
+                ComponentCoefficients {
+                    component_id: component.component_id,
+                    horizontal_sampling_factor: component.horizontal_scaling_factor,
+                    vertical_sampling_factor: component.vertical_scaling_factor,
+                    blocks_per_line: blocks.blocks_per_line,
+                    blocks_per_column: blocks.blocks_per_column,
+                    blocks: blocks.blocks,
+                    quantization_table: quantization_table_map[&component.component_id]
+                        .quantization_table_element
+                        .to_array(),
                 }
+            })
+            .collect())
+    }
 
-                let mut dequantizer =
-                    Dequantizer::new(&mcus, &scan_component_order, quantization_table_map);
-                let data = dequantizer.dequantize()?;
-                let idct = IDCT::new(precisions[0]);
+    /// Every marker segment up to the end of the image, in the order they appear. Standalone
+    /// markers, such as RSTn, are left out.
+    pub fn segments(&mut self) -> Result<Vec<Segment<'_>>> {
+        let marlen_map = self.markers()?.clone();
+        let data: &[u8] = &self.buffer;
 
-                let mut image_data = vec![];
+        let mut segments: Vec<_> = marlen_map
+            .into_iter()
+            .filter(|(marker, _)| matches!(marker.is_segment(), MarkerType::Segment))
+            .flat_map(|(marker, marlens)| {
+                marlens.into_iter().map(move |(offset, length)| {
+                    let payload = &data[offset.min(data.len())..(offset + length).min(data.len())];
 
-                for block in data {
-                    let (c1, c2, c3) = block;
+                    Segment {
+                        marker: marker as u8,
+                        offset: offset.saturating_sub(Marker::SIZE + 2),
+                        length: payload.len(),
+                        payload,
+                    }
+                })
+            })
+            .collect();
 
-                    let res = vec![c1, c2, c3]
-                        .par_iter()
-                        .map(|component| {
-                            let component = component.cast::<f32>();
-                            let idct = Simd::from_array(idct.perform_idct(component.to_array()));
-                            let level_shift = Simd::splat(128.0);
-                            idct + level_shift
-                        })
-                        .collect::<Vec<_>>();
+        segments.sort_by_key(|segment| segment.offset);
 
-                    image_data.push((res[0], res[1], res[2]) as MCU)
-                }
+        Ok(segments)
+    }
 
-                Ok(image_data)
-            }
-            _ => todo!(),
-        }
+    /// The text of every COM segment, in the order they appear.
+    pub fn comments(&mut self) -> Result<Vec<String>> {
+        Ok(self.segments()?.iter().filter_map(Segment::text).collect())
     }
 
-    fn check_start_of_image(&mut self) -> Result<()> {
-        let start: Simd<u8, 2> =
-            Simd::from_array([self.mmap[self.cursor], self.mmap[self.cursor + 1]]);
+    /// (JFIF 1.02) Reads the JFIF APP0 segment, along with the thumbnails of the JFXX extension
+    /// segments that follow it. Returns `None` when the image has no JFIF segment.
+    pub fn jfif(&mut self) -> Result<Option<JFIF>> {
+        let mut jfif: Option<JFIF> = None;
 
-        match start
-            .simd_eq(Simd::from([Marker::GLOBAL as u8, Marker::SOI as u8]))
-            .all()
-        {
-            true => Ok(()),
-            false => Err(anyhow!("Error, failed to find SOI marker")),
+        for segment in self.segments_of(Marker::APP0)? {
+            if segment.starts_with(JFIF::IDENTIFIER) && jfif.is_none() {
+                match JFIF::parse(&segment) {
+                    Ok(parsed) => jfif = Some(parsed),
+                    Err(e) => self.recover(format!("failed to parse the JFIF segment: {e}"))?,
+                }
+            } else if segment.starts_with(JFIF::EXTENSION_IDENTIFIER) {
+                match (JFIF::parse_extension(&segment), jfif.as_mut()) {
+                    (Ok(thumbnail), Some(jfif)) => jfif.extension_thumbnails.push(thumbnail),
+                    (Ok(_), None) => {
+                        self.recover("a JFXX segment must follow the JFIF segment".to_string())?
+                    }
+                    (Err(e), _) => self.recover(format!("failed to parse a JFXX segment: {e}"))?,
+                }
+            }
         }
+
+        Ok(jfif)
     }
 
-    fn scan_markers(&mut self) -> Result<HashMap<Marker, Vec<Marlen>>> {
-        let mut temp_chunk = [0u8; Self::LANE_COUNT];
-        let mut all_markers = Marker::all();
-        let mut marker_marlen_map = HashMap::new();
+    /// (EXIF 2.32) Reads the TIFF structure of the first EXIF APP1 segment. Returns `None` when
+    /// the image has no EXIF segment.
+    pub fn exif(&mut self) -> Result<Option<Exif>> {
+        Ok(self.parse_exif()?.map(|(exif, _)| exif))
+    }
 
-        while self.cursor < self.mmap.len() {
-            let end = (self.cursor + Self::LANE_COUNT).min(self.mmap.len());
-            let len = end - self.cursor;
+    /// Parses the first EXIF APP1 segment for `exif` and `exif_thumbnail`. Also returns the
+    /// offset of the TIFF header within the buffer, which the offsets of IFD1 are relative to.
+    fn parse_exif(&mut self) -> Result<Option<(Exif, usize)>> {
+        let Some(segment) = self
+            .segments_of(Marker::APP1)?
+            .into_iter()
+            .find(|segment| segment.starts_with(Exif::IDENTIFIER))
+        else {
+            return Ok(None);
+        };
 
-            temp_chunk[..len].copy_from_slice(&self.mmap[self.cursor..end]);
-            let mut curr_chunk = Simd::from_array(temp_chunk);
+        // the identifier comes before the TIFF header.
+        let tiff_offset = segment.offset_from(&self.buffer) + Exif::IDENTIFIER.len();
 
-            let high_marker_mask = Simd::splat(Marker::GLOBAL as u8);
-            let high_marker_matches = curr_chunk.simd_eq(high_marker_mask);
-            if !high_marker_matches.any() {
-                temp_chunk = [0u8; Self::LANE_COUNT];
-                self.cursor += Self::LANE_COUNT - 1;
+        match Exif::parse(&segment) {
+            Ok(exif) => Ok(Some((exif, tiff_offset))),
+            Err(e) => {
+                self.recover(format!("failed to parse the EXIF segment: {e}"))?;
+                Ok(None)
+            }
+        }
+    }
 
-                continue;
+    /// (XMP Part 3 1.1.3) Reads the standard XMP packet, along with the extended packet it refers
+    /// to. Returns `None` when the image has no XMP packet.
+    pub fn xmp(&mut self) -> Result<Option<Xmp>> {
+        let segments = self.segments_of(Marker::APP1)?;
+
+        let Some(segment) = segments
+            .iter()
+            .find(|segment| segment.starts_with(Xmp::IDENTIFIER))
+        else {
+            return Ok(None);
+        };
+
+        let mut xmp = match Xmp::parse(segment) {
+            Ok(xmp) => xmp,
+            Err(e) => {
+                self.recover(format!("failed to parse the XMP segment: {e}"))?;
+                return Ok(None);
             }
+        };
 
-            curr_chunk = curr_chunk.rotate_elements_left::<1>();
+        let chunks: Vec<&[u8]> = segments
+            .iter()
+            .filter(|segment| segment.starts_with(Xmp::EXTENSION_IDENTIFIER))
+            .map(|segment| &**segment)
+            .collect();
 
-            let visited_markers: Vec<_> = all_markers
-                .par_iter()
-                .filter_map(|low_marker| {
-                    let low_marker_mask = Simd::splat(*low_marker as u8);
-                    let low_marker_matches = curr_chunk.simd_eq(low_marker_mask);
-                    !low_marker_matches.any() && return None;
+        if let Err(e) = xmp.assemble_extended(&chunks) {
+            self.recover(format!("failed to read the extended XMP packet: {e}"))?;
+        }
 
-                    let mut marker_matches = high_marker_matches & low_marker_matches;
-                    !marker_matches.any() && return None;
+        Ok(Some(xmp))
+    }
 
-                    let mut local_visited_markers = vec![];
-                    let mut local_marker_marlen_map = vec![];
+    /// Reads the Photoshop image resources of the APP13 segments, decoding the IPTC-IIM records
+    /// among them. Returns `None` when the image has no Photoshop segment.
+    pub fn photoshop(&mut self) -> Result<Option<Photoshop>> {
+        let segments = self.segments_of(Marker::APPD)?;
+        let segments: Vec<&[u8]> = segments
+            .iter()
+            .filter(|segment| segment.starts_with(Photoshop::IDENTIFIER))
+            .map(|segment| &**segment)
+            .collect();
 
-                    while let Some(marker_index) = marker_matches.first_set() {
-                        marker_matches.set(marker_index, false);
+        if segments.is_empty() {
+            return Ok(None);
+        }
 
-                        if low_marker.singleton() {
-                            local_visited_markers.push(*low_marker);
-                        }
+        match Photoshop::parse(&segments) {
+            Ok(photoshop) => Ok(Some(photoshop)),
+            Err(e) => {
+                self.recover(format!("failed to parse the Photoshop segments: {e}"))?;
+                Ok(None)
+            }
+        }
+    }
 
-                        let marker_offset = self.cursor + marker_index;
-                        let segment_offset = marker_offset + Marker::SIZE;
+    /// Writes out the image with its EXIF APP1 segment replaced by `exif`, or removed when it is
+    /// `None`. Without an EXIF segment, it is added after SOI and any JFIF segment. Every other
+    /// segment, and the entropy-coded data, is copied unchanged. Fails when `exif` holds fields
+    /// whose offsets `Exif::to_segment` cannot relocate, such as a maker note.
+    pub fn rewrite_exif(&mut self, exif: Option<&Exif>) -> Result<Vec<u8>> {
+        let segment = match exif {
+            Some(exif) => {
+                let payload = exif.to_segment()?;
+                let length = (payload.len() + 2) as u16;
 
-                        /*
-                        A note about marlen. It contains the offset of the [segment].
-                        Consider the two cases:
+                [
+                    &[Marker::GLOBAL as u8, Marker::APP1 as u8],
+                    &length.to_be_bytes()[..],
+                    &payload,
+                ]
+                .concat()
+            }
+            None => vec![],
+        };
 
-                        Segment Marker:
-                        [SOF][Length][Segment .... (length) ....]
-                                     | <- this is the marlen position
+        let segments = self.segments()?;
+        let end = |segment: &Segment| segment.offset + Marker::SIZE + 2 + segment.length;
 
-                        Standalone Marker:
-                        [SOI][....]
-                             | <- this is the marlen position
+        let existing = segments.iter().find(|segment| {
+            segment.marker == Marker::APP1 as u8 && segment.payload.starts_with(Exif::IDENTIFIER)
+        });
+        let jfif = segments.first().filter(|segment| {
+            segment.marker == Marker::APP0 as u8 && segment.payload.starts_with(JFIF::IDENTIFIER)
+        });
 
-                         */
-                        let segment_marlen = match low_marker.is_segment() {
-                            MarkerType::Segment => (
-                                segment_offset + 2,
-                                u16::from_be_bytes([
-                                    self.mmap[segment_offset],
-                                    self.mmap[segment_offset + 1],
-                                ]) as usize
-                                    - 2,
-                            ),
-                            MarkerType::StandAlone => (segment_offset, 0),
-                        };
+        let replaced = match (existing, jfif) {
+            (Some(existing), _) => existing.offset..end(existing),
+            (None, Some(jfif)) => end(jfif)..end(jfif),
+            (None, None) => Marker::SIZE..Marker::SIZE,
+        };
 
-                        local_marker_marlen_map.push((*low_marker, segment_marlen))
-                    }
+        let data: &[u8] = &self.buffer;
+        Ok([&data[..replaced.start], &segment, &data[replaced.end..]].concat())
+    }
 
-                    Some((local_visited_markers, local_marker_marlen_map))
-                })
-                .collect();
+    /// (CIPA DC-007 5.2) Reads the MP index of the APP2 MPF segment, which locates the other
+    /// images of a multi-picture file. Returns `None` when the image has no MPF segment.
+    pub fn mp_index(&mut self) -> Result<Option<MpIndex>> {
+        let Some(segment) = self
+            .segments_of(Marker::APP2)?
+            .into_iter()
+            .find(|segment| segment.starts_with(MpIndex::IDENTIFIER))
+        else {
+            return Ok(None);
+        };
 
-            for (local_visited_markers, local_marker_marlen_map) in visited_markers {
-                for marker in local_visited_markers {
-                    all_markers.remove(&marker);
-                }
+        // the identifier comes before the TIFF header.
+        let tiff_offset = segment.offset_from(&self.buffer) + MpIndex::IDENTIFIER.len();
 
-                for (marker, segment_marlen) in local_marker_marlen_map {
-                    marker_marlen_map
-                        .entry(marker)
-                        .or_insert_with(Vec::new)
-                        .push(segment_marlen)
-                }
+        match MpIndex::parse(&segment, tiff_offset) {
+            Ok(index) => Ok(Some(index)),
+            Err(e) => {
+                self.recover(format!("failed to parse the MP index: {e}"))?;
+                Ok(None)
             }
-
-            temp_chunk = [0u8; Self::LANE_COUNT];
-            self.cursor += Self::LANE_COUNT - 1;
         }
+    }
 
-        Ok(marker_marlen_map)
+    /// A decoder for an image of the MP index, which shares the buffer of this decoder along
+    /// with its options.
+    pub fn sub_image(&self, entry: &MpEntry) -> Result<Decoder> {
+        self.embedded(entry.offset, entry.size)
     }
 
+    /// A decoder for the JPEG thumbnail of EXIF IFD1, which shares the buffer of this decoder
+    /// along with its options. Returns `None` when the image has no thumbnail.
+    pub fn exif_thumbnail(&mut self) -> Result<Option<Decoder>> {
+        let Some((exif, tiff_offset)) = self.parse_exif()? else {
+            return Ok(None);
+        };
+
+        match exif.thumbnail_location() {
+            Some((offset, length)) => Ok(Some(self.embedded(tiff_offset + offset, length)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes the EXIF thumbnail without reading the entropy-coded data of the main image. When
+    /// there is no thumbnail, the main image is decoded at an eighth of its size instead.
+    pub fn decode_thumbnail(&mut self) -> Result<Image> {
+        if let Some(mut thumbnail) = self.exif_thumbnail()? {
+            // the thumbnail has the orientation of the main image.
+            thumbnail.options.auto_orient = false;
+
+            match thumbnail.decode() {
+                Ok(image) if self.options.auto_orient => {
+                    return Ok(image.oriented(self.exif_orientation()?))
+                }
+                Ok(image) => return Ok(image),
+                Err(e) => self.recover(format!("failed to decode the EXIF thumbnail: {e}"))?,
+            }
+        }
+
+        let options = self.options;
+        self.options = options.scale_factor(ScaleFactor::Eighth);
+        let image = self.decode();
+        self.options = options;
+
+        image
+    }
+
+    /// A decoder for the `size` bytes at `offset`, which shares the buffer of this decoder along
+    /// with its options.
+    fn embedded(&self, offset: usize, size: usize) -> Result<Decoder> {
+        if size < 2 || offset + size > self.buffer.len() {
+            return Err(anyhow!(format!(
+                "the image at offset {offset} of {size} bytes lies outside the file"
+            )));
+        }
+
+        let mut decoder = Decoder {
+            buffer: self.buffer.slice(offset..offset + size),
+            cursor: 0,
+            options: self.options,
+            markers: None,
+        };
+        decoder.check_start_of_image()?;
+
+        Ok(decoder)
+    }
+
+    /// Reads the Adobe APP14 segment. Returns `None` when the image has no Adobe segment.
+    pub fn adobe(&mut self) -> Result<Option<Adobe>> {
+        let Some(segment) = self
+            .segments_of(Marker::APPE)?
+            .into_iter()
+            .find(|segment| segment.starts_with(Adobe::IDENTIFIER))
+        else {
+            return Ok(None);
+        };
+
+        match Adobe::parse(&segment) {
+            Ok(adobe) => Ok(Some(adobe)),
+            Err(e) => {
+                self.recover(format!("failed to parse the Adobe segment: {e}"))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// The color space the components are interpreted in: the `coded_color_space` option when
+    /// set, and otherwise the one libjpeg would detect from the number of components, the JFIF
+    /// and Adobe segments, and the component IDs.
+    pub fn coded_color_space(&mut self) -> Result<CodedColorSpace> {
+        let frame_header = self.setup()?.parse_start_of_frame()?;
+        self.detect_color_space(&frame_header)
+    }
+
+    /// (libjpeg jdapimin.c) Detects the color space of the components from their number, the
+    /// JFIF and Adobe segments, and the component IDs, in that order of precedence:
+    ///
+    /// - one component is grayscale.
+    /// - three components are YCbCr with a JFIF segment, RGB with an Adobe transform of 0 and
+    ///   YCbCr with any other, and otherwise RGB when their IDs are 'R', 'G' and 'B'.
+    /// - four components are CMYK, or YCCK with an Adobe transform other than 0.
+    fn detect_color_space(&mut self, frame_header: &FrameHeader) -> Result<CodedColorSpace> {
+        let num_components = frame_header.components.len();
+
+        if let Some(color_space) = self.options.coded_color_space {
+            return match color_space.num_components() == num_components {
+                true => Ok(color_space),
+                false => Err(anyhow!(format!(
+                    "{color_space:?} cannot be coded in {num_components} components"
+                ))),
+            };
+        }
+
+        let jfif = self
+            .segments_of(Marker::APP0)?
+            .iter()
+            .any(|segment| segment.starts_with(JFIF::IDENTIFIER));
+
+        let transform = self.adobe()?.map(|adobe| adobe.transform);
+        if let Some(transform @ 3..) = transform {
+            self.recover(format!("unknown Adobe transform {transform}"))?;
+        }
+
+        let ids: Vec<u8> = frame_header
+            .components
+            .iter()
+            .map(|component| component.component_id)
+            .collect();
+
+        match (num_components, jfif, transform) {
+            (1, _, _) => Ok(CodedColorSpace::Grayscale),
+            (3, true, _) => Ok(CodedColorSpace::YCbCr),
+            (3, false, Some(0)) => Ok(CodedColorSpace::RGB),
+            (3, false, Some(_)) => Ok(CodedColorSpace::YCbCr),
+            (3, false, None) => match ids[..] {
+                [b'R', b'G', b'B'] => Ok(CodedColorSpace::RGB),
+                _ => Ok(CodedColorSpace::YCbCr),
+            },
+            (4, _, Some(0) | None) => Ok(CodedColorSpace::CMYK),
+            (4, _, Some(_)) => Ok(CodedColorSpace::YCCK),
+            (num_components, _, _) => Err(anyhow!(format!(
+                "the color space of {num_components} components is unknown"
+            ))),
+        }
+    }
+
+    /// (ICC.1 B.4) Reassembles the ICC profile from the APP2 segments it was split across.
+    /// Returns `None` when the image has no ICC profile.
+    pub fn icc_profile(&mut self) -> Result<Option<IccProfile>> {
+        let segments = self.segments_of(Marker::APP2)?;
+        let chunks: Vec<&[u8]> = segments
+            .iter()
+            .filter(|segment| segment.starts_with(IccProfile::IDENTIFIER))
+            .map(|segment| &**segment)
+            .collect();
+
+        if chunks.is_empty() {
+            return Ok(None);
+        }
+
+        match IccProfile::assemble(&chunks) {
+            Ok(profile) => Ok(Some(profile)),
+            Err(e) => {
+                self.recover(format!("failed to read the ICC profile: {e}"))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Checks the spectral selection and successive approximation parameters of a scan against
+    /// the coding process (B.2.3, G.1.1.1).
+    fn check_scan(&self, encoding: CodingProcess, scan_header: &ScanHeader) -> Result<()> {
+        let ScanHeader {
+            start_of_spectral,
+            end_of_spectral,
+            successive_approx_bit_position_high,
+            point_transform,
+            ..
+        } = *scan_header;
+
+        // a band past the 64 coefficients of a block cannot be decoded, whatever the mode.
+        if start_of_spectral > end_of_spectral || end_of_spectral > 63 {
+            return Err(anyhow!(format!(
+                "expected a band of coefficients within 0 to 63. Got Ss={start_of_spectral}, Se={end_of_spectral}"
+            )));
+        }
+
+        match encoding.operation() {
+            Operation::Sequential => {
+                if (start_of_spectral, end_of_spectral) != (0, 63)
+                    || (successive_approx_bit_position_high, point_transform) != (0, 0)
+                {
+                    self.recover(format!(
+                        "a sequential scan codes every coefficient at full precision. Got Ss={start_of_spectral}, Se={end_of_spectral}, Ah={successive_approx_bit_position_high}, Al={point_transform}"
+                    ))?;
+                }
+            }
+            Operation::Progressive => {
+                if (start_of_spectral == 0) != (end_of_spectral == 0) {
+                    return Err(anyhow!(format!(
+                        "a progressive scan codes either the DC coefficient or a band of AC coefficients. Got Ss={start_of_spectral}, Se={end_of_spectral}"
+                    )));
+                }
+
+                if start_of_spectral > 0 && scan_header.scan_component_selectors.len() != 1 {
+                    return Err(anyhow!(
+                        "a scan of AC coefficients codes a single component"
+                    ));
+                }
+
+                if successive_approx_bit_position_high > 13 || point_transform > 13 {
+                    self.recover(format!(
+                        "expected successive approximation bit positions of at most 13. Got Ah={successive_approx_bit_position_high}, Al={point_transform}"
+                    ))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The quantization table of every component, by component id. A table may be redefined, in
+    /// which case the last definition is used.
+    fn quantization_table_map(
+        frame_header: &FrameHeader,
+        quantization_tables: &[QuantizationTable],
+    ) -> Result<HashMap<u8, QuantizationTable>> {
+        let mut quantization_table_map = HashMap::new();
+
+        for component in &frame_header.components {
+            let Component {
+                component_id,
+                qt_table_id,
+                ..
+            } = component;
+
+            let qt_table = *quantization_tables
+                .iter()
+                .rev()
+                .find(|qt| qt.table_id == *qt_table_id)
+                .ok_or(anyhow!(format!(
+                    "failed to find qt table id {}. \n{:?}",
+                    qt_table_id, quantization_tables
+                )))?;
+
+            quantization_table_map.insert(*component_id, qt_table);
+        }
+
+        Ok(quantization_table_map)
+    }
+
+    fn check_start_of_image(&mut self) -> Result<()> {
+        if self.cursor + 2 > self.buffer.len() {
+            return Err(anyhow!("Error, failed to find SOI marker"));
+        }
+
+        let start: Simd<u8, 2> =
+            Simd::from_array([self.buffer[self.cursor], self.buffer[self.cursor + 1]]);
+
+        match start
+            .simd_eq(Simd::from([Marker::GLOBAL as u8, Marker::SOI as u8]))
+            .all()
+        {
+            true => Ok(()),
+            false => Err(anyhow!("Error, failed to find SOI marker")),
+        }
+    }
+
+    fn scan_markers(&mut self) -> Result<MarlenMap> {
+        let mut temp_chunk = [0u8; Self::LANE_COUNT];
+        let all_markers = Marker::all();
+        let mut found_markers = vec![];
+
+        while self.cursor < self.buffer.len() {
+            let end = (self.cursor + Self::LANE_COUNT).min(self.buffer.len());
+            let len = end - self.cursor;
+
+            temp_chunk[..len].copy_from_slice(&self.buffer[self.cursor..end]);
+            let mut curr_chunk = Simd::from_array(temp_chunk);
+
+            let high_marker_mask = Simd::splat(Marker::GLOBAL as u8);
+            let mut high_marker_matches = curr_chunk.simd_eq(high_marker_mask);
+
+            // The last lane wraps around to the first when rotated; the next chunk starts at it.
+            high_marker_matches.set(Self::LANE_COUNT - 1, false);
+
+            if !high_marker_matches.any() {
+                temp_chunk = [0u8; Self::LANE_COUNT];
+                self.cursor += Self::LANE_COUNT - 1;
+
+                continue;
+            }
+
+            curr_chunk = curr_chunk.rotate_elements_left::<1>();
+
+            let visited_markers: Vec<_> = all_markers
+                .par_iter()
+                .filter_map(|low_marker| {
+                    let low_marker_mask = Simd::splat(*low_marker as u8);
+                    let low_marker_matches = curr_chunk.simd_eq(low_marker_mask);
+                    if !low_marker_matches.any() {
+                        return None;
+                    }
+
+                    let mut marker_matches = high_marker_matches & low_marker_matches;
+                    if !marker_matches.any() {
+                        return None;
+                    }
+
+                    let mut local_marker_marlen_map = vec![];
+
+                    while let Some(marker_index) = marker_matches.first_set() {
+                        marker_matches.set(marker_index, false);
+
+                        let marker_offset = self.cursor + marker_index;
+                        let segment_offset = marker_offset + Marker::SIZE;
+
+                        /*
+                        A note about marlen. It contains the offset of the [segment].
+                        Consider the two cases:
+
+                        Segment Marker:
+                        [SOF][Length][Segment .... (length) ....]
+                                     | <- this is the marlen position
+
+                        Standalone Marker:
+                        [SOI][....]
+                             | <- this is the marlen position
+
+                         */
+                        let segment_marlen = match low_marker.is_segment() {
+                            MarkerType::Segment if segment_offset + 1 < self.buffer.len() => (
+                                segment_offset + 2,
+                                (u16::from_be_bytes([
+                                    self.buffer[segment_offset],
+                                    self.buffer[segment_offset + 1],
+                                ]) as usize)
+                                    .saturating_sub(2),
+                            ),
+                            _ => (segment_offset, 0),
+                        };
+
+                        local_marker_marlen_map.push((marker_offset, *low_marker, segment_marlen))
+                    }
+
+                    Some(local_marker_marlen_map)
+                })
+                .collect();
+
+            found_markers.extend(visited_markers.into_iter().flatten());
+
+            temp_chunk = [0u8; Self::LANE_COUNT];
+            self.cursor += Self::LANE_COUNT - 1;
+        }
+
+        // Bytes that look like markers also turn up within the payload of a segment, such as the
+        // thumbnail of an APP1 segment. Only the markers that follow the end of the segment
+        // before them are kept, up to the end of the image.
+        found_markers.sort_by_key(|(marker_offset, _, _)| *marker_offset);
+
+        let mut marker_marlen_map: HashMap<Marker, Vec<Marlen>> = HashMap::new();
+        let mut segment_end = 0;
+
+        for (marker_offset, marker, (offset, length)) in found_markers {
+            if marker_offset < segment_end {
+                continue;
+            }
+
+            segment_end = offset + length;
+            marker_marlen_map
+                .entry(marker)
+                .or_default()
+                .push((offset, length));
+
+            if marker == Marker::EOI {
+                break;
+            }
+        }
+
+        Ok(marker_marlen_map)
+    }
+
+    /// Locates every marker up to the end of the image. The buffer is only scanned the first
+    /// time, and the markers are kept for every later call.
+    fn markers(&mut self) -> Result<&MarlenMap> {
+        let markers = match self.markers.take() {
+            Some(markers) => markers,
+            None => {
+                self.cursor = 0;
+                self.check_start_of_image()?;
+                self.scan_markers()?
+            }
+        };
+
+        Ok(self.markers.insert(markers))
+    }
+
+    /// The payload of every segment with the given marker, in the order they appear. Unlike
+    /// `setup`, this needs no frame marker, so the metadata of any image can be read.
+    fn segments_of(&mut self, marker: Marker) -> Result<Vec<SharedBuffer>> {
+        let marlens = self.markers()?.get(&marker).cloned().unwrap_or_default();
+        let len = self.buffer.len();
+
+        Ok(marlens
+            .into_iter()
+            .map(|(offset, length)| {
+                self.buffer
+                    .slice(offset.min(len)..(offset + length).min(len))
+            })
+            .collect())
+    }
+
+    /// Locates every marker segment and detects the coding process from the frame marker.
     pub(crate) fn setup(&mut self) -> Result<Parser> {
-        self.check_start_of_image()?;
-        let marlen_map = self.scan_markers()?;
+        let marlen_map = self.markers()?.clone();
 
-        Ok(Parser::new(self.mmap.to_vec(), marlen_map, self.encoding))
+        let (encoding, entropy_coding) =
+            Parser::start_of_frame_marker(&marlen_map)?.encoding_process()?;
+
+        Ok(Parser::new(
+            self.buffer.clone(),
+            marlen_map,
+            encoding,
+            entropy_coding,
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::color_spaces::ConversionMatrix;
+    use crate::exif::{tag, IfdKind, Value};
+    use crate::jfif::DensityUnits;
+    use crate::mpf::ImageType;
+    use crate::options::{UpsamplingMethod, YCbCrMatrix, YCbCrRange};
+    use crate::quantize::{Dithering, PaletteMethod};
+    use crate::tensor::TensorLayout;
+
     use super::*;
 
     #[test]
     fn test_decode() -> Result<()> {
-        let mut decoder = Decoder {
-            mmap: unsafe { Mmap::map(&File::open("mike.jpg")?)? },
-            cursor: 0,
-            encoding: CodingProcess::BaselineDCT,
+        let mut decoder = Decoder::from_file_path("mike.jpg")?;
+        assert_eq!(decoder.setup()?.encoding, CodingProcess::BaselineDCT);
+
+        let image = decoder.decode()?;
+        assert_eq!((image.width, image.height), (640, 763));
+        assert_eq!(image.data.len(), 640 * 763 * 3);
+
+        // an image in memory decodes as the mapped file does.
+        let bytes = std::fs::read("mike.jpg")?;
+        assert_eq!(Decoder::from_bytes(bytes).decode()?.data, image.data);
+        assert!(Decoder::from_bytes([0xFF]).decode().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_with_options() -> Result<()> {
+        let options = DecoderOptions::new()
+            .output_color_space(OutputColorSpace::Grayscale)
+            .scale_factor(ScaleFactor::Half)
+            .strict(true)
+            .num_threads(2);
+
+        let mut decoder = Decoder::from_file_path("mike.jpg")?.with_options(options);
+
+        let image = decoder.decode()?;
+        assert_eq!((image.width, image.height), (320, 382));
+        assert_eq!(image.color_space, OutputColorSpace::Grayscale);
+        assert_eq!(image.data.len(), 320 * 382);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spectral_band_out_of_range() -> Result<()> {
+        let bytes = std::fs::read("mike.jpg")?;
+        let mut decoder = Decoder::from_bytes(bytes.clone());
+
+        // Ss and Se follow Ns and the two bytes of every component selector.
+        let start_of_spectral = decoder
+            .segments()?
+            .into_iter()
+            .find(|segment| segment.marker == Marker::SOS as u8)
+            .map(|segment| segment.offset + Marker::SIZE + 2 + 1 + 2 * 3)
+            .unwrap();
+
+        // a lenient decode only warns about other malformed scan parameters.
+        for band in [[70, 100], [0, 127], [10, 5]] {
+            let mut malformed = bytes.clone();
+            malformed[start_of_spectral..start_of_spectral + 2].copy_from_slice(&band);
+
+            assert!(Decoder::from_bytes(malformed.clone()).decode().is_err());
+            assert!(Decoder::from_bytes(malformed).read_coefficients().is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_luminance() -> Result<()> {
+        for path in ["mike.jpg", "mike_restart.jpg"] {
+            let options = DecoderOptions::new().output_color_space(OutputColorSpace::Grayscale);
+            let grayscale = Decoder::from_file_path(path)?
+                .with_options(options)
+                .decode()?;
+
+            let options = DecoderOptions::new().output_color_space(OutputColorSpace::YCbCr);
+            let ycbcr = Decoder::from_file_path(path)?
+                .with_options(options)
+                .decode()?;
+
+            let ys: Vec<_> = ycbcr.data.chunks_exact(3).map(|pixel| pixel[0]).collect();
+            assert_eq!(grayscale.data, ys);
+        }
+
+        Ok(())
+    }
+
+    fn assert_region_matches(path: &str, options: DecoderOptions) -> Result<()> {
+        let image = Decoder::from_file_path(path)?
+            .with_options(options)
+            .decode()?;
+        let channels = image.color_space.num_channels();
+
+        for (x, y, width, height) in [(0, 0, 1, 1), (13, 37, 61, 29), (17, 3, 40, 80)] {
+            let region = Decoder::from_file_path(path)?
+                .with_options(options)
+                .decode_region(x, y, width, height)?;
+            assert_eq!((region.width, region.height), (width, height));
+
+            for row in 0..height {
+                let start = ((y + row) * image.width + x) * channels;
+                assert_eq!(
+                    &region.data[row * width * channels..(row + 1) * width * channels],
+                    &image.data[start..start + width * channels]
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fancy_upsampling() -> Result<()> {
+        let options = DecoderOptions::new().upsampling_method(UpsamplingMethod::Fancy);
+        let fancy = Decoder::from_file_path("mike.jpg")?
+            .with_options(options)
+            .decode()?;
+        let nearest = Decoder::from_file_path("mike.jpg")?.decode()?;
+
+        assert_eq!((fancy.width, fancy.height), (nearest.width, nearest.height));
+        assert_ne!(fancy.data, nearest.data);
+
+        // only the chroma is filtered, so the two stay close.
+        let difference: u64 = fancy
+            .data
+            .iter()
+            .zip(&nearest.data)
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum();
+        assert!(difference < fancy.data.len() as u64);
+
+        // a 38x22 4:2:0 image, whose 19x11 chroma is padded with samples far from the edge,
+        // against the output of libjpeg with fancy upsampling and the float IDCT.
+        let image = Decoder::from_file_path("odd_fancy.jpg")?
+            .with_options(options)
+            .decode()?;
+        let expected = std::fs::read("odd_fancy.rgb")?;
+
+        assert_eq!((image.width, image.height), (38, 22));
+        assert_eq!(image.data.len(), expected.len());
+        assert!(image
+            .data
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| a.abs_diff(*b) <= 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_region() -> Result<()> {
+        assert_region_matches("mike.jpg", DecoderOptions::new())?;
+        assert_region_matches(
+            "mike.jpg",
+            DecoderOptions::new().scale_factor(ScaleFactor::Half),
+        )?;
+
+        // a restart marker every 3 MCUs, so whole intervals are skipped.
+        assert_region_matches("mike_restart.jpg", DecoderOptions::new().strict(true))?;
+
+        // the chroma filter reaches into the MCUs around the region.
+        let fancy = DecoderOptions::new().upsampling_method(UpsamplingMethod::Fancy);
+        assert_region_matches("mike.jpg", fancy)?;
+        assert_region_matches("mike_restart.jpg", fancy.strict(true))?;
+
+        let mut decoder = Decoder::from_file_path("mike_restart.jpg")?;
+        assert!(decoder.decode_region(190, 0, 11, 1).is_err());
+        assert!(decoder.decode_region(0, 0, 0, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_coefficients() -> Result<()> {
+        // the same image coded by a baseline and a progressive process.
+        let sequential = Decoder::from_file_path("mike_restart.jpg")?.read_coefficients()?;
+        let progressive = Decoder::from_file_path("mike_progressive.jpg")?.read_coefficients()?;
+
+        assert_eq!(sequential.len(), 3);
+        assert_eq!(progressive.len(), 3);
+
+        for ((a, b), (h, v, blocks_per_line, blocks_per_column)) in sequential
+            .iter()
+            .zip(&progressive)
+            .zip([(2, 2, 26, 20), (1, 1, 13, 10), (1, 1, 13, 10)])
+        {
+            for c in [a, b] {
+                assert_eq!(
+                    (c.horizontal_sampling_factor, c.vertical_sampling_factor),
+                    (h, v)
+                );
+                assert_eq!(
+                    (c.blocks_per_line, c.blocks_per_column),
+                    (blocks_per_line, blocks_per_column)
+                );
+                assert_eq!(c.blocks.len(), blocks_per_line * blocks_per_column);
+            }
+
+            assert_eq!(a.quantization_table, b.quantization_table);
+
+            // the blocks past the edge of the component are only coded by the interleaved scan.
+            let (width, height) = (
+                (200 * h as usize).div_ceil(2),
+                (150 * v as usize).div_ceil(2),
+            );
+            for row in 0..height.div_ceil(8) {
+                for column in 0..width.div_ceil(8) {
+                    assert_eq!(a.block(row, column), b.block(row, column));
+                }
+            }
+        }
+
+        assert_ne!(sequential[0].block(3, 4), &[0; 64]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_planes() -> Result<()> {
+        let planes = Decoder::from_file_path("mike.jpg")?.decode_planes()?;
+        assert_eq!((planes.width, planes.height), (640, 763));
+
+        let dimensions: Vec<_> = planes.planes.iter().map(|p| (p.width, p.height)).collect();
+        assert_eq!(dimensions, vec![(640, 763), (320, 382), (320, 382)]);
+
+        let options = DecoderOptions::new().output_color_space(OutputColorSpace::YCbCr);
+        let image = Decoder::from_file_path("mike.jpg")?
+            .with_options(options)
+            .decode()?;
+
+        // the luminance is never resampled, and every chroma sample covers 2 x 2 pixels.
+        for (idx, pixel) in image.data.chunks_exact(3).enumerate() {
+            let (x, y) = (idx % 640, idx / 640);
+            assert_eq!(pixel[0], planes.planes[0].data[idx]);
+            assert_eq!(pixel[1], planes.planes[1].data[(y / 2) * 320 + x / 2]);
+        }
+
+        assert_eq!(planes.to_i420()?.len(), 640 * 763 + 2 * 320 * 382);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_jfif() -> Result<()> {
+        let jfif = Decoder::from_file_path("mike.jpg")?.jfif()?.unwrap();
+
+        assert_eq!(jfif.version, (1, 1));
+        assert_eq!(jfif.density_units, DensityUnits::AspectRatio);
+        assert_eq!((jfif.x_density, jfif.y_density), (1, 1));
+        assert_eq!(jfif.thumbnail, None);
+        assert!(jfif.extension_thumbnails.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_segments() -> Result<()> {
+        let mut decoder = Decoder::from_file_path("mike.jpg")?;
+        let segments = decoder.segments()?;
+        let bytes = std::fs::read("mike.jpg")?;
+
+        let markers: Vec<_> = segments.iter().map(|segment| segment.marker).collect();
+        assert_eq!(markers[0], Marker::APP0 as u8);
+        assert_eq!(*markers.last().unwrap(), Marker::SOS as u8);
+        assert!(segments.windows(2).all(|w| w[0].offset < w[1].offset));
+
+        for segment in &segments {
+            assert_eq!(bytes[segment.offset..][..2], [0xFF, segment.marker]);
+            assert_eq!(segment.payload.len(), segment.length);
+        }
+
+        assert!(segments[0].payload.starts_with(JFIF::IDENTIFIER));
+
+        let comment = Segment {
+            marker: Marker::COM as u8,
+            offset: 0,
+            length: 4,
+            payload: b"caf\xE9",
+        };
+        assert_eq!(comment.text().as_deref(), Some("caf\u{e9}"));
+        assert_eq!(segments[0].text(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub_images() -> Result<()> {
+        let mut decoder = Decoder::from_file_path("mike_stereo.mpo")?;
+        let index = decoder.mp_index()?.unwrap();
+
+        assert_eq!(index.version, "0100");
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.entries[0].image_type, ImageType::BaselinePrimary);
+        assert_eq!(index.entries[1].image_type, ImageType::Disparity);
+
+        let primary = decoder.decode()?;
+        let mut expected = Decoder::from_file_path("mike_progressive.jpg")?;
+        let mut disparity = decoder.sub_image(&index.entries[1])?;
+
+        assert_eq!(
+            disparity.read_coefficients()?[0].blocks,
+            expected.read_coefficients()?[0].blocks
+        );
+        assert_eq!(disparity.mp_index()?, None);
+        assert_eq!((primary.width, primary.height), (200, 150));
+
+        // the first image is the whole file up to its EOI marker.
+        let mut first = decoder.sub_image(&index.entries[0])?;
+        assert_eq!(first.decode()?.data, primary.data);
+
+        let outside = MpEntry {
+            offset: 19000,
+            ..index.entries[1]
         };
+        assert!(decoder.sub_image(&outside).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_thumbnail() -> Result<()> {
+        let mut decoder = Decoder::from_file_path("mike_exif.jpg")?;
+        let thumbnail = decoder.decode_thumbnail()?;
+        assert_eq!((thumbnail.width, thumbnail.height), (50, 38));
+
+        // the same stream, decoded on its own.
+        let stream = decoder.exif()?.unwrap().thumbnail().unwrap().to_vec();
+        let mut expected = Decoder::from_bytes(stream);
+        assert_eq!(thumbnail.data, expected.decode()?.data);
+
+        // without a thumbnail, the main image is scaled down.
+        let mut decoder = Decoder::from_file_path("mike_restart.jpg")?;
+        assert!(decoder.exif_thumbnail()?.is_none());
+
+        let thumbnail = decoder.decode_thumbnail()?;
+        assert_eq!((thumbnail.width, thumbnail.height), (25, 19));
+        assert_eq!(decoder.options.scale_factor, ScaleFactor::Full);
+
+        // a broken EXIF segment is recovered from, or rejected, as by `exif`. The byte order of
+        // the TIFF header follows SOI, the APP1 marker and length, and the EXIF identifier.
+        let mut bytes = std::fs::read("mike_exif.jpg")?;
+        assert_eq!(bytes[12..14], *b"II");
+        bytes[12..14].copy_from_slice(b"XX");
+
+        let mut decoder = Decoder::from_bytes(bytes.clone());
+        assert_eq!(decoder.exif()?, None);
+        assert!(decoder.exif_thumbnail()?.is_none());
+
+        let strict = DecoderOptions::new().strict(true);
+        let mut decoder = Decoder::from_bytes(bytes).with_options(strict);
+        assert!(decoder.exif().is_err());
+        assert!(decoder.exif_thumbnail().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_orient() -> Result<()> {
+        // the value of the orientation field of IFD0 follows SOI, the APP1 marker and length, the
+        // EXIF identifier, the TIFF header, the number of fields and the field header.
+        let mut bytes = std::fs::read("mike_exif.jpg")?;
+        assert_eq!(bytes[30..32], [1, 0]);
+        bytes[30] = 6;
+
+        let rotated = || Decoder::from_bytes(bytes.clone());
+
+        let options = DecoderOptions::new().auto_orient(true);
+        let image = rotated().with_options(options).decode()?;
+        let stored = rotated().decode()?;
+
+        assert_eq!(stored.orientation, Orientation::Normal);
+        assert_eq!(image.orientation, Orientation::Rotate90);
+        assert_eq!((image.width, image.height), (150, 200));
+        assert_eq!(
+            image.data,
+            Orientation::Rotate90.apply(200, 150, 3, &stored.data).2
+        );
+
+        // regions are taken in display coordinates, and match the pixels of the oriented image.
+        let region = rotated()
+            .with_options(options)
+            .decode_region(10, 20, 30, 40)?;
+        assert_eq!(region.orientation, Orientation::Rotate90);
+        assert_eq!((region.width, region.height), (30, 40));
+        for y in 0..40 {
+            assert_eq!(
+                region.data[y * 90..(y + 1) * 90],
+                image.data[((20 + y) * 150 + 10) * 3..][..90]
+            );
+        }
+
+        let region = rotated()
+            .with_options(options)
+            .decode_region_u16(10, 20, 30, 40)?;
+        assert_eq!(region.orientation, Orientation::Rotate90);
+        assert_eq!((region.width, region.height), (30, 40));
+
+        assert!(rotated()
+            .with_options(options)
+            .decode_region(0, 0, 200, 150)
+            .is_err());
+
+        let thumbnail = rotated().with_options(options).decode_thumbnail()?;
+        assert_eq!(thumbnail.orientation, Orientation::Rotate90);
+        assert_eq!((thumbnail.width, thumbnail.height), (38, 50));
+
+        // an upright image is left as it is.
+        let image = Decoder::from_file_path("mike_exif.jpg")?
+            .with_options(options)
+            .decode()?;
+        assert_eq!(image.orientation, Orientation::Normal);
+        assert_eq!(image.data, stored.data);
 
-        decoder.decode()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_exif() -> Result<()> {
+        let mut decoder = Decoder::from_file_path("mike_exif.jpg")?;
+        let mut exif = decoder.exif()?.unwrap();
+
+        exif.set(IfdKind::Primary, tag::ORIENTATION, Value::Short(vec![6]));
+        exif.set(
+            IfdKind::Primary,
+            tag::MODEL,
+            Value::Ascii("Mike".to_string()),
+        );
+        exif.set(
+            IfdKind::GPS,
+            tag::GPS_ALTITUDE,
+            Value::Rational(vec![(35, 1)]),
+        );
+
+        let bytes = decoder.rewrite_exif(Some(&exif))?;
+        let mut rewritten = Decoder::from_bytes(bytes.clone());
+
+        let parsed = rewritten.exif()?.unwrap();
+        assert_eq!(parsed.orientation(), Some(6));
+        assert_eq!(parsed.model(), Some("Mike"));
+        assert_eq!(parsed.gps_altitude(), Some(35.0));
+        assert_eq!(parsed.thumbnail(), exif.thumbnail());
+
+        // every other segment, and the image data, is unchanged.
+        let original = std::fs::read("mike_exif.jpg")?;
+        let exif_end = decoder.segments()?[1].offset;
+        assert_eq!(
+            bytes[bytes.len() - (original.len() - exif_end)..],
+            original[exif_end..]
+        );
+        assert_eq!(rewritten.decode()?.data, decoder.decode()?.data);
+
+        // the location is stripped, and then the whole segment.
+        exif.remove_ifd(IfdKind::GPS);
+        assert_eq!(
+            exif.remove(IfdKind::Primary, tag::MODEL),
+            Some(Value::Ascii("Mike".to_string()))
+        );
+        let stripped = Exif::parse(&exif.to_segment()?)?;
+        assert_eq!(stripped.gps_altitude(), None);
+        assert_eq!(stripped.model(), None);
+        assert_eq!(stripped.ifds.len(), 2);
+
+        assert_eq!(
+            decoder.rewrite_exif(None)?,
+            [&original[..2], &original[exif_end..]].concat()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_management() -> Result<()> {
+        let p3 = MatrixTrc {
+            colorants: [
+                [0.515121, 0.241196, -0.001053],
+                [0.291977, 0.692245, 0.041885],
+                [0.157104, 0.066574, 0.784073],
+            ],
+            ..MatrixTrc::srgb()
+        };
+
+        let embed = |profile: &[u8]| -> Result<Decoder> {
+            let segment = [IccProfile::IDENTIFIER, &[1, 1], profile].concat();
+            with_segment("mike_restart.jpg", 0xE2, &segment)
+        };
+
+        let options = DecoderOptions::new().color_management(true);
+        let original = Decoder::from_file_path("mike_restart.jpg")?.decode()?;
+
+        // images without a profile are already sRGB.
+        let image = Decoder::from_file_path("mike_restart.jpg")?
+            .with_options(options)
+            .decode()?;
+        assert_eq!(image.data, original.data);
+
+        let mut decoder = embed(&p3.to_icc()?)?.with_options(options);
+        let image = decoder.decode()?;
+
+        let mut expected = original.data.clone();
+        let source = MatrixTrc::parse(&decoder.icc_profile()?.unwrap())?;
+        Transform::new(&source, &MatrixTrc::srgb())?.apply(&mut expected);
+        assert_eq!(image.data, expected);
+        assert_ne!(image.data, original.data);
+
+        // converting to the embedded profile itself leaves the samples alone.
+        assert_eq!(decoder.decode_to_profile(&source)?.data, original.data);
+
+        let region = decoder.decode_region(10, 20, 30, 40)?;
+        assert_eq!(region.data[..3], image.data[(20 * 200 + 10) * 3..][..3]);
+
+        // a CMYK profile cannot be converted from.
+        let mut cmyk = p3.to_icc()?;
+        cmyk[16..20].copy_from_slice(b"CMYK");
+        assert!(embed(&cmyk)?.with_options(options).decode().is_err());
+
+        // grayscale output is left alone without a profile, and converted through the curves
+        // with one.
+        let grayscale = DecoderOptions::new().output_color_space(OutputColorSpace::Grayscale);
+        let gray = Decoder::from_file_path("mike_restart.jpg")?
+            .with_options(grayscale)
+            .decode()?;
+        let managed = Decoder::from_file_path("mike_restart.jpg")?
+            .with_options(grayscale.color_management(true))
+            .decode()?;
+        assert_eq!(managed.data, gray.data);
+
+        let mut expected = gray.data.clone();
+        Transform::new(&source, &MatrixTrc::srgb())?.apply_gray(&mut expected);
+        let managed = decoder
+            .with_options(grayscale.color_management(true))
+            .decode()?;
+        assert_eq!(managed.data, expected);
+
+        Ok(())
+    }
+
+    /// Decodes the file at `path` from memory, with a segment inserted right after SOI.
+    fn with_segment(path: &str, marker: u8, payload: &[u8]) -> Result<Decoder> {
+        let original = std::fs::read(path)?;
+        let length = ((payload.len() + 2) as u16).to_be_bytes();
+
+        Ok(Decoder::from_bytes(
+            [
+                &original[..2],
+                &[0xFF, marker],
+                &length,
+                payload,
+                &original[2..],
+            ]
+            .concat(),
+        ))
+    }
+
+    #[test]
+    fn test_decode_u16() -> Result<()> {
+        // 8-bit images come out with the same samples as with `decode`.
+        let mut decoder = Decoder::from_file_path("mike.jpg")?;
+        let image = decoder.decode()?;
+        let wide = decoder.decode_u16()?;
+        assert_eq!((wide.width, wide.height, wide.bit_depth), (640, 763, 8));
+        assert!(image
+            .data
+            .iter()
+            .zip(&wide.data)
+            .all(|(a, b)| *a as u16 == *b));
+
+        let region = decoder.decode_region_u16(100, 200, 50, 40)?;
+        for y in 0..40 {
+            let row = &wide.data[((200 + y) * 640 + 100) * 3..][..150];
+            assert_eq!(&region.data[y * 150..(y + 1) * 150], row);
+        }
+
+        // samples of 12-bit images keep their precision. Every 8x8 block of the 12-bit image is
+        // flat with these Y, Cb and Cr samples.
+        let blocks = [[3000, 2048, 2048], [1000, 1000, 3000], [4000, 3900, 100]];
+        let decoder = Decoder::from_file_path("twelve_bit.jpg")?;
+
+        let ycbcr = decoder
+            .with_options(DecoderOptions::new().output_color_space(OutputColorSpace::YCbCr))
+            .decode_u16()?;
+        assert_eq!(ycbcr.bit_depth, 12);
+        for (i, block) in blocks.iter().enumerate() {
+            for y in 0..8 {
+                for x in 0..8 {
+                    let pixel = (y * 24 + i * 8 + x) * 3;
+                    assert_eq!(ycbcr.data[pixel..pixel + 3], *block);
+                }
+            }
+        }
+
+        let mut decoder = Decoder::from_file_path("twelve_bit.jpg")?;
+        let rgb = decoder.decode_u16()?;
+        assert_eq!(rgb.data[..3], [3000; 3]);
+        assert_eq!(rgb.data[(8 * 3)..(8 * 3 + 3)], [2335, 681, 0]);
+        assert!(rgb.data.iter().all(|sample| *sample <= 4095));
+
+        let region = decoder.decode_region_u16(6, 2, 4, 3)?;
+        assert_eq!(region.data[..6], [3000, 3000, 3000, 3000, 3000, 3000]);
+        assert_eq!(region.data[6..9], [2335, 681, 0]);
+
+        // and are scaled down to 8 bits by `decode`.
+        let image = decoder.decode()?;
+        assert_eq!(image.data[..3], [187; 3]);
+
+        let mut decoder = Decoder::from_file_path("twelve_bit.jpg")?;
+        let mut scanline_reader = decoder.scanlines()?;
+        assert_eq!(scanline_reader.bit_depth(), 12);
+        let mut buffer = vec![0u16; 24 * 3];
+        assert!(scanline_reader
+            .read_scanlines_u16(&mut buffer, 24 * 3 - 1)
+            .is_err());
+        assert_eq!(scanline_reader.read_scanlines_u16(&mut buffer, 24 * 3)?, 1);
+        assert_eq!(buffer[..3], [3000; 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_tensor() -> Result<()> {
+        let mut decoder = Decoder::from_file_path("mike.jpg")?;
+        let image = decoder.decode()?;
+
+        // the samples are those of `decode` before they are rounded.
+        let hwc = decoder.decode_tensor(&TensorOptions::new())?;
+        assert_eq!(hwc.shape, [763, 640, 3]);
+        assert!(image
+            .data
+            .iter()
+            .zip(&hwc.data)
+            .all(|(a, b)| (*a as f32 - b * 255.0).abs() <= 0.5 + 1e-3));
+        assert!(hwc.data.iter().any(|sample| sample.fract() != 0.0));
+
+        let (mean, std) = ([0.485, 0.456, 0.406], [0.229, 0.224, 0.225]);
+        let chw = decoder.decode_tensor(
+            &TensorOptions::new()
+                .layout(TensorLayout::CHW)
+                .normalize(&mean, &std)
+                .crop(100, 200, 50, 40),
+        )?;
+        assert_eq!(chw.shape, [3, 40, 50]);
+        for c in 0..3 {
+            for y in 0..40 {
+                for x in 0..50 {
+                    let sample = hwc.data[((200 + y) * 640 + 100 + x) * 3 + c];
+                    let expected = (sample - mean[c]) / std[c];
+                    assert!((chw.data[(c * 40 + y) * 50 + x] - expected).abs() < 1e-4);
+                }
+            }
+        }
+
+        // the normalization has to match the channels of the output color space.
+        let mut decoder = decoder
+            .with_options(DecoderOptions::new().output_color_space(OutputColorSpace::Grayscale));
+        assert!(decoder
+            .decode_tensor(&TensorOptions::new().normalize(&mean, &std))
+            .is_err());
+        let gray = decoder.decode_tensor(&TensorOptions::new().normalize(&[0.5], &[0.5]))?;
+        assert_eq!(gray.shape, [763, 640, 1]);
+
+        // samples of 12-bit images are scaled by 4095.
+        let tensor = Decoder::from_file_path("twelve_bit.jpg")?
+            .with_options(DecoderOptions::new().output_color_space(OutputColorSpace::YCbCr))
+            .decode_tensor(&TensorOptions::new().layout(TensorLayout::CHW))?;
+        assert_eq!(tensor.shape, [3, 8, 24]);
+        assert!((tensor.data[0] - 3000.0 / 4095.0).abs() < 1e-6);
+        assert!((tensor.data[8 * 24 + 8] - 1000.0 / 4095.0).abs() < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_coded_color_space() -> Result<()> {
+        // the components of rgb.jpg have the IDs 'R', 'G' and 'B', and those of twelve_bit.jpg
+        // the IDs 1, 2 and 3.
+        let jfif = b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+        let adobe =
+            |transform: u8| [b"Adobe".as_slice(), &[0, 100, 0, 0, 0, 0, transform]].concat();
+
+        let detect = |path, segment: Option<(u8, &[u8])>| -> Result<CodedColorSpace> {
+            match segment {
+                Some((marker, payload)) => with_segment(path, marker, payload)?,
+                None => Decoder::from_file_path(path)?,
+            }
+            .coded_color_space()
+        };
+
+        // (libjpeg) the JFIF segment comes first, then the Adobe transform, then the IDs.
+        assert_eq!(detect("twelve_bit.jpg", None)?, CodedColorSpace::YCbCr);
+        assert_eq!(detect("rgb.jpg", None)?, CodedColorSpace::RGB);
+        assert_eq!(
+            detect("rgb.jpg", Some((0xE0, jfif)))?,
+            CodedColorSpace::YCbCr
+        );
+        assert_eq!(
+            detect("twelve_bit.jpg", Some((0xEE, &adobe(0))))?,
+            CodedColorSpace::RGB
+        );
+        assert_eq!(
+            detect("rgb.jpg", Some((0xEE, &adobe(1))))?,
+            CodedColorSpace::YCbCr
+        );
+        assert_eq!(
+            Decoder::from_file_path("mike.jpg")?.coded_color_space()?,
+            CodedColorSpace::YCbCr
+        );
+
+        let mut decoder = with_segment("rgb.jpg", 0xEE, &adobe(0))?;
+        assert_eq!(decoder.adobe()?.map(|adobe| adobe.transform), Some(0));
+
+        // RGB components are written out as is, or converted with the weights of the matrix.
+        let matrix = ConversionMatrix::new(YCbCrMatrix::BT601, YCbCrRange::Full, 8)?;
+        let image = decoder.decode()?;
+        assert_eq!(image.data[..3], [200, 40, 90]);
+        assert_eq!(image.data[8 * 3..8 * 3 + 3], [16, 128, 250]);
+
+        // the planes of RGB components are not Y, Cb and Cr.
+        assert!(decoder.decode_planes().is_err());
+
+        let with_options = |options: DecoderOptions| -> Result<Image> {
+            Decoder::from_file_path("rgb.jpg")?
+                .with_options(options)
+                .decode()
+        };
+        let with_output = |output_color_space| {
+            with_options(DecoderOptions::new().output_color_space(output_color_space))
+        };
+
+        let ycbcr = matrix
+            .rgb_to_ycbcr(200.0, 40.0, 90.0)
+            .map(|sample| sample.round() as u8);
+        assert_eq!(with_output(OutputColorSpace::YCbCr)?.data[..3], ycbcr);
+        let gray = with_output(OutputColorSpace::Grayscale)?;
+        assert_eq!(
+            gray.data[0],
+            matrix.rgb_luma(200.0, 40.0, 90.0).round() as u8
+        );
+        assert_eq!(
+            gray.data[8],
+            matrix.rgb_luma(16.0, 128.0, 250.0).round() as u8
+        );
+
+        // callers can override the detected color space, as long as it has as many components.
+        let interpret = |coded_color_space| {
+            with_options(DecoderOptions::new().coded_color_space(coded_color_space))
+        };
+        let image = interpret(CodedColorSpace::YCbCr)?;
+        assert_eq!(image.data[..3], matrix.convert::<u8>(200.0, 40.0, 90.0));
+        assert!(interpret(CodedColorSpace::Grayscale).is_err());
+        assert!(interpret(CodedColorSpace::CMYK).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_indexed() -> Result<()> {
+        let image = Decoder::from_file_path("mike_restart.jpg")?.decode()?;
+
+        // the fixed cube is coarser than a palette chosen for the image.
+        for (quantizer, mean_difference) in [
+            (
+                Quantizer::new(PaletteMethod::Cube([6, 7, 6])).dithering(Dithering::Ordered),
+                20,
+            ),
+            (
+                Quantizer::new(PaletteMethod::MedianCut(256)).dithering(Dithering::FloydSteinberg),
+                5,
+            ),
+        ] {
+            let indexed =
+                Decoder::from_file_path("mike_restart.jpg")?.decode_indexed(&quantizer)?;
+            assert_eq!((indexed.width, indexed.height), (200, 150));
+            assert_eq!(indexed.indices.len(), 200 * 150);
+            assert!(indexed.palette.len() <= 256);
+
+            let difference: u64 = indexed
+                .to_rgb()
+                .iter()
+                .zip(&image.data)
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum();
+            assert!(
+                difference < mean_difference * image.data.len() as u64,
+                "{quantizer:?}"
+            );
+        }
+
+        let grayscale = DecoderOptions::new().output_color_space(OutputColorSpace::Grayscale);
+        let quantizer = Quantizer::new(PaletteMethod::MedianCut(16));
+        assert!(Decoder::from_file_path("mike_restart.jpg")?
+            .with_options(grayscale)
+            .decode_indexed(&quantizer)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_without_segments() -> Result<()> {
+        assert_eq!(Decoder::from_file_path("mike.jpg")?.exif()?, None);
+        assert_eq!(Decoder::from_file_path("mike.jpg")?.icc_profile()?, None);
+        assert_eq!(Decoder::from_file_path("mike.jpg")?.xmp()?, None);
+        assert_eq!(Decoder::from_file_path("mike.jpg")?.photoshop()?, None);
+        assert_eq!(Decoder::from_file_path("mike.jpg")?.mp_index()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_without_frame() -> Result<()> {
+        let bytes = std::fs::read("mike_exif.jpg")?;
+        let mut decoder = Decoder::from_bytes(bytes.clone());
+
+        // the same image, with its frame marker turned into a hierarchical one that is not
+        // decoded, and with its frame header cut out.
+        let frame = decoder
+            .segments()?
+            .into_iter()
+            .find(|segment| segment.marker == Marker::SOF0 as u8)
+            .map(|segment| segment.offset..segment.offset + Marker::SIZE + 2 + segment.length)
+            .unwrap();
+
+        let mut hierarchical = bytes.clone();
+        hierarchical[frame.start + 1] = Marker::SOF5 as u8;
+        let without_frame = [&bytes[..frame.start], &bytes[frame.end..]].concat();
+
+        for bytes in [hierarchical, without_frame] {
+            let mut metadata = Decoder::from_bytes(bytes);
+            assert!(metadata.decode().is_err());
+
+            assert_eq!(metadata.exif()?, decoder.exif()?);
+            assert_eq!(metadata.jfif()?, decoder.jfif()?);
+            assert_eq!(metadata.icc_profile()?, decoder.icc_profile()?);
+            assert_eq!(metadata.adobe()?, None);
+        }
 
         Ok(())
     }
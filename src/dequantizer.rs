@@ -1,59 +1,58 @@
 use std::collections::HashMap;
-use std::simd::Simd;
+use std::simd::prelude::*;
 
 use anyhow::{anyhow, Result};
 
 use crate::quantization_table::QuantizationTable;
 
-pub(crate) struct Dequantizer<'a> {
-    data: &'a Vec<([u8; 64], [u8; 64], [u8; 64])>,
-    scan_component_order: &'a Vec<u8>,
+pub(crate) struct Dequantizer {
+    /// The quantization table of every component, keyed by component id.
     quantization_table_map: HashMap<u8, QuantizationTable>,
 }
 
-impl<'a> Dequantizer<'a> {
-    pub(crate) fn new(
-        data: &'a Vec<([u8; 64], [u8; 64], [u8; 64])>,
-        scan_component_order: &'a Vec<u8>,
-        quantization_table_map: HashMap<u8, QuantizationTable>,
-    ) -> Self {
+impl Dequantizer {
+    pub(crate) fn new(quantization_table_map: HashMap<u8, QuantizationTable>) -> Self {
         Dequantizer {
-            data,
-            scan_component_order,
             quantization_table_map,
         }
     }
 
-    pub(crate) fn dequantize(&mut self) -> Result<Vec<(Simd<u8, 64>, Simd<u8, 64>, Simd<u8, 64>)>> {
-        let mut dequantized_coefficients = vec![];
-
-        for mcu in self.data {
-            let (c1, c2, c3) = *mcu;
-            let idct: Result<Vec<_>> = self
-                .scan_component_order
-                .iter()
-                .zip(vec![c1, c2, c3].iter())
-                .map(|(component_id, mcu)| {
-                    let QuantizationTable {
-                        quantization_table_element,
-                        ..
-                    } = *self
-                        .quantization_table_map
-                        .get(component_id)
-                        .ok_or(anyhow!(format!(
-                            "failed to find component id {}",
-                            component_id
-                        )))?;
-
-                    Ok(Simd::from_array(*mcu) * quantization_table_element)
-                })
-                .collect();
-
-            let idct = idct?;
-            debug_assert_eq!(idct.len(), 3);
-            dequantized_coefficients.push((idct[0], idct[1], idct[2]))
-        }
+    /// (A.3.4) Multiplies every coefficient of a block by its quantization value.
+    pub(crate) fn dequantize(&self, component_id: u8, block: &[i16; 64]) -> Result<Simd<i32, 64>> {
+        let QuantizationTable {
+            quantization_table_element,
+            ..
+        } = self
+            .quantization_table_map
+            .get(&component_id)
+            .ok_or(anyhow!(format!(
+                "failed to find component id {}",
+                component_id
+            )))?;
+
+        Ok(Simd::from_array(*block).cast::<i32>() * quantization_table_element.cast::<i32>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sample_precision::SamplePrecision;
+
+    use super::*;
+
+    #[test]
+    fn test_dequantize() -> Result<()> {
+        // the table is coded in zig-zag order, and applied to coefficients in natural order.
+        let qt_data = Simd::from_array(std::array::from_fn(|k| k as u16 + 1));
+        let table = QuantizationTable::from(0, SamplePrecision::EightBit, qt_data);
+        let dequantizer = Dequantizer::new(HashMap::from([(1, table)]));
+
+        let block = [2; 64];
+        let dequantized = dequantizer.dequantize(1, &block)?.to_array();
+        assert_eq!(&dequantized[..10], &[2, 4, 12, 14, 30, 32, 56, 58, 6, 10]);
+
+        assert!(dequantizer.dequantize(2, &block).is_err());
 
-        Ok(dequantized_coefficients)
+        Ok(())
     }
 }
@@ -1,239 +1,801 @@
+use std::ops::Range;
+
 use anyhow::{anyhow, Result};
+use log::warn;
 
+use crate::bitreader::BitReader;
 use crate::coding::EntropyCoding;
-use crate::huffman_tree::HuffmanClass;
-use crate::scan_header::ScanHeader;
+use crate::frame_header::FrameHeader;
+use crate::huffman_tree::{HuffmanClass, NPtr};
+use crate::marker::Marker;
+use crate::scan_header::{EncodingOrder, ScanHeader};
+
+/// The quantized DCT coefficients of one component, laid out as a grid of blocks. The grid may
+/// only cover part of the component, starting at the block (`first_row`, `first_column`).
+pub(crate) struct ComponentBlocks {
+    pub(crate) first_row: usize,
+    pub(crate) first_column: usize,
+    pub(crate) blocks_per_line: usize,
+    pub(crate) blocks_per_column: usize,
+
+    /// The coefficients of every block, in zig-zag order while a scan is being decoded into them
+    /// and in natural (row-major) order once handed out.
+    pub(crate) blocks: Vec<[i16; 64]>,
+}
+
+impl ComponentBlocks {
+    pub(crate) fn from(
+        (first_row, first_column): (usize, usize),
+        (blocks_per_line, blocks_per_column): (usize, usize),
+    ) -> Self {
+        ComponentBlocks {
+            first_row,
+            first_column,
+            blocks_per_line,
+            blocks_per_column,
+            blocks: vec![[0; 64]; blocks_per_line * blocks_per_column],
+        }
+    }
+
+    /// Reorders the coefficients of every block from zig-zag order into natural order.
+    pub(crate) fn unzigzag(&mut self) {
+        self.blocks
+            .iter_mut()
+            .for_each(|block| *block = EntropyDecoder::zigzag(*block));
+    }
+
+    pub(crate) fn block(&self, row: usize, column: usize) -> &[i16; 64] {
+        debug_assert!(row < self.blocks_per_column && column < self.blocks_per_line);
+        &self.blocks[row * self.blocks_per_line + column]
+    }
+}
+
+/// (scan component, block row, block column) of every block of an MCU, in the order coded.
+type MCUBlocks = Vec<(usize, usize, usize)>;
 
-pub(crate) struct EntropyDecoder<'a> {
-    data: &'a [u8],
-    cursor: usize,
+/// A component of the scan, along with the tables used to decode its blocks.
+struct ScanComponent {
+    /// The position of the component in the frame header.
+    component_idx: usize,
+    dc_table: NPtr,
+    ac_table: NPtr,
+}
+
+pub(crate) struct EntropyDecoder {
+    reader: BitReader,
     scan_header: ScanHeader,
-    entropy_coding: EntropyCoding,
+
+    /// Owns the Huffman trees that the tables of the scan components point into.
+    _entropy_coding: EntropyCoding,
+
+    /// Ri: the number of MCUs between restart markers, zero when restart markers are disabled.
+    restart_interval: usize,
+
+    /// The offset of every restart marker within the entropy-coded data.
+    restart_offsets: Vec<usize>,
+    strict: bool,
+
+    scan_components: Vec<ScanComponent>,
+
+    /// The scan component of every block of an MCU, in the order they are coded.
+    mcu_layout: Vec<usize>,
+
+    /// PRED: the DC value of the previous block of every scan component.
+    predictions: Vec<i32>,
+
+    /// EOBRUN: the number of blocks left in a run of blocks with no more coefficients in the band
+    /// of a progressive scan (G.1.2.2).
+    eob_run: usize,
+    mcus_decoded: usize,
+
+    /// Set once a lenient decoder gives up on a corrupt scan. The blocks left are zero.
+    exhausted: bool,
 }
 
-impl<'a> EntropyDecoder<'a> {
-    const ZIGZAG_TABLE: [usize; 64] = [
+impl EntropyDecoder {
+    /// The zig-zag index of every coefficient, in natural order.
+    pub(crate) const ZIGZAG_TABLE: [usize; 64] = [
         0, 1, 5, 6, 14, 15, 27, 28, 2, 4, 7, 13, 16, 26, 29, 42, 3, 8, 12, 17, 25, 30, 41, 43, 9,
         11, 18, 24, 31, 40, 44, 53, 10, 19, 23, 32, 39, 45, 52, 54, 20, 22, 33, 38, 46, 51, 55, 60,
         21, 34, 37, 47, 50, 56, 59, 61, 35, 36, 48, 49, 57, 58, 62, 63,
     ];
 
     pub(crate) fn new(
-        data: &'a [u8],
+        reader: BitReader,
         scan_header: ScanHeader,
         entropy_coding: EntropyCoding,
-    ) -> Self {
-        EntropyDecoder {
-            data,
-            cursor: 0,
+        (restart_interval, restart_offsets): (usize, Vec<usize>),
+        strict: bool,
+        frame_header: &FrameHeader,
+    ) -> Result<Self> {
+        let scan_components = match &entropy_coding {
+            EntropyCoding::Huffman(_) => {
+                let huffman_map = entropy_coding.huffman_map();
+
+                scan_header
+                    .scan_component_selectors
+                    .iter()
+                    .map(|s| {
+                        let component_idx = frame_header
+                            .components
+                            .iter()
+                            .position(|c| c.component_id == s.component_id)
+                            .ok_or(anyhow!(format!(
+                                "failed to find a component with id: {}",
+                                s.component_id
+                            )))?;
+
+                        // a progressive scan only codes the DC or the AC coefficients, and a
+                        // DC refinement scan does not use a table at all.
+                        let table = |class, destination_id, used: bool| match huffman_map
+                            .get(&(class, destination_id))
+                        {
+                            Some(root) => Ok(*root),
+                            None if !used => Ok(None),
+                            None => Err(anyhow!(format!(
+                                "failed to find {class:?} table {destination_id}"
+                            ))),
+                        };
+
+                        let dc_table = table(
+                            HuffmanClass::DC,
+                            s.dc_destination_id,
+                            scan_header.start_of_spectral == 0
+                                && scan_header.successive_approx_bit_position_high == 0,
+                        )?;
+                        let ac_table = table(
+                            HuffmanClass::AC,
+                            s.ac_destination_id,
+                            scan_header.end_of_spectral > 0,
+                        )?;
+
+                        Ok(ScanComponent {
+                            component_idx,
+                            dc_table,
+                            ac_table,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+            EntropyCoding::Arithmetic => return Err(anyhow!("arithmetic coding is not supported")),
+        };
+
+        let mcu_layout = match scan_header.encoding_order {
+            EncodingOrder::NonInterleaved => vec![0],
+            EncodingOrder::Interleaved => scan_components
+                .iter()
+                .enumerate()
+                .flat_map(|(scan_idx, scan_component)| {
+                    let component = &frame_header.components[scan_component.component_idx];
+                    let num_blocks = component.horizontal_scaling_factor as usize
+                        * component.vertical_scaling_factor as usize;
+
+                    std::iter::repeat_n(scan_idx, num_blocks)
+                })
+                .collect(),
+        };
+
+        Ok(EntropyDecoder {
+            reader,
+            mcu_layout,
+            predictions: vec![0; scan_components.len()],
+            scan_components,
             scan_header,
-            entropy_coding,
-        }
+            _entropy_coding: entropy_coding,
+            restart_interval,
+            restart_offsets,
+            strict,
+            eob_run: 0,
+            mcus_decoded: 0,
+            exhausted: false,
+        })
     }
 
-    pub(crate) fn zigzag(&mut self, data: Vec<(u8, u8, u8)>) -> Result<Vec<[(u8, u8, u8); 64]>> {
-        self.cursor = 0;
-        let mut unzigzagged = vec![];
+    /// Reorders a block of coefficients from zig-zag order into natural order.
+    pub(crate) fn zigzag(block: [i16; 64]) -> [i16; 64] {
+        std::array::from_fn(|idx| block[Self::ZIGZAG_TABLE[idx]])
+    }
 
-        let mut temp_chunk = [(0u8, 0u8, 0u8); 64];
+    /// Decodes the blocks of the MCUs in `mcu_columns` of an MCU row. Every component of the
+    /// frame gets Vi rows of blocks, laid out like the padded block grid of an interleaved scan
+    /// but starting at the first of the columns. The MCUs before them are decoded and thrown
+    /// away, or skipped over whole restart intervals at a time.
+    pub(crate) fn decode_mcu_row(
+        &mut self,
+        frame_header: &FrameHeader,
+        mcu_row: usize,
+        mcu_columns: Range<usize>,
+    ) -> Result<Vec<ComponentBlocks>> {
+        let mut blocks: Vec<_> = frame_header
+            .components
+            .iter()
+            .map(|component| {
+                let (h, v) = (
+                    component.horizontal_scaling_factor as usize,
+                    component.vertical_scaling_factor as usize,
+                );
+
+                ComponentBlocks::from(
+                    (mcu_row * v, mcu_columns.start * h),
+                    (mcu_columns.len() * h, v),
+                )
+            })
+            .collect();
 
-        while self.cursor < data.len() {
-            let end = (self.cursor + 64).min(data.len());
-            let len = end - self.cursor;
+        let mcus = self.mcus(frame_header, mcu_row..mcu_row + 1, mcu_columns);
+        self.decode_mcus(mcus, &mut blocks)?;
 
-            temp_chunk[..len].copy_from_slice(&data[self.cursor..end]);
+        blocks.iter_mut().for_each(ComponentBlocks::unzigzag);
 
-            let mut new_chunk = [(0u8, 0u8, 0u8); 64];
-            temp_chunk.into_iter().enumerate().for_each(|(idx, block)| {
-                let jdx = Self::ZIGZAG_TABLE[idx];
-                new_chunk[jdx] = block;
-            });
+        Ok(blocks)
+    }
 
-            unzigzagged.push(new_chunk);
+    /// Decodes every MCU of the scan into block grids covering the whole of each component, in
+    /// zig-zag order. The coefficients already in the grids are refined by successive
+    /// approximation scans.
+    pub(crate) fn decode_scan(
+        &mut self,
+        frame_header: &FrameHeader,
+        blocks: &mut [ComponentBlocks],
+    ) -> Result<()> {
+        let mcus = self.mcus(
+            frame_header,
+            0..frame_header.mcu_rows(),
+            0..frame_header.mcus_per_line(),
+        );
+
+        self.decode_mcus(mcus, blocks)
+    }
 
-            self.cursor += len;
+    /// Every MCU within the given MCU rows and columns of the frame, as its index within the scan
+    /// along with the blocks to decode.
+    fn mcus(
+        &self,
+        frame_header: &FrameHeader,
+        mcu_rows: Range<usize>,
+        mcu_columns: Range<usize>,
+    ) -> Vec<(usize, MCUBlocks)> {
+        match self.scan_header.encoding_order {
+            EncodingOrder::NonInterleaved => {
+                // A non-interleaved scan only codes the blocks covering the component itself,
+                // one block per MCU.
+                let component = &frame_header.components[self.scan_components[0].component_idx];
+                let (width, height) = component.dimensions(frame_header);
+                let (h, v) = (
+                    component.horizontal_scaling_factor as usize,
+                    component.vertical_scaling_factor as usize,
+                );
+                let (blocks_per_line, blocks_per_column) = (width.div_ceil(8), height.div_ceil(8));
+
+                let rows = mcu_rows.start * v..(mcu_rows.end * v).min(blocks_per_column);
+                let columns = mcu_columns.start * h..(mcu_columns.end * h).min(blocks_per_line);
+
+                rows.flat_map(|row| {
+                    columns
+                        .clone()
+                        .map(move |column| (row * blocks_per_line + column, vec![(0, row, column)]))
+                })
+                .collect()
+            }
+            EncodingOrder::Interleaved => {
+                let sampling_factors: Vec<_> = self
+                    .scan_components
+                    .iter()
+                    .map(|scan_component| {
+                        let component = &frame_header.components[scan_component.component_idx];
+                        (
+                            component.horizontal_scaling_factor as usize,
+                            component.vertical_scaling_factor as usize,
+                        )
+                    })
+                    .collect();
+
+                let mcus_per_line = frame_header.mcus_per_line();
+
+                mcu_rows
+                    .flat_map(|mcu_row| {
+                        mcu_columns
+                            .clone()
+                            .map(move |mcu_column| (mcu_row, mcu_column))
+                    })
+                    .map(|(mcu_row, mcu_column)| {
+                        let mcu = sampling_factors
+                            .iter()
+                            .enumerate()
+                            .flat_map(|(scan_idx, &(h, v))| {
+                                (0..v).flat_map(move |y| {
+                                    (0..h).map(move |x| {
+                                        (scan_idx, mcu_row * v + y, mcu_column * h + x)
+                                    })
+                                })
+                            })
+                            .collect();
+
+                        (mcu_row * mcus_per_line + mcu_column, mcu)
+                    })
+                    .collect()
+            }
         }
-
-        Ok(unzigzagged)
     }
 
-    pub(crate) fn decode(&mut self) -> Result<Vec<(u8, u8, u8)>> {
-        let uncompressed_image_data = match &self.entropy_coding {
-            EntropyCoding::Huffman(_) => self.decode_huffman(),
-            EntropyCoding::Arithmetic(_) => todo!(),
-        }?;
+    fn decode_mcus(
+        &mut self,
+        mcus: Vec<(usize, MCUBlocks)>,
+        blocks: &mut [ComponentBlocks],
+    ) -> Result<()> {
+        for (mcu_index, mcu) in mcus {
+            if self.exhausted {
+                break;
+            }
+
+            let decoded = self
+                .skip_to(mcu_index)
+                .and_then(|_| self.decode_mcu(&mcu, Some(blocks)));
+
+            if let Err(e) = decoded {
+                match self.strict {
+                    true => return Err(e),
+                    false => {
+                        warn!("stopped decoding the scan early: {e}");
+                        self.exhausted = true;
+                    }
+                }
+            }
+        }
 
-        Ok(uncompressed_image_data)
+        Ok(())
     }
 
-    fn decode_huffman(&mut self) -> Result<Vec<(u8, u8, u8)>> {
-        let mut image_data = vec![];
-        let huffman_map = self.entropy_coding.huffman_map();
+    /// Advances the decoder to the MCU at `mcu_index`. When restart markers are present, the
+    /// intervals in between are jumped over without being decoded. The MCUs skipped are decoded
+    /// from scratch, so this is only sound for scans that do not refine earlier ones.
+    fn skip_to(&mut self, mcu_index: usize) -> Result<()> {
+        if let Some(interval) = mcu_index.checked_div(self.restart_interval) {
+            let interval_start = interval * self.restart_interval;
+
+            if interval_start > self.mcus_decoded {
+                if let Some(&offset) = self.restart_offsets.get(interval - 1) {
+                    // the restart marker itself is consumed along with the first MCU.
+                    self.reader.byte_cur = offset;
+                    self.reader.bit_cur = 0;
+                    self.mcus_decoded = interval_start;
+                }
+            }
+        }
 
-        let ac_dc_destination_ids: Vec<_> = self
-            .scan_header
-            .scan_component_selectors
+        let skipped: Vec<_> = self
+            .mcu_layout
             .iter()
-            .map(|s| (s.dc_destination_id, s.ac_destination_id))
+            .map(|&scan_idx| (scan_idx, 0, 0))
             .collect();
 
-        let mut component_ptr = 0;
-        let mut num_coeffs = 0;
-
-        let mut node_cursor = *huffman_map
-            .get(&(HuffmanClass::DC, ac_dc_destination_ids[component_ptr].0))
-            .ok_or(anyhow!(format!(
-                "failed to find a component with id: {component_ptr}"
-            )))?;
-
-        let mut component_batch = vec![];
-        while self.cursor < self.data.len() {
-            if let Some(node) = node_cursor {
-                unsafe {
-                    if (*node.as_ptr()).code != u8::MAX {
-                        component_batch.push((*node.as_ptr()).code);
-                        component_ptr += 1;
-
-                        if component_ptr == ac_dc_destination_ids.len() {
-                            component_ptr = 0;
-                            num_coeffs += 1;
-
-                            debug_assert_eq!(component_batch.len(), 3);
-                            image_data.push((
-                                component_batch[0],
-                                component_batch[1],
-                                component_batch[2],
-                            ));
-                            component_batch.clear();
-                        }
+        while self.mcus_decoded < mcu_index {
+            self.decode_mcu(&skipped, None)?;
+        }
 
-                        let (next_class, next_destination_id) = if num_coeffs % 64 == 0 {
-                            (HuffmanClass::DC, ac_dc_destination_ids[component_ptr].0)
-                        } else {
-                            (HuffmanClass::AC, ac_dc_destination_ids[component_ptr].1)
-                        };
+        Ok(())
+    }
+
+    /// Decodes a single MCU into `blocks` when given, otherwise into scratch blocks.
+    fn decode_mcu(
+        &mut self,
+        mcu: &MCUBlocks,
+        mut blocks: Option<&mut [ComponentBlocks]>,
+    ) -> Result<()> {
+        if self.restart_interval > 0
+            && self.mcus_decoded > 0
+            && self.mcus_decoded.is_multiple_of(self.restart_interval)
+        {
+            let expected = ((self.mcus_decoded / self.restart_interval - 1) % 8) as u8;
+            self.restart(expected)?;
+            self.predictions.iter_mut().for_each(|p| *p = 0);
+            self.eob_run = 0;
+        }
+
+        let mut scratch: [i16; 64];
+
+        for &(scan_idx, row, column) in mcu {
+            let block = match blocks.as_deref_mut() {
+                Some(blocks) => {
+                    let component = &mut blocks[self.scan_components[scan_idx].component_idx];
+                    let idx = (row - component.first_row) * component.blocks_per_line
+                        + (column - component.first_column);
+
+                    &mut component.blocks[idx]
+                }
+                None => {
+                    scratch = [0; 64];
+                    &mut scratch
+                }
+            };
+
+            self.decode_block(scan_idx, block)?;
+        }
+
+        self.mcus_decoded += 1;
+
+        Ok(())
+    }
+
+    /// Decodes the coefficients of a block coded in the scan, in zig-zag order.
+    fn decode_block(&mut self, scan_idx: usize, block: &mut [i16; 64]) -> Result<()> {
+        let ScanHeader {
+            start_of_spectral,
+            successive_approx_bit_position_high,
+            ..
+        } = self.scan_header;
+
+        match (start_of_spectral, successive_approx_bit_position_high) {
+            (0, 0) => {
+                self.decode_dc_first(scan_idx, block)?;
+
+                // a sequential scan codes the AC coefficients along with the DC coefficient.
+                if self.scan_header.end_of_spectral > 0 {
+                    self.decode_ac_first(scan_idx, block)?;
+                }
+
+                Ok(())
+            }
+            (0, _) => self.decode_dc_refine(block),
+            (_, 0) => self.decode_ac_first(scan_idx, block),
+            (_, _) => self.decode_ac_refine(scan_idx, block),
+        }
+    }
+
+    /// (F.2.2.1, G.1.2.1) Decodes the DC difference of a block, scaled by the point transform.
+    fn decode_dc_first(&mut self, scan_idx: usize, block: &mut [i16; 64]) -> Result<()> {
+        let t = self.decode_symbol(self.scan_components[scan_idx].dc_table)?;
+        let diff = self.receive_extend(t)?;
+
+        self.predictions[scan_idx] += diff;
+        block[0] = (self.predictions[scan_idx] << self.scan_header.point_transform) as i16;
+
+        Ok(())
+    }
+
+    /// (G.1.2.1) A DC refinement scan codes the next bit of every DC coefficient as is.
+    fn decode_dc_refine(&mut self, block: &mut [i16; 64]) -> Result<()> {
+        if self.reader.read_bit()? == 1 {
+            block[0] |= 1 << self.scan_header.point_transform;
+        }
+
+        Ok(())
+    }
+
+    /// (F.2.2.2, G.1.2.2) Decodes the AC coefficients of the band Ss..=Se. In a sequential scan
+    /// the band is every AC coefficient and there are no end-of-band runs.
+    fn decode_ac_first(&mut self, scan_idx: usize, block: &mut [i16; 64]) -> Result<()> {
+        if self.eob_run > 0 {
+            self.eob_run -= 1;
+            return Ok(());
+        }
+
+        let ac_table = self.scan_components[scan_idx].ac_table;
+        let end = self.scan_header.end_of_spectral as usize;
+        let point_transform = self.scan_header.point_transform;
+
+        let mut k = (self.scan_header.start_of_spectral as usize).max(1);
+        while k <= end {
+            let rs = self.decode_symbol(ac_table)?;
+            let (r, s) = (rs >> 4, rs & 0b1111);
+
+            if s == 0 {
+                match r {
+                    15 => {
+                        k += 16;
+                        continue;
+                    }
+                    _ => {
+                        // EOBr: this block and 2^r - 1 + the next r bits more end here.
+                        self.eob_run = (1 << r) - 1 + self.reader.read_bits(r)? as usize;
+                        break;
+                    }
+                }
+            }
+
+            k += r as usize;
+            if k > end {
+                return Err(anyhow!("coefficient run extends past the end of the band"));
+            }
+
+            block[k] = (self.receive_extend(s)? << point_transform) as i16;
+            k += 1;
+        }
 
-                        node_cursor =
-                            *huffman_map
-                                .get(&(next_class, next_destination_id))
-                                .ok_or(anyhow!(format!(
-                                    "failed to find a component with id: {component_ptr}"
-                                )))?;
+        Ok(())
+    }
+
+    /// (G.1.2.3) Refines the AC coefficients of the band Ss..=Se by one bit. Coefficients that
+    /// are already nonzero get a correction bit, and newly nonzero ones are coded like in the
+    /// first scan of the band, with runs counting only the coefficients that are still zero.
+    fn decode_ac_refine(&mut self, scan_idx: usize, block: &mut [i16; 64]) -> Result<()> {
+        let ac_table = self.scan_components[scan_idx].ac_table;
+        let end = self.scan_header.end_of_spectral as usize;
+        let (positive, negative) = (
+            1i16 << self.scan_header.point_transform,
+            -1i16 << self.scan_header.point_transform,
+        );
+
+        let mut k = self.scan_header.start_of_spectral as usize;
+
+        if self.eob_run == 0 {
+            while k <= end {
+                let rs = self.decode_symbol(ac_table)?;
+                let (mut r, s) = ((rs >> 4) as i32, rs & 0b1111);
+
+                let value = match s {
+                    0 if r < 15 => {
+                        self.eob_run = (1 << r) + self.reader.read_bits(r as u8)? as usize;
+                        break;
+                    }
+                    0 => 0,
+                    _ => match self.reader.read_bit()? {
+                        1 => positive,
+                        _ => negative,
+                    },
+                };
+
+                while k <= end {
+                    if block[k] != 0 {
+                        self.refine(&mut block[k], positive, negative)?;
                     } else {
-                        match self.data[self.cursor] {
-                            0 => {
-                                node_cursor = (*node.as_ptr()).left;
-                            }
-                            1 => {
-                                node_cursor = (*node.as_ptr()).right;
-                            }
-                            _ => unreachable!(),
-                        };
+                        r -= 1;
+                        if r < 0 {
+                            break;
+                        }
                     }
+
+                    k += 1;
+                }
+
+                if value != 0 {
+                    if k > end {
+                        return Err(anyhow!("coefficient run extends past the end of the band"));
+                    }
+
+                    block[k] = value;
+                }
+
+                k += 1;
+            }
+        }
+
+        if self.eob_run > 0 {
+            // the rest of the band has no new coefficients, but the nonzero ones are refined.
+            while k <= end {
+                if block[k] != 0 {
+                    self.refine(&mut block[k], positive, negative)?;
+                }
+
+                k += 1;
+            }
+
+            self.eob_run -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the correction bit of a coefficient that is already nonzero, away from zero.
+    fn refine(&mut self, coefficient: &mut i16, positive: i16, negative: i16) -> Result<()> {
+        if self.reader.read_bit()? == 1 && *coefficient & positive == 0 {
+            *coefficient += match *coefficient >= 0 {
+                true => positive,
+                false => negative,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// (F.2.2.3) Walks the huffman tree one bit at a time until it reaches a leaf.
+    fn decode_symbol(&mut self, root: NPtr) -> Result<u8> {
+        let mut node_cursor = root;
+
+        while let Some(node) = node_cursor {
+            unsafe {
+                if (*node.as_ptr()).is_leaf() {
+                    return Ok((*node.as_ptr()).code);
                 }
+
+                node_cursor = match self.reader.read_bit()? {
+                    0 => (*node.as_ptr()).left,
+                    _ => (*node.as_ptr()).right,
+                };
             }
+        }
+
+        Err(anyhow!("invalid huffman code"))
+    }
 
-            self.cursor += 1;
+    /// (F.2.2.1) Receives `ssss` additional bits and extends them into a signed value.
+    fn receive_extend(&mut self, ssss: u8) -> Result<i32> {
+        if ssss > 16 {
+            return Err(anyhow!("expected at most 16 additional bits. Got {ssss}"));
         }
 
-        Ok(image_data)
+        let v = self.reader.read_bits(ssss)? as i32;
+
+        match ssss > 0 && v < 1 << (ssss - 1) {
+            true => Ok(v - (1 << ssss) + 1),
+            false => Ok(v),
+        }
+    }
+
+    /// Skips the padding bits at the end of a restart interval and consumes the RSTm marker that
+    /// follows it.
+    fn restart(&mut self, expected: u8) -> Result<()> {
+        self.reader.align();
+        let aligned = self.reader.byte_cur;
+
+        let mut byte = self.reader.read_bits(8)? as u8;
+        if byte == Marker::GLOBAL as u8 {
+            // any number of fill bytes may precede a marker.
+            while byte == Marker::GLOBAL as u8 {
+                byte = self.reader.read_bits(8)? as u8;
+            }
+
+            if byte == Marker::RST0 as u8 + expected {
+                return Ok(());
+            }
+        }
+
+        match self.strict {
+            true => Err(anyhow!("expected restart marker RST{expected}")),
+            false => {
+                warn!("expected restart marker RST{expected}, got {byte:#04X}");
+                self.reader.byte_cur = aligned;
+                Ok(())
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::decoder::SharedBuffer;
     use crate::frame_header::ComponentType;
-    use crate::scan_header::EncodingOrder;
+    use crate::huffman_tree::HuffmanTree;
 
     use super::*;
 
     #[test]
     fn test_zigzag() -> Result<()> {
+        let data: [i16; 64] = std::array::from_fn(|idx| idx as i16);
+
+        let unzigzagged = EntropyDecoder::zigzag(data);
+
+        assert_eq!(&unzigzagged[..10], &[0, 1, 5, 6, 14, 15, 27, 28, 2, 4]);
+        for (idx, coefficient) in unzigzagged.iter().enumerate() {
+            assert_eq!(*coefficient as usize, EntropyDecoder::ZIGZAG_TABLE[idx]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive_extend() -> Result<()> {
+        let mut entropy_decoder = EntropyDecoder {
+            reader: BitReader::new(SharedBuffer::new(vec![0b1010_1000])),
+            scan_header: ScanHeader {
+                encoding_order: EncodingOrder::NonInterleaved,
+                component_type: ComponentType::Grayscale,
+                scan_component_selectors: vec![],
+                start_of_spectral: 0,
+                end_of_spectral: 63,
+                successive_approx_bit_position_high: 0,
+                point_transform: 0,
+            },
+            _entropy_coding: EntropyCoding::Huffman(vec![]),
+            restart_interval: 0,
+            restart_offsets: vec![],
+            strict: true,
+            scan_components: vec![],
+            mcu_layout: vec![],
+            predictions: vec![],
+            eob_run: 0,
+            mcus_decoded: 0,
+            exhausted: false,
+        };
+
+        assert!(entropy_decoder.receive_extend(17).is_err());
+        assert_eq!(entropy_decoder.receive_extend(3)?, 5);
+        assert_eq!(entropy_decoder.receive_extend(3)?, -5);
+        assert!(entropy_decoder.receive_extend(3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_ac_refine() -> Result<()> {
+        // (0, 1) is coded as 0 and the end of band as 10.
+        let tree = HuffmanTree::from(1, 0, vec![(0x11, 1), (0x00, 2)]);
+        let ac_table = tree.root;
+
+        let mut entropy_decoder = EntropyDecoder {
+            // (0, 1), a positive sign, a correction bit for the nonzero coefficient skipped
+            // over, then the end of band.
+            reader: BitReader::new(SharedBuffer::new(vec![0b0111_0111])),
+            scan_header: ScanHeader {
+                encoding_order: EncodingOrder::NonInterleaved,
+                component_type: ComponentType::Grayscale,
+                scan_component_selectors: vec![],
+                start_of_spectral: 1,
+                end_of_spectral: 5,
+                successive_approx_bit_position_high: 2,
+                point_transform: 1,
+            },
+            _entropy_coding: EntropyCoding::Huffman(vec![tree]),
+            restart_interval: 0,
+            restart_offsets: vec![],
+            strict: true,
+            scan_components: vec![ScanComponent {
+                component_idx: 0,
+                dc_table: None,
+                ac_table,
+            }],
+            mcu_layout: vec![0],
+            predictions: vec![0],
+            eob_run: 0,
+            mcus_decoded: 0,
+            exhausted: false,
+        };
+
+        let mut block = [0i16; 64];
+        block[1] = -4;
+
+        entropy_decoder.decode_block(0, &mut block)?;
+        assert_eq!(&block[..6], &[0, -6, 0, 2, 0, 0]);
+        assert_eq!(entropy_decoder.eob_run, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart() -> Result<()> {
+        // three bits of an interval, padded with ones, a fill byte and RST1.
         let mut entropy_decoder = EntropyDecoder {
-            data: &[],
-            cursor: 0,
+            reader: BitReader::new(SharedBuffer::new(vec![0b1011_1111, 0xFF, 0xFF, 0xD1, 0xAB])),
             scan_header: ScanHeader {
                 encoding_order: EncodingOrder::NonInterleaved,
                 component_type: ComponentType::Grayscale,
                 scan_component_selectors: vec![],
                 start_of_spectral: 0,
-                end_of_spectral: 0,
+                end_of_spectral: 63,
                 successive_approx_bit_position_high: 0,
                 point_transform: 0,
             },
-            entropy_coding: EntropyCoding::Huffman(vec![]),
+            _entropy_coding: EntropyCoding::Huffman(vec![]),
+            restart_interval: 1,
+            restart_offsets: vec![],
+            strict: true,
+            scan_components: vec![],
+            mcu_layout: vec![],
+            predictions: vec![],
+            eob_run: 0,
+            mcus_decoded: 0,
+            exhausted: false,
         };
 
-        let data = vec![
-            (0, 0, 0),
-            (1, 1, 1),
-            (2, 2, 2),
-            (3, 3, 3),
-            (4, 4, 4),
-            (5, 5, 5),
-            (6, 6, 6),
-            (7, 7, 7),
-            (8, 8, 8),
-            (9, 9, 9),
-            (10, 10, 10),
-            (11, 11, 11),
-            (12, 12, 12),
-            (13, 13, 13),
-            (14, 14, 14),
-            (15, 15, 15),
-            (16, 16, 16),
-            (17, 17, 17),
-            (18, 18, 18),
-            (19, 19, 19),
-            (20, 20, 20),
-            (21, 21, 21),
-            (22, 22, 22),
-            (23, 23, 23),
-            (24, 24, 24),
-            (25, 25, 25),
-            (26, 26, 26),
-            (27, 27, 27),
-            (28, 28, 28),
-            (29, 29, 29),
-            (30, 30, 30),
-            (31, 31, 31),
-            (32, 32, 32),
-            (33, 33, 33),
-            (34, 34, 34),
-            (35, 35, 35),
-            (36, 36, 36),
-            (37, 37, 37),
-            (38, 38, 38),
-            (39, 39, 39),
-            (40, 40, 40),
-            (41, 41, 41),
-            (42, 42, 42),
-            (43, 43, 43),
-            (44, 44, 44),
-            (45, 45, 45),
-            (46, 46, 46),
-            (47, 47, 47),
-            (48, 48, 48),
-            (49, 49, 49),
-            (50, 50, 50),
-            (51, 51, 51),
-            (52, 52, 52),
-            (53, 53, 53),
-            (54, 54, 54),
-            (55, 55, 55),
-            (56, 56, 56),
-            (57, 57, 57),
-            (58, 58, 58),
-            (59, 59, 59),
-            (60, 60, 60),
-            (61, 61, 61),
-            (62, 62, 62),
-            (63, 63, 63),
-        ];
-
-        let unzigzagged = entropy_decoder.zigzag(data)?;
-
-        println!("{:?}", unzigzagged);
+        entropy_decoder.reader.bit_cur = 3;
+        entropy_decoder.restart(1)?;
+        assert_eq!(entropy_decoder.reader.read_bits(8)?, 0xAB);
+
+        // a missing marker is an error when strict, and otherwise decoding resumes where the
+        // padding ends.
+        (
+            entropy_decoder.reader.byte_cur,
+            entropy_decoder.reader.bit_cur,
+        ) = (0, 3);
+        assert!(entropy_decoder.restart(2).is_err());
+
+        (
+            entropy_decoder.reader.byte_cur,
+            entropy_decoder.reader.bit_cur,
+        ) = (0, 3);
+        entropy_decoder.strict = false;
+        entropy_decoder.restart(2)?;
+        assert_eq!(entropy_decoder.reader.byte_cur, 1);
 
         Ok(())
     }
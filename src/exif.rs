@@ -0,0 +1,1186 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+
+/// The tags read by the helpers of `Exif`, along with the pointers to other IFDs.
+pub mod tag {
+    pub const MAKE: u16 = 0x010F;
+    pub const MODEL: u16 = 0x0110;
+    pub const ORIENTATION: u16 = 0x0112;
+    pub const DATE_TIME: u16 = 0x0132;
+    pub const EXIF_IFD_POINTER: u16 = 0x8769;
+    pub const GPS_IFD_POINTER: u16 = 0x8825;
+    pub const INTEROP_IFD_POINTER: u16 = 0xA005;
+    pub const JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+    pub const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+
+    /// Tags whose values are offsets into the TIFF structure that `Exif::to_segment` cannot
+    /// relocate. Maker notes hold offsets of their own.
+    pub const STRIP_OFFSETS: u16 = 0x0111;
+    pub const FREE_OFFSETS: u16 = 0x0120;
+    pub const TILE_OFFSETS: u16 = 0x0144;
+    pub const SUB_IFDS: u16 = 0x014A;
+    pub const MAKER_NOTE: u16 = 0x927C;
+
+    pub const EXPOSURE_TIME: u16 = 0x829A;
+    pub const F_NUMBER: u16 = 0x829D;
+    pub const ISO_SPEED_RATINGS: u16 = 0x8827;
+    pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+    pub const FOCAL_LENGTH: u16 = 0x920A;
+
+    pub const GPS_LATITUDE_REF: u16 = 0x0001;
+    pub const GPS_LATITUDE: u16 = 0x0002;
+    pub const GPS_LONGITUDE_REF: u16 = 0x0003;
+    pub const GPS_LONGITUDE: u16 = 0x0004;
+    pub const GPS_ALTITUDE_REF: u16 = 0x0005;
+    pub const GPS_ALTITUDE: u16 = 0x0006;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// "II"
+    LittleEndian,
+    /// "MM"
+    BigEndian,
+}
+
+impl ByteOrder {
+    pub(crate) fn u16(&self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub(crate) fn u32(&self, bytes: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn u64(&self, bytes: [u8; 8]) -> u64 {
+        match self {
+            ByteOrder::LittleEndian => u64::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u64::from_be_bytes(bytes),
+        }
+    }
+
+    fn u16_bytes(&self, value: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::LittleEndian => value.to_le_bytes(),
+            ByteOrder::BigEndian => value.to_be_bytes(),
+        }
+    }
+
+    fn u32_bytes(&self, value: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::LittleEndian => value.to_le_bytes(),
+            ByteOrder::BigEndian => value.to_be_bytes(),
+        }
+    }
+
+    fn u64_bytes(&self, value: u64) -> [u8; 8] {
+        match self {
+            ByteOrder::LittleEndian => value.to_le_bytes(),
+            ByteOrder::BigEndian => value.to_be_bytes(),
+        }
+    }
+}
+
+/// The IFDs of the TIFF structure within an EXIF segment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IfdKind {
+    /// IFD0, describing the primary image.
+    Primary,
+    Exif,
+    GPS,
+    Interop,
+    /// IFD1, describing the thumbnail.
+    Thumbnail,
+}
+
+/// The value of a field, decoded according to its TIFF type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(Vec<u8>),
+    /// Trailing NUL bytes are removed.
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+
+    /// A type TIFF 6.0 does not define: its type number, count and the four bytes of the entry
+    /// that hold the value or its offset. The size of its elements is unknown, so whether the
+    /// bytes are an offset is too, and `Exif::to_segment` rejects it unless its count is zero.
+    Unknown(u16, u32, [u8; 4]),
+}
+
+impl Value {
+    /// The size in bytes of one element of every TIFF type, by type number.
+    fn element_size(field_type: u16) -> Option<usize> {
+        match field_type {
+            1 | 2 | 6 | 7 => Some(1),
+            3 | 8 => Some(2),
+            4 | 9 | 11 => Some(4),
+            5 | 10 | 12 => Some(8),
+            _ => None,
+        }
+    }
+
+    fn parse(field_type: u16, bytes: &[u8], byte_order: ByteOrder) -> Value {
+        let u16s = || bytes.chunks_exact(2).map(|b| byte_order.u16([b[0], b[1]]));
+        let u32s = || {
+            bytes
+                .chunks_exact(4)
+                .map(|b| byte_order.u32([b[0], b[1], b[2], b[3]]))
+        };
+        let rationals = || {
+            bytes.chunks_exact(8).map(|b| {
+                (
+                    byte_order.u32([b[0], b[1], b[2], b[3]]),
+                    byte_order.u32([b[4], b[5], b[6], b[7]]),
+                )
+            })
+        };
+
+        match field_type {
+            1 => Value::Byte(bytes.to_vec()),
+            2 => Value::Ascii(
+                String::from_utf8_lossy(bytes)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            ),
+            3 => Value::Short(u16s().collect()),
+            4 => Value::Long(u32s().collect()),
+            5 => Value::Rational(rationals().collect()),
+            6 => Value::SByte(bytes.iter().map(|b| *b as i8).collect()),
+            8 => Value::SShort(u16s().map(|v| v as i16).collect()),
+            9 => Value::SLong(u32s().map(|v| v as i32).collect()),
+            10 => Value::SRational(rationals().map(|(n, d)| (n as i32, d as i32)).collect()),
+            11 => Value::Float(u32s().map(f32::from_bits).collect()),
+            12 => Value::Double(
+                bytes
+                    .chunks_exact(8)
+                    .map(|b| f64::from_bits(byte_order.u64(b.try_into().unwrap())))
+                    .collect(),
+            ),
+            _ => Value::Undefined(bytes.to_vec()),
+        }
+    }
+
+    /// The TIFF type number, the number of elements and the bytes of the value. ASCII values are
+    /// terminated with a NUL byte.
+    fn encode(&self, byte_order: ByteOrder) -> (u16, u32, Vec<u8>) {
+        let rationals = |v: &[(u32, u32)]| -> Vec<u8> {
+            v.iter()
+                .flat_map(|(n, d)| [byte_order.u32_bytes(*n), byte_order.u32_bytes(*d)])
+                .flatten()
+                .collect()
+        };
+
+        let (field_type, count, bytes) = match self {
+            Value::Byte(v) => (1, v.len(), v.clone()),
+            Value::Ascii(s) => (2, s.len() + 1, [s.as_bytes(), &[0]].concat()),
+            Value::Short(v) => (
+                3,
+                v.len(),
+                v.iter().flat_map(|v| byte_order.u16_bytes(*v)).collect(),
+            ),
+            Value::Long(v) => (
+                4,
+                v.len(),
+                v.iter().flat_map(|v| byte_order.u32_bytes(*v)).collect(),
+            ),
+            Value::Rational(v) => (5, v.len(), rationals(v)),
+            Value::SByte(v) => (6, v.len(), v.iter().map(|v| *v as u8).collect()),
+            Value::Undefined(v) => (7, v.len(), v.clone()),
+            Value::SShort(v) => (
+                8,
+                v.len(),
+                v.iter()
+                    .flat_map(|v| byte_order.u16_bytes(*v as u16))
+                    .collect(),
+            ),
+            Value::SLong(v) => (
+                9,
+                v.len(),
+                v.iter()
+                    .flat_map(|v| byte_order.u32_bytes(*v as u32))
+                    .collect(),
+            ),
+            Value::SRational(v) => {
+                let v: Vec<_> = v.iter().map(|(n, d)| (*n as u32, *d as u32)).collect();
+                (10, v.len(), rationals(&v))
+            }
+            Value::Float(v) => (
+                11,
+                v.len(),
+                v.iter()
+                    .flat_map(|v| byte_order.u32_bytes(v.to_bits()))
+                    .collect(),
+            ),
+            Value::Double(v) => (
+                12,
+                v.len(),
+                v.iter()
+                    .flat_map(|v| byte_order.u64_bytes(v.to_bits()))
+                    .collect(),
+            ),
+            // only fields without elements are written, see `Exif::to_segment`.
+            Value::Unknown(field_type, count, _) => {
+                return (*field_type, *count, vec![]);
+            }
+        };
+
+        (field_type, count as u32, bytes)
+    }
+
+    /// The first element of an unsigned integer value.
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::Byte(v) => v.first().map(|v| *v as u32),
+            Value::Short(v) => v.first().map(|v| *v as u32),
+            Value::Long(v) => v.first().copied(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Ascii(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Every element of a numeric value as a float, with rationals divided out.
+    pub fn to_f64s(&self) -> Vec<f64> {
+        match self {
+            Value::Byte(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::Short(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::Long(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::Rational(v) => v.iter().map(|(n, d)| *n as f64 / *d as f64).collect(),
+            Value::SByte(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::SShort(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::SLong(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::SRational(v) => v.iter().map(|(n, d)| *n as f64 / *d as f64).collect(),
+            Value::Float(v) => v.iter().map(|v| *v as f64).collect(),
+            Value::Double(v) => v.clone(),
+            Value::Ascii(_) | Value::Undefined(_) | Value::Unknown(..) => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub tag: u16,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ifd {
+    pub kind: IfdKind,
+    pub fields: Vec<Field>,
+}
+
+impl Ifd {
+    pub fn get(&self, tag: u16) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|field| field.tag == tag)
+            .map(|field| &field.value)
+    }
+}
+
+/// A date and time as recorded by the camera, without a time zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Parses the "YYYY:MM:DD HH:MM:SS" format of EXIF. Fields out of their range, such as a
+    /// month of 0 or an hour of 24, are rejected.
+    pub fn parse(value: &str) -> Option<Self> {
+        let fields: Vec<_> = value
+            .split([':', ' '])
+            .map(|field| field.trim().parse::<u16>().ok())
+            .collect::<Option<_>>()?;
+
+        let [year, month, day, hour, minute, second] = fields[..] else {
+            return None;
+        };
+
+        let date_time = DateTime {
+            year,
+            month: u8::try_from(month).ok()?,
+            day: u8::try_from(day).ok()?,
+            hour: u8::try_from(hour).ok()?,
+            minute: u8::try_from(minute).ok()?,
+            second: u8::try_from(second).ok()?,
+        };
+
+        let in_range = (1..=12).contains(&date_time.month)
+            && (1..=31).contains(&date_time.day)
+            && date_time.hour < 24
+            && date_time.minute < 60
+            && date_time.second < 60;
+
+        in_range.then_some(date_time)
+    }
+}
+
+/// The TIFF structure of an EXIF APP1 segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exif {
+    pub byte_order: ByteOrder,
+    pub ifds: Vec<Ifd>,
+
+    /// The TIFF structure itself, which the offsets of the fields are relative to.
+    pub(crate) tiff: Vec<u8>,
+}
+
+impl Exif {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"Exif\0\0";
+
+    /// Parses the payload of an APP1 segment that starts with the EXIF identifier.
+    pub(crate) fn parse(segment: &[u8]) -> Result<Self> {
+        let tiff = segment
+            .strip_prefix(Self::IDENTIFIER)
+            .ok_or(anyhow!("expected an EXIF identifier"))?;
+
+        let mut visited = HashSet::new();
+        let (mut exif, ifd1_offset) = Self::parse_tiff(tiff, &mut visited)?;
+
+        for (tag, kind) in [
+            (tag::EXIF_IFD_POINTER, IfdKind::Exif),
+            (tag::GPS_IFD_POINTER, IfdKind::GPS),
+        ] {
+            if let Some(offset) = exif.get(IfdKind::Primary, tag).and_then(Value::as_u32) {
+                exif.read_ifd(kind, offset as usize, &mut visited)?;
+            }
+        }
+
+        if let Some(offset) = exif
+            .get(IfdKind::Exif, tag::INTEROP_IFD_POINTER)
+            .and_then(Value::as_u32)
+        {
+            exif.read_ifd(IfdKind::Interop, offset as usize, &mut visited)?;
+        }
+
+        if ifd1_offset != 0 {
+            exif.read_ifd(IfdKind::Thumbnail, ifd1_offset, &mut visited)?;
+        }
+
+        Ok(exif)
+    }
+
+    /// Reads the TIFF header and IFD0 of a TIFF structure, and returns the offset of the next
+    /// IFD. Other segments, such as MPF, lay out their IFDs the same way.
+    pub(crate) fn parse_tiff(tiff: &[u8], visited: &mut HashSet<usize>) -> Result<(Self, usize)> {
+        if tiff.len() < 8 {
+            return Err(anyhow!("expected a TIFF header"));
+        }
+
+        let byte_order = match &tiff[..2] {
+            b"II" => ByteOrder::LittleEndian,
+            b"MM" => ByteOrder::BigEndian,
+            _ => return Err(anyhow!("unknown TIFF byte order {:?}", &tiff[..2])),
+        };
+
+        if byte_order.u16([tiff[2], tiff[3]]) != 42 {
+            return Err(anyhow!("expected the TIFF magic number 42"));
+        }
+
+        let mut exif = Exif {
+            byte_order,
+            ifds: vec![],
+            tiff: tiff.to_vec(),
+        };
+
+        let ifd0_offset = exif.read_u32(4)? as usize;
+        let ifd1_offset = exif.read_ifd(IfdKind::Primary, ifd0_offset, visited)?;
+
+        Ok((exif, ifd1_offset))
+    }
+
+    fn read_bytes(&self, offset: usize, length: usize) -> Result<&[u8]> {
+        self.tiff
+            .get(offset..offset + length)
+            .ok_or(anyhow!(format!(
+                "expected {length} bytes at offset {offset} of the TIFF structure"
+            )))
+    }
+
+    fn read_u16(&self, offset: usize) -> Result<u16> {
+        let bytes = self.read_bytes(offset, 2)?;
+        Ok(self.byte_order.u16([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&self, offset: usize) -> Result<u32> {
+        let bytes = self.read_bytes(offset, 4)?;
+        Ok(self
+            .byte_order
+            .u32([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads the fields of the IFD at `offset`, and returns the offset of the next IFD. Fields
+    /// with an unknown type are kept as `Value::Unknown`.
+    fn read_ifd(
+        &mut self,
+        kind: IfdKind,
+        offset: usize,
+        visited: &mut HashSet<usize>,
+    ) -> Result<usize> {
+        if !visited.insert(offset) {
+            return Err(anyhow!(format!(
+                "the {kind:?} IFD at offset {offset} was already read"
+            )));
+        }
+
+        let num_fields = self.read_u16(offset)? as usize;
+        let mut fields = Vec::with_capacity(num_fields);
+
+        for idx in 0..num_fields {
+            let entry = offset + 2 + idx * 12;
+
+            let tag = self.read_u16(entry)?;
+            let field_type = self.read_u16(entry + 2)?;
+            let count = self.read_u32(entry + 4)? as usize;
+
+            let Some(element_size) = Value::element_size(field_type) else {
+                let bytes = self.read_bytes(entry + 8, 4)?;
+                fields.push(Field {
+                    tag,
+                    value: Value::Unknown(field_type, count as u32, bytes.try_into()?),
+                });
+                continue;
+            };
+
+            // values of up to 4 bytes are stored in place of the offset.
+            let length = element_size * count;
+            let value_offset = match length <= 4 {
+                true => entry + 8,
+                false => self.read_u32(entry + 8)? as usize,
+            };
+
+            let bytes = self.read_bytes(value_offset, length)?;
+            fields.push(Field {
+                tag,
+                value: Value::parse(field_type, bytes, self.byte_order),
+            });
+        }
+
+        let next_ifd = self.read_u32(offset + 2 + num_fields * 12)? as usize;
+        self.ifds.push(Ifd { kind, fields });
+
+        Ok(next_ifd)
+    }
+
+    pub fn ifd(&self, kind: IfdKind) -> Option<&Ifd> {
+        self.ifds.iter().find(|ifd| ifd.kind == kind)
+    }
+
+    pub fn get(&self, kind: IfdKind, tag: u16) -> Option<&Value> {
+        self.ifd(kind).and_then(|ifd| ifd.get(tag))
+    }
+
+    /// The offset within the TIFF structure and the length of the JPEG thumbnail of IFD1.
+    pub(crate) fn thumbnail_location(&self) -> Option<(usize, usize)> {
+        let offset = self
+            .get(IfdKind::Thumbnail, tag::JPEG_INTERCHANGE_FORMAT)?
+            .as_u32()? as usize;
+        let length = self
+            .get(IfdKind::Thumbnail, tag::JPEG_INTERCHANGE_FORMAT_LENGTH)?
+            .as_u32()? as usize;
+
+        Some((offset, length))
+    }
+
+    /// The JPEG stream of the thumbnail of IFD1.
+    pub fn thumbnail(&self) -> Option<&[u8]> {
+        let (offset, length) = self.thumbnail_location()?;
+        self.tiff.get(offset..offset.checked_add(length)?)
+    }
+
+    /// Sets the value of a field, adding the IFD when there is none of its kind. An Interop IFD
+    /// is only reachable from the EXIF IFD, so an empty one is added along with it. The pointers
+    /// to other IFDs and to the thumbnail are recomputed by `to_segment`, so setting them has no
+    /// effect.
+    pub fn set(&mut self, kind: IfdKind, tag: u16, value: Value) {
+        if kind == IfdKind::Interop && self.ifd(IfdKind::Exif).is_none() {
+            self.ifds.push(Ifd {
+                kind: IfdKind::Exif,
+                fields: vec![],
+            });
+        }
+
+        let idx = match self.ifds.iter().position(|ifd| ifd.kind == kind) {
+            Some(idx) => idx,
+            None => {
+                self.ifds.push(Ifd {
+                    kind,
+                    fields: vec![],
+                });
+                self.ifds.len() - 1
+            }
+        };
+
+        let fields = &mut self.ifds[idx].fields;
+        match fields.iter_mut().find(|field| field.tag == tag) {
+            Some(field) => field.value = value,
+            None => {
+                let idx = fields.partition_point(|field| field.tag < tag);
+                fields.insert(idx, Field { tag, value });
+            }
+        }
+    }
+
+    /// Removes a field, and returns its value.
+    pub fn remove(&mut self, kind: IfdKind, tag: u16) -> Option<Value> {
+        let fields = &mut self.ifds.iter_mut().find(|ifd| ifd.kind == kind)?.fields;
+        let idx = fields.iter().position(|field| field.tag == tag)?;
+
+        Some(fields.remove(idx).value)
+    }
+
+    /// Removes an IFD, such as the GPS IFD to strip the location of the image. The Interop IFD
+    /// is removed along with the EXIF IFD that points to it, and the thumbnail along with IFD1.
+    pub fn remove_ifd(&mut self, kind: IfdKind) -> Option<Ifd> {
+        if kind == IfdKind::Exif {
+            self.ifds.retain(|ifd| ifd.kind != IfdKind::Interop);
+        }
+
+        let idx = self.ifds.iter().position(|ifd| ifd.kind == kind)?;
+        Some(self.ifds.remove(idx))
+    }
+
+    /// Lays out the IFDs as the payload of an APP1 segment, identifier included. IFD0 is followed
+    /// by the EXIF, Interop, GPS and IFD1 IFDs, each with the values that do not fit in place,
+    /// and then by the JPEG thumbnail. The pointers between them are recomputed. Fields holding
+    /// other offsets, such as strip offsets and maker notes, would be left pointing at the old
+    /// layout, so they are rejected and have to be removed first. So are fields of an unknown
+    /// type, whose values may be offsets.
+    pub fn to_segment(&self) -> Result<Vec<u8>> {
+        let thumbnail = self.thumbnail();
+        let byte_order = self.byte_order;
+
+        for ifd in &self.ifds {
+            if let Some(field) = ifd.fields.iter().find(|field| {
+                matches!(
+                    field.tag,
+                    tag::STRIP_OFFSETS
+                        | tag::FREE_OFFSETS
+                        | tag::TILE_OFFSETS
+                        | tag::SUB_IFDS
+                        | tag::MAKER_NOTE
+                ) || matches!(field.value, Value::Unknown(_, count, _) if count > 0)
+            }) {
+                return Err(anyhow!(format!(
+                    "the {:?} IFD holds tag {:#06X}, whose offsets cannot be relocated. Remove it before rewriting the segment",
+                    ifd.kind, field.tag
+                )));
+            }
+        }
+
+        if self.ifd(IfdKind::Interop).is_some() && self.ifd(IfdKind::Exif).is_none() {
+            return Err(anyhow!(
+                "the Interop IFD is only reachable from the EXIF IFD, which is missing"
+            ));
+        }
+
+        let mut ifds: Vec<Ifd> = [
+            IfdKind::Primary,
+            IfdKind::Exif,
+            IfdKind::Interop,
+            IfdKind::GPS,
+            IfdKind::Thumbnail,
+        ]
+        .into_iter()
+        .filter_map(|kind| match (kind, self.ifd(kind)) {
+            (IfdKind::Primary, None) => Some(Ifd {
+                kind,
+                fields: vec![],
+            }),
+            (_, ifd) => ifd.cloned(),
+        })
+        .collect();
+
+        // (the IFD holding the pointer, its tag, the IFD pointed to, or `None` for the thumbnail)
+        let kinds: Vec<_> = ifds.iter().map(|ifd| ifd.kind).collect();
+        let pointers: Vec<_> = [
+            (IfdKind::Primary, tag::EXIF_IFD_POINTER, Some(IfdKind::Exif)),
+            (IfdKind::Primary, tag::GPS_IFD_POINTER, Some(IfdKind::GPS)),
+            (
+                IfdKind::Exif,
+                tag::INTEROP_IFD_POINTER,
+                Some(IfdKind::Interop),
+            ),
+            (IfdKind::Thumbnail, tag::JPEG_INTERCHANGE_FORMAT, None),
+        ]
+        .into_iter()
+        .filter(|(parent, _, target)| {
+            kinds.contains(parent)
+                && match target {
+                    Some(target) => kinds.contains(target),
+                    None => thumbnail.is_some(),
+                }
+        })
+        .collect();
+
+        // none of these tags is defined in the GPS or Interop IFDs.
+        for ifd in &mut ifds {
+            ifd.fields.retain(|field| {
+                !matches!(
+                    field.tag,
+                    tag::EXIF_IFD_POINTER
+                        | tag::GPS_IFD_POINTER
+                        | tag::INTEROP_IFD_POINTER
+                        | tag::JPEG_INTERCHANGE_FORMAT
+                        | tag::JPEG_INTERCHANGE_FORMAT_LENGTH
+                )
+            });
+
+            for (parent, tag, _) in &pointers {
+                if *parent == ifd.kind {
+                    ifd.fields.push(Field {
+                        tag: *tag,
+                        value: Value::Long(vec![0]),
+                    });
+                }
+            }
+
+            if let (IfdKind::Thumbnail, Some(thumbnail)) = (ifd.kind, thumbnail) {
+                ifd.fields.push(Field {
+                    tag: tag::JPEG_INTERCHANGE_FORMAT_LENGTH,
+                    value: Value::Long(vec![thumbnail.len() as u32]),
+                });
+            }
+
+            // (TIFF 6.0 2) the fields of an IFD are sorted by tag.
+            ifd.fields.sort_by_key(|field| field.tag);
+        }
+
+        // the pointers are as long as the placeholders, so the layout does not change once they
+        // are filled in. Values are padded to start on a word boundary.
+        let ifd_size = |ifd: &Ifd| {
+            6 + 12 * ifd.fields.len()
+                + ifd
+                    .fields
+                    .iter()
+                    .map(|field| field.value.encode(byte_order).2.len())
+                    .filter(|length| *length > 4)
+                    .map(|length| length.next_multiple_of(2))
+                    .sum::<usize>()
+        };
+
+        let mut offsets = vec![8];
+        for ifd in &ifds {
+            offsets.push(offsets.last().unwrap() + ifd_size(ifd));
+        }
+        let offset_of = |kind: Option<IfdKind>| match kind {
+            Some(kind) => offsets[kinds.iter().position(|k| *k == kind).unwrap()],
+            None => *offsets.last().unwrap(),
+        };
+
+        for (parent, tag, target) in &pointers {
+            let ifd = &mut ifds[kinds.iter().position(|kind| kind == parent).unwrap()];
+            let field = ifd
+                .fields
+                .iter_mut()
+                .find(|field| field.tag == *tag)
+                .unwrap();
+            field.value = Value::Long(vec![offset_of(*target) as u32]);
+        }
+
+        let mut tiff = match byte_order {
+            ByteOrder::LittleEndian => b"II".to_vec(),
+            ByteOrder::BigEndian => b"MM".to_vec(),
+        };
+        tiff.extend(byte_order.u16_bytes(42));
+        tiff.extend(byte_order.u32_bytes(8));
+
+        for (idx, ifd) in ifds.iter().enumerate() {
+            let mut data_offset = offsets[idx] + 6 + 12 * ifd.fields.len();
+            let mut data = vec![];
+
+            tiff.extend(byte_order.u16_bytes(ifd.fields.len() as u16));
+            for field in &ifd.fields {
+                let (field_type, count, mut bytes) = field.value.encode(byte_order);
+
+                tiff.extend(byte_order.u16_bytes(field.tag));
+                tiff.extend(byte_order.u16_bytes(field_type));
+                tiff.extend(byte_order.u32_bytes(count));
+
+                match bytes.len() <= 4 {
+                    true => {
+                        bytes.resize(4, 0);
+                        tiff.extend(bytes);
+                    }
+                    false => {
+                        bytes.resize(bytes.len().next_multiple_of(2), 0);
+                        tiff.extend(byte_order.u32_bytes(data_offset as u32));
+                        data_offset += bytes.len();
+                        data.extend(bytes);
+                    }
+                }
+            }
+
+            // IFD0 is the only IFD that links to another, IFD1.
+            let next_ifd = match (ifd.kind, kinds.contains(&IfdKind::Thumbnail)) {
+                (IfdKind::Primary, true) => offset_of(Some(IfdKind::Thumbnail)),
+                _ => 0,
+            };
+            tiff.extend(byte_order.u32_bytes(next_ifd as u32));
+            tiff.extend(data);
+        }
+
+        if let (true, Some(thumbnail)) = (kinds.contains(&IfdKind::Thumbnail), thumbnail) {
+            tiff.extend(thumbnail);
+        }
+
+        // the length field of a segment counts itself.
+        let segment = [Self::IDENTIFIER, &tiff].concat();
+        if segment.len() + 2 > u16::MAX as usize {
+            return Err(anyhow!(format!(
+                "the EXIF segment of {} bytes does not fit in an APP1 segment",
+                segment.len()
+            )));
+        }
+
+        Ok(segment)
+    }
+
+    /// The orientation of the primary image, from 1 to 8 as in TIFF 6.0.
+    pub fn orientation(&self) -> Option<u16> {
+        self.get(IfdKind::Primary, tag::ORIENTATION)
+            .and_then(Value::as_u32)
+            .map(|orientation| orientation as u16)
+    }
+
+    /// When the picture was taken, or failing that, when the file was last changed.
+    pub fn capture_time(&self) -> Option<DateTime> {
+        self.get(IfdKind::Exif, tag::DATE_TIME_ORIGINAL)
+            .or(self.get(IfdKind::Primary, tag::DATE_TIME))
+            .and_then(Value::as_str)
+            .and_then(DateTime::parse)
+    }
+
+    pub fn make(&self) -> Option<&str> {
+        self.get(IfdKind::Primary, tag::MAKE)
+            .and_then(Value::as_str)
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.get(IfdKind::Primary, tag::MODEL)
+            .and_then(Value::as_str)
+    }
+
+    /// The exposure time in seconds, as the rational it was recorded as.
+    pub fn exposure_time(&self) -> Option<(u32, u32)> {
+        match self.get(IfdKind::Exif, tag::EXPOSURE_TIME)? {
+            Value::Rational(v) => v.first().copied(),
+            _ => None,
+        }
+    }
+
+    pub fn f_number(&self) -> Option<f64> {
+        self.get(IfdKind::Exif, tag::F_NUMBER)?
+            .to_f64s()
+            .first()
+            .copied()
+    }
+
+    pub fn iso(&self) -> Option<u32> {
+        self.get(IfdKind::Exif, tag::ISO_SPEED_RATINGS)
+            .and_then(Value::as_u32)
+    }
+
+    /// The latitude and longitude in decimal degrees, negative to the south and west.
+    pub fn gps_coordinates(&self) -> Option<(f64, f64)> {
+        let coordinate = |tag, reference_tag, negative| {
+            let degrees = match self.get(IfdKind::GPS, tag)?.to_f64s()[..] {
+                [degrees, minutes, seconds] => degrees + minutes / 60.0 + seconds / 3600.0,
+                _ => return None,
+            };
+
+            match self.get(IfdKind::GPS, reference_tag)?.as_str()? {
+                reference if reference == negative => Some(-degrees),
+                _ => Some(degrees),
+            }
+        };
+
+        Some((
+            coordinate(tag::GPS_LATITUDE, tag::GPS_LATITUDE_REF, "S")?,
+            coordinate(tag::GPS_LONGITUDE, tag::GPS_LONGITUDE_REF, "W")?,
+        ))
+    }
+
+    /// The altitude in meters, negative below sea level.
+    pub fn gps_altitude(&self) -> Option<f64> {
+        let altitude = *self
+            .get(IfdKind::GPS, tag::GPS_ALTITUDE)?
+            .to_f64s()
+            .first()?;
+
+        match self
+            .get(IfdKind::GPS, tag::GPS_ALTITUDE_REF)
+            .and_then(Value::as_u32)
+        {
+            Some(1) => Some(-altitude),
+            _ => Some(altitude),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (tag, type, count, value bytes in the byte order of the structure)
+    type TestField = (u16, u16, u32, Vec<u8>);
+
+    /// Lays out IFD0, the EXIF, Interop, GPS and IFD1 IFDs one after another, each followed by
+    /// the values that do not fit in place. The pointer fields are filled in.
+    fn tiff(byte_order: ByteOrder, mut ifds: Vec<Vec<TestField>>) -> Vec<u8> {
+        let u16_bytes = |v: u16| match byte_order {
+            ByteOrder::LittleEndian => v.to_le_bytes().to_vec(),
+            ByteOrder::BigEndian => v.to_be_bytes().to_vec(),
+        };
+        let u32_bytes = |v: u32| match byte_order {
+            ByteOrder::LittleEndian => v.to_le_bytes().to_vec(),
+            ByteOrder::BigEndian => v.to_be_bytes().to_vec(),
+        };
+
+        let ifd_size = |fields: &Vec<TestField>| {
+            6 + 12 * fields.len()
+                + fields
+                    .iter()
+                    .filter(|f| f.3.len() > 4)
+                    .map(|f| f.3.len())
+                    .sum::<usize>()
+        };
+
+        // IFD0 points at the EXIF and GPS IFDs, and the EXIF IFD at the Interop IFD.
+        ifds[0].push((tag::EXIF_IFD_POINTER, 4, 1, vec![]));
+        ifds[0].push((tag::GPS_IFD_POINTER, 4, 1, vec![]));
+        ifds[1].push((tag::INTEROP_IFD_POINTER, 4, 1, vec![]));
+
+        let mut offsets = vec![8];
+        for fields in &ifds {
+            offsets.push(offsets.last().unwrap() + ifd_size(fields));
+        }
+
+        for (ifd, target) in [(0, 1), (0, 3), (1, 2)] {
+            let field = ifds[ifd].iter_mut().find(|f| f.3.is_empty()).unwrap();
+            field.3 = u32_bytes(offsets[target] as u32);
+        }
+
+        let mut tiff = match byte_order {
+            ByteOrder::LittleEndian => b"II".to_vec(),
+            ByteOrder::BigEndian => b"MM".to_vec(),
+        };
+        tiff.extend(u16_bytes(42));
+        tiff.extend(u32_bytes(8));
+
+        for (idx, fields) in ifds.iter().enumerate() {
+            let mut data_offset = offsets[idx] + 6 + 12 * fields.len();
+            let mut data: Vec<u8> = vec![];
+
+            tiff.extend(u16_bytes(fields.len() as u16));
+            for (tag, field_type, count, value) in fields {
+                tiff.extend(u16_bytes(*tag));
+                tiff.extend(u16_bytes(*field_type));
+                tiff.extend(u32_bytes(*count));
+
+                match value.len() <= 4 {
+                    true => {
+                        let mut value = value.clone();
+                        value.resize(4, 0);
+                        tiff.extend(value);
+                    }
+                    false => {
+                        tiff.extend(u32_bytes(data_offset as u32));
+                        data.extend(value);
+                        data_offset += value.len();
+                    }
+                }
+            }
+
+            // only IFD0 links to the next IFD, IFD1.
+            let next_ifd = match idx {
+                0 => offsets[4] as u32,
+                _ => 0,
+            };
+            tiff.extend(u32_bytes(next_ifd));
+            tiff.extend(data);
+        }
+
+        tiff
+    }
+
+    fn segment(byte_order: ByteOrder) -> Vec<u8> {
+        let u16_bytes = |v: u16| match byte_order {
+            ByteOrder::LittleEndian => v.to_le_bytes().to_vec(),
+            ByteOrder::BigEndian => v.to_be_bytes().to_vec(),
+        };
+        let u32_bytes = |v: u32| match byte_order {
+            ByteOrder::LittleEndian => v.to_le_bytes().to_vec(),
+            ByteOrder::BigEndian => v.to_be_bytes().to_vec(),
+        };
+        let rationals = |values: &[(u32, u32)]| {
+            values
+                .iter()
+                .flat_map(|(n, d)| [u32_bytes(*n), u32_bytes(*d)].concat())
+                .collect::<Vec<_>>()
+        };
+
+        let ifds = vec![
+            vec![
+                (tag::MAKE, 2, 7, b"Google\0".to_vec()),
+                (tag::MODEL, 2, 8, b"Pixel 8\0".to_vec()),
+                (tag::ORIENTATION, 3, 1, u16_bytes(6)),
+            ],
+            vec![
+                (tag::EXPOSURE_TIME, 5, 1, rationals(&[(1, 250)])),
+                (tag::F_NUMBER, 5, 1, rationals(&[(18, 10)])),
+                (tag::ISO_SPEED_RATINGS, 3, 1, u16_bytes(100)),
+                (
+                    tag::DATE_TIME_ORIGINAL,
+                    2,
+                    20,
+                    b"2024:05:17 10:30:00\0".to_vec(),
+                ),
+                (tag::FOCAL_LENGTH, 10, 1, rationals(&[(-7i32 as u32, 2)])),
+            ],
+            vec![(0x0001, 2, 4, b"R98\0".to_vec())],
+            vec![
+                (tag::GPS_LATITUDE_REF, 2, 2, b"N\0".to_vec()),
+                (
+                    tag::GPS_LATITUDE,
+                    5,
+                    3,
+                    rationals(&[(48, 1), (51, 1), (2997, 100)]),
+                ),
+                (tag::GPS_LONGITUDE_REF, 2, 2, b"W\0".to_vec()),
+                (
+                    tag::GPS_LONGITUDE,
+                    5,
+                    3,
+                    rationals(&[(2, 1), (17, 1), (4020, 100)]),
+                ),
+                (tag::GPS_ALTITUDE_REF, 1, 1, vec![1]),
+                (tag::GPS_ALTITUDE, 5, 1, rationals(&[(35, 1)])),
+            ],
+            vec![
+                (0x0103, 3, 1, u16_bytes(6)),
+                (0x1234, 99, 3, vec![1, 2, 3, 4]),
+            ],
+        ];
+
+        [Exif::IDENTIFIER.to_vec(), tiff(byte_order, ifds)].concat()
+    }
+
+    #[test]
+    fn test_parse_exif() -> Result<()> {
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let exif = Exif::parse(&segment(byte_order))?;
+            assert_eq!(exif.byte_order, byte_order);
+
+            let kinds: Vec<_> = exif.ifds.iter().map(|ifd| ifd.kind).collect();
+            assert_eq!(
+                kinds,
+                vec![
+                    IfdKind::Primary,
+                    IfdKind::Exif,
+                    IfdKind::GPS,
+                    IfdKind::Interop,
+                    IfdKind::Thumbnail
+                ]
+            );
+
+            assert_eq!(exif.make(), Some("Google"));
+            assert_eq!(exif.model(), Some("Pixel 8"));
+            assert_eq!(exif.orientation(), Some(6));
+            assert_eq!(exif.exposure_time(), Some((1, 250)));
+            assert_eq!(exif.f_number(), Some(1.8));
+            assert_eq!(exif.iso(), Some(100));
+            assert_eq!(
+                exif.capture_time(),
+                Some(DateTime {
+                    year: 2024,
+                    month: 5,
+                    day: 17,
+                    hour: 10,
+                    minute: 30,
+                    second: 0
+                })
+            );
+            assert_eq!(
+                exif.get(IfdKind::Exif, tag::FOCAL_LENGTH),
+                Some(&Value::SRational(vec![(-7, 2)]))
+            );
+            assert_eq!(
+                exif.get(IfdKind::Interop, 0x0001).and_then(Value::as_str),
+                Some("R98")
+            );
+
+            let (latitude, longitude) = exif.gps_coordinates().unwrap();
+            assert!((latitude - 48.858325).abs() < 1e-6);
+            assert!((longitude + 2.294500).abs() < 1e-6);
+            assert_eq!(exif.gps_altitude(), Some(-35.0));
+
+            // the field of an unknown type is kept as it was.
+            let thumbnail = exif.ifd(IfdKind::Thumbnail).unwrap();
+            assert_eq!(thumbnail.get(0x0103), Some(&Value::Short(vec![6])));
+            assert_eq!(
+                thumbnail.get(0x1234),
+                Some(&Value::Unknown(99, 3, [1, 2, 3, 4]))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_segment() -> Result<()> {
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut exif = Exif::parse(&segment(byte_order))?;
+            exif.remove(IfdKind::Thumbnail, 0x1234);
+
+            exif.set(IfdKind::Primary, tag::ORIENTATION, Value::Short(vec![8]));
+            exif.set(
+                IfdKind::Primary,
+                tag::DATE_TIME,
+                Value::Ascii("2024:05:18 09:00:00".to_string()),
+            );
+            exif.remove_ifd(IfdKind::Exif);
+
+            let parsed = Exif::parse(&exif.to_segment()?)?;
+            assert_eq!(parsed.byte_order, byte_order);
+            assert_eq!(parsed.orientation(), Some(8));
+            assert_eq!(parsed.capture_time().map(|time| time.day), Some(18));
+            assert_eq!(parsed.ifd(IfdKind::Exif), None);
+            assert_eq!(parsed.ifd(IfdKind::Interop), None);
+            assert_eq!(parsed.ifd(IfdKind::GPS), exif.ifd(IfdKind::GPS));
+            assert_eq!(parsed.get(IfdKind::Primary, tag::EXIF_IFD_POINTER), None);
+
+            // an Interop IFD set without an EXIF IFD is written along with an empty one.
+            exif.set(IfdKind::Interop, 0x0001, Value::Ascii("R98".to_string()));
+            let parsed = Exif::parse(&exif.to_segment()?)?;
+            assert_eq!(
+                parsed.ifd(IfdKind::Exif).map(|ifd| ifd.fields.len()),
+                Some(1)
+            );
+            assert_eq!(
+                parsed.get(IfdKind::Interop, 0x0001).and_then(Value::as_str),
+                Some("R98")
+            );
+
+            // one left without the EXIF IFD that points to it cannot be written.
+            let mut exif = exif.clone();
+            exif.ifds.retain(|ifd| ifd.kind != IfdKind::Exif);
+            assert!(exif.to_segment().is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut exif = Exif::parse(&segment(byte_order))?;
+
+            // the four bytes of a field of an unknown type may be an offset into the old layout.
+            assert!(exif.to_segment().is_err());
+            exif.set(IfdKind::Thumbnail, 0x1234, Value::Unknown(99, 0, [0; 4]));
+
+            let parsed = Exif::parse(&exif.to_segment()?)?;
+
+            // values are padded to start on a word boundary, which moves the IFDs the pointers
+            // point to. Every other field is kept, in the same order.
+            assert_eq!(parsed.byte_order, byte_order);
+            assert_eq!(parsed.ifds.len(), exif.ifds.len());
+            for (parsed, ifd) in parsed.ifds.iter().zip(&exif.ifds) {
+                assert_eq!(parsed.kind, ifd.kind);
+
+                let tags = |ifd: &Ifd| ifd.fields.iter().map(|f| f.tag).collect::<Vec<_>>();
+                assert_eq!(tags(parsed), tags(ifd));
+
+                for field in &ifd.fields {
+                    if !matches!(
+                        field.tag,
+                        tag::EXIF_IFD_POINTER | tag::GPS_IFD_POINTER | tag::INTEROP_IFD_POINTER
+                    ) {
+                        assert_eq!(parsed.get(field.tag), Some(&field.value));
+                    }
+                }
+            }
+
+            // the offsets within a maker note or to strips would be left stale.
+            for (kind, tag) in [
+                (IfdKind::Exif, tag::MAKER_NOTE),
+                (IfdKind::Thumbnail, tag::STRIP_OFFSETS),
+            ] {
+                let mut exif = exif.clone();
+                exif.set(kind, tag, Value::Undefined(vec![0; 16]));
+                assert!(exif.to_segment().is_err());
+
+                exif.remove(kind, tag);
+                exif.to_segment()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_malformed_exif() {
+        let segment = segment(ByteOrder::BigEndian);
+
+        assert!(Exif::parse(&segment[..segment.len() - 8]).is_err());
+        assert!(Exif::parse(&[Exif::IDENTIFIER, b"XX\0\x2A\0\0\0\x08"].concat()).is_err());
+
+        // IFD0 links back to itself.
+        let looped = [Exif::IDENTIFIER, b"MM\0\x2A\0\0\0\x08\0\0\0\0\0\x08"].concat();
+        assert!(Exif::parse(&looped).is_err());
+    }
+
+    #[test]
+    fn test_parse_date_time() {
+        assert_eq!(
+            DateTime::parse("2024:12:31 23:59:59"),
+            Some(DateTime {
+                year: 2024,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 59,
+                second: 59,
+            })
+        );
+
+        for value in [
+            "2024:300:01 00:00:00",
+            "2024:00:01 00:00:00",
+            "2024:01:00 00:00:00",
+            "2024:01:01 99:00:00",
+            "2024:01:01 00:60:00",
+            "2024:01:01 00:00",
+        ] {
+            assert_eq!(DateTime::parse(value), None, "{value}");
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use anyhow::{anyhow, Result};
+
 use crate::sample_precision::SamplePrecision;
 use crate::scan_header::EncodingOrder;
 
@@ -23,6 +25,30 @@ pub struct FrameHeader {
     pub(crate) components: Vec<Component>,
 }
 
+impl FrameHeader {
+    /// Hmax and Vmax: the largest horizontal and vertical sampling factors in the frame.
+    pub(crate) fn max_sampling_factors(&self) -> (usize, usize) {
+        self.components.iter().fold((1, 1), |(h_max, v_max), c| {
+            (
+                h_max.max(c.horizontal_scaling_factor as usize),
+                v_max.max(c.vertical_scaling_factor as usize),
+            )
+        })
+    }
+
+    /// The number of MCUs spanning one line of an interleaved scan.
+    pub(crate) fn mcus_per_line(&self) -> usize {
+        let (h_max, _) = self.max_sampling_factors();
+        self.image_width.div_ceil(8 * h_max)
+    }
+
+    /// The number of MCU rows spanning the image in an interleaved scan.
+    pub(crate) fn mcu_rows(&self) -> usize {
+        let (_, v_max) = self.max_sampling_factors();
+        self.image_height.div_ceil(8 * v_max)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) enum ComponentType {
     Grayscale,
@@ -30,13 +56,13 @@ pub(crate) enum ComponentType {
 }
 
 impl ComponentType {
-    pub(crate) fn from(b: u8) -> (Self, EncodingOrder) {
+    /// The type of a frame or scan of `b` components. Frames of more than 4 components are
+    /// valid (B.2.2), but not supported.
+    pub(crate) fn parse(b: u8) -> Result<(Self, EncodingOrder)> {
         match b {
-            1 => (ComponentType::Grayscale, EncodingOrder::NonInterleaved),
-            2 => (ComponentType::Color, EncodingOrder::Interleaved),
-            3 => (ComponentType::Color, EncodingOrder::Interleaved),
-            4 => todo!(),
-            _ => unreachable!(),
+            1 => Ok((ComponentType::Grayscale, EncodingOrder::NonInterleaved)),
+            2..=4 => Ok((ComponentType::Color, EncodingOrder::Interleaved)),
+            _ => Err(anyhow!(format!("expected 1 to 4 components. Got {b}"))),
         }
     }
 }
@@ -80,4 +106,44 @@ impl Component {
             qt_table_id,
         }
     }
+
+    /// (A.1.1) The dimensions of the component in samples, xi = ⌈X × Hi / Hmax⌉ and
+    /// yi = ⌈Y × Vi / Vmax⌉.
+    pub(crate) fn dimensions(&self, frame_header: &FrameHeader) -> (usize, usize) {
+        let (h_max, v_max) = frame_header.max_sampling_factors();
+        (
+            (frame_header.image_width * self.horizontal_scaling_factor as usize).div_ceil(h_max),
+            (frame_header.image_height * self.vertical_scaling_factor as usize).div_ceil(v_max),
+        )
+    }
+
+    /// The number of blocks per line and per column in the padded block grid of an interleaved
+    /// scan. Every MCU holds Hi x Vi blocks of this component.
+    pub(crate) fn block_grid(&self, frame_header: &FrameHeader) -> (usize, usize) {
+        (
+            frame_header.mcus_per_line() * self.horizontal_scaling_factor as usize,
+            frame_header.mcu_rows() * self.vertical_scaling_factor as usize,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_type() -> Result<()> {
+        assert_eq!(
+            ComponentType::parse(1)?,
+            (ComponentType::Grayscale, EncodingOrder::NonInterleaved)
+        );
+        assert_eq!(
+            ComponentType::parse(4)?,
+            (ComponentType::Color, EncodingOrder::Interleaved)
+        );
+        assert!(ComponentType::parse(0).is_err());
+        assert!(ComponentType::parse(5).is_err());
+
+        Ok(())
+    }
 }
@@ -1,60 +1,378 @@
 use std::simd::prelude::*;
+use std::simd::StdFloat;
 
-pub fn realign_rgb_data(src: &[u8], num: usize) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-    let mut r: Vec<u8> = vec![0; num];
-    let mut g: Vec<u8> = vec![0; num];
-    let mut b: Vec<u8> = vec![0; num];
+use anyhow::{anyhow, Result};
 
-    for i in 0..num {
-        r[i] = src[i * 4];
-        g[i] = src[i * 4 + 1];
-        b[i] = src[i * 4 + 2];
+use crate::cms::Curve;
+
+/// The number of pixels converted at once.
+const LANES: usize = 16;
+
+/// The number of linear levels the sRGB curve is inverted at.
+const LINEAR_LEVELS: usize = 4096;
+
+/// The order of the channels of an interleaved pixel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelLayout {
+    RGB,
+    RGBA,
+    BGR,
+    BGRA,
+}
+
+impl PixelLayout {
+    pub fn num_channels(&self) -> usize {
+        match self {
+            PixelLayout::RGB | PixelLayout::BGR => 3,
+            PixelLayout::RGBA | PixelLayout::BGRA => 4,
+        }
+    }
+
+    /// The offsets of red, green and blue within a pixel, and of alpha if it has one.
+    fn offsets(&self) -> ([usize; 3], Option<usize>) {
+        match self {
+            PixelLayout::RGB => ([0, 1, 2], None),
+            PixelLayout::RGBA => ([0, 1, 2], Some(3)),
+            PixelLayout::BGR => ([2, 1, 0], None),
+            PixelLayout::BGRA => ([2, 1, 0], Some(3)),
+        }
+    }
+}
+
+/// The pixels to convert, either interleaved or as separate red, green and blue planes.
+#[derive(Debug, Copy, Clone)]
+pub enum Input<'a> {
+    Interleaved(&'a [u8], PixelLayout),
+    Planar([&'a [u8]; 3]),
+}
+
+impl Input<'_> {
+    fn num_pixels(&self) -> Result<usize> {
+        match self {
+            Input::Interleaved(data, layout) => {
+                let num_channels = layout.num_channels();
+                match data.len() % num_channels {
+                    0 => Ok(data.len() / num_channels),
+                    _ => Err(anyhow!(format!(
+                        "{} bytes are not whole {layout:?} pixels",
+                        data.len()
+                    ))),
+                }
+            }
+            Input::Planar([r, g, b]) => match r.len() == g.len() && g.len() == b.len() {
+                true => Ok(r.len()),
+                false => Err(anyhow!(format!(
+                    "the planes of {}, {} and {} samples differ in size",
+                    r.len(),
+                    g.len(),
+                    b.len()
+                ))),
+            },
+        }
+    }
+
+    /// The red, green and blue samples of pixel `i`.
+    fn rgb(&self, i: usize) -> [u8; 3] {
+        match self {
+            Input::Interleaved(data, layout) => {
+                let ([r, g, b], _) = layout.offsets();
+                let pixel = &data[i * layout.num_channels()..];
+                [pixel[r], pixel[g], pixel[b]]
+            }
+            Input::Planar([r, g, b]) => [r[i], g[i], b[i]],
+        }
+    }
+
+    fn alpha(&self, i: usize) -> u8 {
+        match self {
+            Input::Interleaved(data, layout) => match layout.offsets() {
+                (_, Some(alpha)) => data[i * layout.num_channels() + alpha],
+                (_, None) => 255,
+            },
+            Input::Planar(_) => 255,
+        }
+    }
+}
+
+/// The layout of the grayscale pixels written out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputLayout {
+    Gray,
+
+    /// Gray followed by the alpha of the input, or 255 when it has none.
+    GrayAlpha,
+
+    /// Gray repeated across red, green and blue, followed by alpha as in `GrayAlpha`.
+    RGBA,
+}
+
+impl OutputLayout {
+    pub fn num_channels(&self) -> usize {
+        match self {
+            OutputLayout::Gray => 1,
+            OutputLayout::GrayAlpha => 2,
+            OutputLayout::RGBA => 4,
+        }
+    }
+}
+
+/// The weights of red, green and blue in the luma.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LumaWeights {
+    /// 0.299, 0.587 and 0.114, as in the Y component of JFIF.
+    BT601,
+
+    /// 0.2126, 0.7152 and 0.0722.
+    BT709,
+}
+
+impl LumaWeights {
+    pub fn coefficients(&self) -> [f32; 3] {
+        match self {
+            LumaWeights::BT601 => [0.299, 0.587, 0.114],
+            LumaWeights::BT709 => [0.2126, 0.7152, 0.0722],
+        }
+    }
+}
+
+/// Converts RGB pixels into grayscale ones.
+#[derive(Debug, Clone)]
+pub struct Grayscale {
+    weights: [f32; 3],
+
+    /// When weighing linear light, the sRGB curve as a table for each 8-bit sample, and its
+    /// inverse at evenly spaced linear levels.
+    linear_light: Option<([f32; 256], Vec<u8>)>,
+}
+
+impl Grayscale {
+    pub fn new(weights: LumaWeights) -> Self {
+        Grayscale {
+            weights: weights.coefficients(),
+            linear_light: None,
+        }
+    }
+
+    /// Weighs the samples once the sRGB curve is undone, and encodes the result with it again.
+    /// This keeps the lightness of saturated colors, at the cost of a table lookup per sample.
+    pub fn linear_light(mut self, linear_light: bool) -> Self {
+        self.linear_light = linear_light.then(|| {
+            let curve = Curve::srgb();
+
+            let mut decode = [0.0; 256];
+            for (x, linear) in decode.iter_mut().enumerate() {
+                *linear = curve.eval(x as f64 / 255.0) as f32;
+            }
+
+            let encode = (0..LINEAR_LEVELS)
+                .map(|level| {
+                    let linear = level as f64 / (LINEAR_LEVELS - 1) as f64;
+                    let encoded = match linear <= 0.0031308 {
+                        true => 12.92 * linear,
+                        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+                    };
+                    (encoded * 255.0).round().clamp(0.0, 255.0) as u8
+                })
+                .collect();
+
+            (decode, encode)
+        });
+        self
+    }
+
+    /// Converts every pixel of `input` and writes it into `output` in the given layout. Returns
+    /// the number of pixels written.
+    pub fn convert(&self, input: Input, output: &mut [u8], layout: OutputLayout) -> Result<usize> {
+        let num_pixels = input.num_pixels()?;
+        let num_channels = layout.num_channels();
+
+        if output.len() < num_pixels * num_channels {
+            return Err(anyhow!(format!(
+                "an output of {} bytes cannot hold {num_pixels} {layout:?} pixels",
+                output.len()
+            )));
+        }
+
+        let mut grays = [0u8; LANES];
+
+        for start in (0..num_pixels).step_by(LANES) {
+            let len = LANES.min(num_pixels - start);
+            self.convert_lanes(&input, start, &mut grays[..len]);
+
+            for (i, gray) in grays[..len].iter().enumerate() {
+                let pixel = &mut output[(start + i) * num_channels..][..num_channels];
+
+                match layout {
+                    OutputLayout::Gray => pixel[0] = *gray,
+                    OutputLayout::GrayAlpha => {
+                        pixel.copy_from_slice(&[*gray, input.alpha(start + i)])
+                    }
+                    OutputLayout::RGBA => {
+                        pixel.copy_from_slice(&[*gray, *gray, *gray, input.alpha(start + i)])
+                    }
+                }
+            }
+        }
+
+        Ok(num_pixels)
     }
 
-    (r, g, b)
+    /// Converts the pixels from `start` on into `grays`, `LANES` at a time. The lanes past the
+    /// last pixel are left at zero and never written out.
+    fn convert_lanes(&self, input: &Input, start: usize, grays: &mut [u8]) {
+        let mut channels = [[0f32; LANES]; 3];
+        for (i, _) in grays.iter().enumerate() {
+            let rgb = input.rgb(start + i);
+
+            for c in 0..3 {
+                channels[c][i] = match &self.linear_light {
+                    Some((decode, _)) => decode[rgb[c] as usize],
+                    None => rgb[c] as f32,
+                };
+            }
+        }
+
+        let [r, g, b] = channels.map(Simd::<f32, LANES>::from_array);
+        let [kr, kg, kb] = self.weights.map(Simd::splat);
+        let luma = r * kr + g * kg + b * kb;
+
+        match &self.linear_light {
+            Some((_, encode)) => {
+                let levels = (luma * Simd::splat((LINEAR_LEVELS - 1) as f32))
+                    .round()
+                    .simd_clamp(Simd::splat(0.0), Simd::splat((LINEAR_LEVELS - 1) as f32))
+                    .cast::<usize>();
+
+                for (i, gray) in grays.iter_mut().enumerate() {
+                    *gray = encode[levels[i]];
+                }
+            }
+            None => {
+                let luma = luma
+                    .round()
+                    .simd_clamp(Simd::splat(0.0), Simd::splat(255.0))
+                    .cast::<u8>();
+                grays.copy_from_slice(&luma.as_array()[..grays.len()]);
+            }
+        }
+    }
 }
 
-pub fn rgb_to_grayscale(src: &[u8], dst: &mut [u8], num: usize) {
-    let simd_size = 64;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts() -> Result<()> {
+        // more pixels than lanes, so the last few are a remainder.
+        let num_pixels = LANES + 3;
+        let rgb: Vec<u8> = (0..num_pixels * 3).map(|i| (i * 29 % 256) as u8).collect();
 
-    let (r, g, b) = realign_rgb_data(src, num);
+        let expected: Vec<u8> = rgb
+            .chunks_exact(3)
+            .map(|p| {
+                (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32).round() as u8
+            })
+            .collect();
 
-    let r_factor = f32x64::splat(0.29891);
-    let g_factor = f32x64::splat(0.58661);
-    let b_factor = f32x64::splat(0.11448);
+        let grayscale = Grayscale::new(LumaWeights::BT601);
+        let mut output = vec![0; num_pixels];
+        assert_eq!(
+            grayscale.convert(
+                Input::Interleaved(&rgb, PixelLayout::RGB),
+                &mut output,
+                OutputLayout::Gray
+            )?,
+            num_pixels
+        );
+        assert_eq!(output, expected);
 
-    let mut i = 0;
-    while i < num {
-        let end = (i + simd_size).min(num);
-        let len = end - i;
-        let mut r_chunk = [0u8; 64];
-        let mut g_chunk = [0u8; 64];
-        let mut b_chunk = [0u8; 64];
+        let bgra: Vec<u8> = rgb
+            .chunks_exact(3)
+            .enumerate()
+            .flat_map(|(i, p)| [p[2], p[1], p[0], i as u8])
+            .collect();
+        let mut output = vec![0; num_pixels * 2];
+        grayscale.convert(
+            Input::Interleaved(&bgra, PixelLayout::BGRA),
+            &mut output,
+            OutputLayout::GrayAlpha,
+        )?;
+        let gray_alpha: Vec<u8> = expected
+            .iter()
+            .enumerate()
+            .flat_map(|(i, gray)| [*gray, i as u8])
+            .collect();
+        assert_eq!(output, gray_alpha);
 
-        r_chunk[..len].copy_from_slice(&r[i..end]);
-        g_chunk[..len].copy_from_slice(&g[i..end]);
-        b_chunk[..len].copy_from_slice(&b[i..end]);
+        let planes: Vec<Vec<u8>> = (0..3)
+            .map(|c| rgb.iter().skip(c).step_by(3).copied().collect())
+            .collect();
+        let mut output = vec![0; num_pixels * 4];
+        grayscale.convert(
+            Input::Planar([&planes[0], &planes[1], &planes[2]]),
+            &mut output,
+            OutputLayout::RGBA,
+        )?;
+        let rgba: Vec<u8> = expected
+            .iter()
+            .flat_map(|gray| [*gray, *gray, *gray, 255])
+            .collect();
+        assert_eq!(output, rgba);
 
-        let r_chunk = u8x64::from_array(r_chunk);
-        let g_chunk = u8x64::from_array(g_chunk);
-        let b_chunk = u8x64::from_array(b_chunk);
+        // partial pixels, planes that differ in size, and an output that is too small.
+        let mut output = vec![0; num_pixels];
+        assert!(grayscale
+            .convert(
+                Input::Interleaved(&rgb[1..], PixelLayout::RGB),
+                &mut output,
+                OutputLayout::Gray
+            )
+            .is_err());
+        assert!(grayscale
+            .convert(
+                Input::Planar([&planes[0], &planes[1][1..], &planes[2]]),
+                &mut output,
+                OutputLayout::Gray
+            )
+            .is_err());
+        assert!(grayscale
+            .convert(
+                Input::Interleaved(&rgb, PixelLayout::RGB),
+                &mut output[1..],
+                OutputLayout::Gray
+            )
+            .is_err());
+
+        Ok(())
+    }
 
-        let r_f32 = r_chunk.cast();
-        let g_f32 = g_chunk.cast();
-        let b_f32 = b_chunk.cast();
+    #[test]
+    fn test_weights() -> Result<()> {
+        let convert = |grayscale: &Grayscale, pixel: [u8; 3]| -> Result<u8> {
+            let mut output = [0];
+            grayscale.convert(
+                Input::Interleaved(&pixel, PixelLayout::RGB),
+                &mut output,
+                OutputLayout::Gray,
+            )?;
+            Ok(output[0])
+        };
 
-        let gray = r_f32 * r_factor + g_f32 * g_factor + b_f32 * b_factor;
-        let gray_u8 = gray.cast::<u8>();
+        let bt601 = Grayscale::new(LumaWeights::BT601);
+        let bt709 = Grayscale::new(LumaWeights::BT709);
+        assert_eq!(convert(&bt601, [0, 255, 0])?, 150);
+        assert_eq!(convert(&bt709, [0, 255, 0])?, 182);
+        assert_eq!(convert(&bt709, [255, 255, 255])?, 255);
 
-        for j in 0..len {
-            let idx = i + j;
-            let y = gray_u8[j];
-            dst[idx * 4] = y;
-            dst[idx * 4 + 1] = y;
-            dst[idx * 4 + 2] = y;
-            dst[idx * 4 + 3] = 255;
+        // in linear light, grays stay put and saturated colors come out lighter.
+        let linear = Grayscale::new(LumaWeights::BT709).linear_light(true);
+        for gray in [0, 1, 17, 128, 254, 255] {
+            assert_eq!(convert(&linear, [gray; 3])?, gray);
         }
+        assert_eq!(convert(&linear, [0, 0, 255])?, 76);
+        assert_eq!(convert(&bt709, [0, 0, 255])?, 18);
 
-        i += simd_size
+        Ok(())
     }
 }
@@ -1,5 +1,3 @@
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
 use std::marker::PhantomData;
 use std::ptr::NonNull;
 
@@ -20,61 +18,27 @@ impl HuffmanClass {
     }
 }
 
-#[derive(Debug, Eq)]
-pub(crate) struct HeapItem {
-    freq: usize,
-    node: NPtr,
-}
-
-impl HeapItem {
-    fn from(freq: usize, node: NPtr) -> Self {
-        HeapItem { freq, node }
-    }
-}
-
-impl From<(usize, NPtr)> for HeapItem {
-    fn from(tuple: (usize, NPtr)) -> Self {
-        HeapItem {
-            freq: tuple.0,
-            node: tuple.1,
-        }
-    }
-}
-
-impl Ord for HeapItem {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.freq.cmp(&self.freq)
-    }
-}
-
-impl PartialOrd for HeapItem {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for HeapItem {
-    fn eq(&self, other: &Self) -> bool {
-        self.freq == other.freq
-    }
-}
-
 pub(crate) struct HuffmanNode {
+    /// The decoded value of a leaf. Internal nodes hold `u8::MAX`.
     pub(crate) code: u8,
-    pub(crate) freq: usize,
     pub(crate) left: NPtr,
     pub(crate) right: NPtr,
 }
 
 impl HuffmanNode {
-    pub(crate) fn new_node(code: u8, freq: usize) -> Self {
-        HuffmanNode {
-            code,
-            freq,
-            left: None,
-            right: None,
+    pub(crate) fn new_node(code: u8) -> NonNull<HuffmanNode> {
+        unsafe {
+            NonNull::new_unchecked(Box::into_raw(Box::new(HuffmanNode {
+                code,
+                left: None,
+                right: None,
+            })))
         }
     }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.left.is_none() && self.right.is_none()
+    }
 }
 
 pub(crate) type NPtr = Option<NonNull<HuffmanNode>>;
@@ -92,50 +56,41 @@ pub struct HuffmanTree {
 }
 
 impl HuffmanTree {
-    pub fn from(class: u8, destination_id: u8, code_freqs: Vec<(u8, usize)>) -> Self {
-        let mut min_heap = BinaryHeap::new();
-
-        for (code, freq) in code_freqs {
-            let new_node = unsafe {
-                NonNull::new_unchecked(Box::into_raw(Box::new(HuffmanNode::new_node(code, freq))))
-            };
-
-            min_heap.push(HeapItem::from(freq, Some(new_node)))
-        }
-
-        while min_heap.len() > 1 {
-            let left = min_heap.pop();
-
-            if min_heap.len() == 1 {
-                break;
-            }
-
-            let right = min_heap.pop();
-
-            match (left, right) {
-                (Some(left_item), Some(right_item)) => {
-                    let sum_freq = left_item.freq + right_item.freq;
-                    let new_node = unsafe {
-                        NonNull::new_unchecked(Box::into_raw(Box::new(HuffmanNode {
-                            freq: sum_freq,
-                            code: u8::MAX,
-                            right: right_item.node,
-                            left: left_item.node,
-                        })))
+    /// (Annex C) Builds the tree from the huffman values of a table, each paired with the length
+    /// of its code. The values are listed in order of increasing code length, so the codes can be
+    /// generated canonically: each code is the previous one plus one, shifted left whenever the
+    /// code length grows.
+    pub fn from(class: u8, destination_id: u8, code_lengths: Vec<(u8, usize)>) -> Self {
+        let root = HuffmanNode::new_node(u8::MAX);
+
+        let mut code = 0u32;
+        let mut previous_length = code_lengths.first().map_or(0, |(_, length)| *length);
+
+        for (value, length) in code_lengths {
+            code <<= length - previous_length;
+            previous_length = length;
+
+            let mut node = root;
+            for bit in (0..length).rev() {
+                unsafe {
+                    let branch = match (code >> bit) & 1 {
+                        0 => &mut (*node.as_ptr()).left,
+                        _ => &mut (*node.as_ptr()).right,
                     };
 
-                    min_heap.push(HeapItem::from(sum_freq, Some(new_node)))
+                    node = *branch.get_or_insert_with(|| HuffmanNode::new_node(u8::MAX));
                 }
-                _ => unreachable!(),
             }
-        }
 
-        let root_item = min_heap.pop();
-        debug_assert!(root_item.is_some());
-        let HeapItem { node: root, .. } = root_item.unwrap();
+            unsafe {
+                (*node.as_ptr()).code = value;
+            }
+
+            code += 1;
+        }
 
         HuffmanTree {
-            root,
+            root: Some(root),
             class: HuffmanClass::from(class),
             destination_id,
             _woof: PhantomData,
@@ -171,7 +126,7 @@ mod tests {
 
     #[test]
     fn test_tree_construction() -> Result<()> {
-        let code_freqs = vec![(1, 5), (2, 9), (3, 12), (4, 13), (5, 16), (6, 45)];
+        let code_freqs = vec![(1, 2), (2, 2), (3, 3), (4, 3), (5, 3), (6, 4)];
 
         let tree = HuffmanTree::from(1, 1, code_freqs);
 
@@ -185,30 +140,29 @@ mod tests {
     }
 
     #[test]
-    fn test_min_heap() -> Result<()> {
-        let mut min_heap = BinaryHeap::new();
-
-        for i in 36..0 {
-            min_heap.push(HeapItem {
-                freq: i,
-                node: Some(unsafe {
-                    NonNull::new_unchecked(Box::into_raw(Box::new(HuffmanNode::new_node(
-                        i as u8, i,
-                    ))))
-                }),
-            })
-        }
-
-        let mut expected = 36;
-        while !min_heap.is_empty() {
-            let res = min_heap.pop();
-            assert!(res.is_some());
-            let HeapItem { freq, .. } = res.unwrap();
+    fn test_canonical_codes() -> Result<()> {
+        let tree = HuffmanTree::from(0, 0, vec![(1, 2), (2, 2), (3, 3), (4, 3), (5, 3), (6, 4)]);
+
+        let decode = |bits: &[u8]| unsafe {
+            let mut node = tree.root.unwrap();
+            for bit in bits {
+                node = match bit {
+                    0 => (*node.as_ptr()).left,
+                    _ => (*node.as_ptr()).right,
+                }
+                .unwrap();
+            }
 
-            assert_eq!(expected, freq);
+            assert!((*node.as_ptr()).is_leaf());
+            (*node.as_ptr()).code
+        };
 
-            expected -= 1;
-        }
+        assert_eq!(decode(&[0, 0]), 1);
+        assert_eq!(decode(&[0, 1]), 2);
+        assert_eq!(decode(&[1, 0, 0]), 3);
+        assert_eq!(decode(&[1, 0, 1]), 4);
+        assert_eq!(decode(&[1, 1, 0]), 5);
+        assert_eq!(decode(&[1, 1, 1, 0]), 6);
 
         Ok(())
     }
@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+
+/// (ICC.1 7.2.5) The kind of device or transform a profile describes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeviceClass {
+    Input,
+    Display,
+    Output,
+    DeviceLink,
+    ColorSpace,
+    Abstract,
+    NamedColor,
+    Unknown([u8; 4]),
+}
+
+impl DeviceClass {
+    fn from(signature: [u8; 4]) -> Self {
+        match &signature {
+            b"scnr" => DeviceClass::Input,
+            b"mntr" => DeviceClass::Display,
+            b"prtr" => DeviceClass::Output,
+            b"link" => DeviceClass::DeviceLink,
+            b"spac" => DeviceClass::ColorSpace,
+            b"abst" => DeviceClass::Abstract,
+            b"nmcl" => DeviceClass::NamedColor,
+            _ => DeviceClass::Unknown(signature),
+        }
+    }
+}
+
+/// (ICC.1 7.2.6) The color space of the data a profile converts, or of its connection space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorSpace {
+    XYZ,
+    Lab,
+    RGB,
+    Gray,
+    CMYK,
+    YCbCr,
+    Unknown([u8; 4]),
+}
+
+impl ColorSpace {
+    fn from(signature: [u8; 4]) -> Self {
+        match &signature {
+            b"XYZ " => ColorSpace::XYZ,
+            b"Lab " => ColorSpace::Lab,
+            b"RGB " => ColorSpace::RGB,
+            b"GRAY" => ColorSpace::Gray,
+            b"CMYK" => ColorSpace::CMYK,
+            b"YCbr" => ColorSpace::YCbCr,
+            _ => ColorSpace::Unknown(signature),
+        }
+    }
+}
+
+/// An ICC profile, reassembled from the APP2 segments it was split across.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccProfile {
+    /// (major, minor, bug fix), such as (4, 3, 0) for version 4.3.
+    pub version: (u8, u8, u8),
+    pub device_class: DeviceClass,
+    pub color_space: ColorSpace,
+    pub connection_space: ColorSpace,
+    pub description: Option<String>,
+
+    /// The whole profile, header included.
+    pub data: Vec<u8>,
+}
+
+impl IccProfile {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"ICC_PROFILE\0";
+    const HEADER_SIZE: usize = 128;
+
+    /// Concatenates the chunks of the APP2 segments that start with the ICC identifier, in the
+    /// order of their sequence numbers, and parses the result. Every chunk has to agree on the
+    /// number of chunks, and every sequence number from 1 to it has to appear exactly once.
+    pub(crate) fn assemble(segments: &[&[u8]]) -> Result<Self> {
+        let mut chunks: Vec<(u8, &[u8])> = Vec::with_capacity(segments.len());
+        let mut num_chunks = None;
+
+        for segment in segments {
+            let [sequence_number, count, chunk @ ..] = segment
+                .strip_prefix(Self::IDENTIFIER)
+                .ok_or(anyhow!("expected an ICC profile identifier"))?
+            else {
+                return Err(anyhow!("expected the sequence number of the ICC chunk"));
+            };
+
+            if *num_chunks.get_or_insert(*count) != *count {
+                return Err(anyhow!(format!(
+                    "ICC chunks disagree on the number of chunks: {} and {count}",
+                    num_chunks.unwrap()
+                )));
+            }
+
+            chunks.push((*sequence_number, chunk));
+        }
+
+        let num_chunks = num_chunks.ok_or(anyhow!("expected at least one ICC chunk"))?;
+        chunks.sort_by_key(|(sequence_number, _)| *sequence_number);
+
+        let sequence_numbers: Vec<_> = chunks.iter().map(|(number, _)| *number).collect();
+        if !sequence_numbers.iter().copied().eq(1..=num_chunks) {
+            return Err(anyhow!(format!(
+                "expected ICC chunks 1 to {num_chunks}. Got {sequence_numbers:?}"
+            )));
+        }
+
+        Self::parse(
+            chunks
+                .into_iter()
+                .flat_map(|(_, chunk)| chunk)
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// (ICC.1 7.2) Parses the header of a profile, and its description.
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        if data.len() < Self::HEADER_SIZE + 4 {
+            return Err(anyhow!(format!(
+                "expected at least {} bytes in the ICC profile. Got {}",
+                Self::HEADER_SIZE + 4,
+                data.len()
+            )));
+        }
+
+        if &data[36..40] != b"acsp" {
+            return Err(anyhow!("expected the ICC profile file signature"));
+        }
+
+        let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if size > data.len() {
+            return Err(anyhow!(format!(
+                "the ICC profile is {size} bytes long, but only {} were found",
+                data.len()
+            )));
+        }
+
+        let signature = |offset: usize| -> [u8; 4] { data[offset..offset + 4].try_into().unwrap() };
+
+        let mut profile = IccProfile {
+            version: (data[8], data[9] >> 4, data[9] & 0x0F),
+            device_class: DeviceClass::from(signature(12)),
+            color_space: ColorSpace::from(signature(16)),
+            connection_space: ColorSpace::from(signature(20)),
+            description: None,
+            data,
+        };
+        profile.description = profile.tag(b"desc").and_then(Self::parse_text);
+
+        Ok(profile)
+    }
+
+    /// (ICC.1 7.3) The data of the tag with the given signature, starting at its type signature.
+    pub fn tag(&self, signature: &[u8; 4]) -> Option<&[u8]> {
+        let table = &self.data[Self::HEADER_SIZE..];
+        let num_tags = u32::from_be_bytes(table[..4].try_into().unwrap()) as usize;
+
+        table[4..]
+            .chunks_exact(12)
+            .take(num_tags)
+            .find(|entry| &entry[..4] == signature)
+            .and_then(|entry| {
+                let offset = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as usize;
+                let size = u32::from_be_bytes(entry[8..12].try_into().unwrap()) as usize;
+
+                self.data.get(offset..offset.checked_add(size)?)
+            })
+    }
+
+    /// Reads the text of a textDescriptionType (ICC.1:2001 6.5.17) or of the first record of a
+    /// multiLocalizedUnicodeType (ICC.1 10.15).
+    fn parse_text(tag: &[u8]) -> Option<String> {
+        let u32_at = |offset: usize| -> Option<usize> {
+            Some(u32::from_be_bytes(tag.get(offset..offset + 4)?.try_into().ok()?) as usize)
+        };
+
+        match tag.get(..4)? {
+            b"desc" => {
+                let text = tag.get(12..12 + u32_at(8)?)?;
+                let text = String::from_utf8_lossy(text);
+
+                Some(text.trim_end_matches('\0').to_string())
+            }
+            b"mluc" if u32_at(8)? > 0 => {
+                let (length, offset) = (u32_at(20)?, u32_at(24)?);
+                let units: Vec<_> = tag
+                    .get(offset..offset + length)?
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                    .collect();
+
+                Some(
+                    String::from_utf16_lossy(&units)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A display profile with a single tag, which describes it.
+    fn profile(description: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 128];
+        data[8..10].copy_from_slice(&[4, 0x30]);
+        data[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        data[36..40].copy_from_slice(b"acsp");
+
+        data.extend(1u32.to_be_bytes());
+        data.extend(b"desc");
+        data.extend(144u32.to_be_bytes());
+        data.extend((description.len() as u32).to_be_bytes());
+        data.extend(description);
+
+        let size = data.len() as u32;
+        data[..4].copy_from_slice(&size.to_be_bytes());
+
+        data
+    }
+
+    fn mluc(text: &str) -> Vec<u8> {
+        let mut tag = b"mluc\0\0\0\0".to_vec();
+        tag.extend(1u32.to_be_bytes());
+        tag.extend(12u32.to_be_bytes());
+        tag.extend(b"enUS");
+        tag.extend((2 * text.len() as u32).to_be_bytes());
+        tag.extend(28u32.to_be_bytes());
+        tag.extend(text.encode_utf16().flat_map(|unit| unit.to_be_bytes()));
+
+        tag
+    }
+
+    fn chunk(sequence_number: u8, count: u8, data: &[u8]) -> Vec<u8> {
+        [IccProfile::IDENTIFIER, &[sequence_number, count], data].concat()
+    }
+
+    #[test]
+    fn test_assemble() -> Result<()> {
+        let data = profile(&mluc("Display P3"));
+        let (first, second) = data.split_at(100);
+
+        let (first, second) = (chunk(1, 2, first), chunk(2, 2, second));
+        let profile = IccProfile::assemble(&[&second, &first])?;
+
+        assert_eq!(profile.data, data);
+        assert_eq!(profile.version, (4, 3, 0));
+        assert_eq!(profile.device_class, DeviceClass::Display);
+        assert_eq!(profile.color_space, ColorSpace::RGB);
+        assert_eq!(profile.connection_space, ColorSpace::XYZ);
+        assert_eq!(profile.description.as_deref(), Some("Display P3"));
+
+        // a chunk is missing, repeated, or disagrees on the number of chunks.
+        assert!(IccProfile::assemble(&[&first]).is_err());
+        assert!(IccProfile::assemble(&[&first, &first]).is_err());
+        assert!(IccProfile::assemble(&[&first, &chunk(2, 3, &data[100..])]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_text_description() -> Result<()> {
+        let mut tag = b"desc\0\0\0\0".to_vec();
+        tag.extend(5u32.to_be_bytes());
+        tag.extend(b"sRGB\0");
+
+        let profile = IccProfile::parse(profile(&tag))?;
+        assert_eq!(profile.description.as_deref(), Some("sRGB"));
+
+        // the signature is missing.
+        let mut data = profile.data;
+        data[36] = 0;
+        assert!(IccProfile::parse(data).is_err());
+
+        Ok(())
+    }
+}
@@ -1,66 +1,184 @@
 use std::f32::consts::PI;
+use std::simd::Simd;
 
+use crate::options::{IdctMethod, ScaleFactor};
 use crate::sample_precision::SamplePrecision;
 
 pub(crate) struct IDCT {
+    /// C(u) cos((2x + 1)uπ / 2N) for every frequency u and output sample x, where N is the
+    /// size of the output block.
     pub(crate) table: [f32; 64],
     pub(crate) precision: SamplePrecision,
+    pub(crate) method: IdctMethod,
+
+    /// N: the width and height of an output block.
+    pub(crate) block_size: usize,
 }
 
 impl IDCT {
     fn norm_coeff(u: usize) -> f32 {
         match u {
-            0 => ((1.0 / 2.0) as f32).sqrt(),
+            0 => (1.0 / 2.0f32).sqrt(),
             _ => 1.0,
         }
     }
 
-    pub(crate) fn new(sample_precision: SamplePrecision) -> Self {
+    /// A scaled block of N x N samples is reconstructed from the N x N lowest frequencies of the
+    /// block, which keeps the DC level of the block intact.
+    pub(crate) fn new(
+        sample_precision: SamplePrecision,
+        method: IdctMethod,
+        scale_factor: ScaleFactor,
+    ) -> Self {
+        let block_size = scale_factor.block_size();
         let mut idct_table = [0.0; 64];
 
-        for u in 0..8 {
-            for x in 0..8 {
-                idct_table[u * 8 + x] =
-                    Self::norm_coeff(u) * ((2.0 * x as f32 + 1.0) * u as f32 * PI / 16.0).cos()
+        for u in 0..block_size {
+            for x in 0..block_size {
+                idct_table[u * 8 + x] = Self::norm_coeff(u)
+                    * ((2.0 * x as f32 + 1.0) * u as f32 * PI / (2.0 * block_size as f32)).cos()
             }
         }
 
         Self {
             table: idct_table,
             precision: sample_precision,
+            method,
+            block_size,
         }
     }
 
-    /// todo refactor this!
-    pub(crate) fn perform_idct(&self, mcu: [f32; 64]) -> [f32; 64] {
+    /// The value added to every reconstructed sample to undo the level shift of A.3.1.
+    pub(crate) fn level_shift(&self) -> f32 {
+        (1u32 << (self.precision.bits() - 1)) as f32
+    }
+
+    /// (A.3.3) Transforms a block of dequantized coefficients in natural order. The N x N output
+    /// samples are written row by row into the front of the returned array, level shifted.
+    pub(crate) fn perform_idct(&self, mcu: Simd<f32, 64>) -> [f32; 64] {
+        let mcu = mcu.to_array();
+
+        let mut output = match self.method {
+            IdctMethod::Direct => self.direct(&mcu),
+            IdctMethod::Separable => self.separable(&mcu),
+        };
+
+        let level_shift = self.level_shift();
+        output[..self.block_size * self.block_size]
+            .iter_mut()
+            .for_each(|sample| *sample += level_shift);
+
+        output
+    }
+
+    fn direct(&self, mcu: &[f32; 64]) -> [f32; 64] {
+        let n = self.block_size;
         let mut output = [0f32; 64];
 
-        for x in 0..8 {
-            for y in 0..8 {
+        for y in 0..n {
+            for x in 0..n {
                 let mut local_sum = 0.0;
 
-                for u in 0..self.precision as usize {
-                    for v in 0..self.precision as usize {
-                        let cu = if u == 0 {
-                            ((1.0 / 2.0) as f32).sqrt()
-                        } else {
-                            1.0
-                        };
-                        let cv = if v == 0 {
-                            ((1.0 / 2.0) as f32).sqrt()
-                        } else {
-                            1.0
-                        };
-                        let dct_coeff = mcu[u * 8 + v];
-                        local_sum +=
-                            cu * cv * dct_coeff * self.table[u * 8 + x] * self.table[v * 8 + y];
+                for v in 0..n {
+                    for u in 0..n {
+                        local_sum += mcu[v * 8 + u] * self.table[v * 8 + y] * self.table[u * 8 + x];
                     }
                 }
 
-                output[x * 8 + y] = 0.25 * local_sum;
+                output[y * n + x] = 0.25 * local_sum;
             }
         }
 
         output
     }
+
+    /// The two-dimensional transform factors into a one-dimensional transform over every column,
+    /// followed by one over every row.
+    fn separable(&self, mcu: &[f32; 64]) -> [f32; 64] {
+        let n = self.block_size;
+        let mut columns = [0f32; 64];
+
+        for y in 0..n {
+            for u in 0..n {
+                columns[y * 8 + u] = 0.5
+                    * (0..n)
+                        .map(|v| mcu[v * 8 + u] * self.table[v * 8 + y])
+                        .sum::<f32>();
+            }
+        }
+
+        let mut output = [0f32; 64];
+
+        for y in 0..n {
+            for x in 0..n {
+                output[y * n + x] = 0.5
+                    * (0..n)
+                        .map(|u| columns[y * 8 + u] * self.table[u * 8 + x])
+                        .sum::<f32>();
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_methods_agree() {
+        let mut coefficients = [0f32; 64];
+        coefficients[0] = -320.0;
+        coefficients[1] = 24.0;
+        coefficients[9] = -12.0;
+        coefficients[56] = 7.0;
+        let coefficients = Simd::from_array(coefficients);
+
+        let direct = IDCT::new(
+            SamplePrecision::EightBit,
+            IdctMethod::Direct,
+            ScaleFactor::Full,
+        );
+        let separable = IDCT::new(
+            SamplePrecision::EightBit,
+            IdctMethod::Separable,
+            ScaleFactor::Full,
+        );
+
+        let (a, b) = (
+            direct.perform_idct(coefficients),
+            separable.perform_idct(coefficients),
+        );
+
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_dc_level_is_kept_when_scaled() {
+        let mut coefficients = [0f32; 64];
+        coefficients[0] = 80.0;
+        let coefficients = Simd::from_array(coefficients);
+
+        for scale_factor in [
+            ScaleFactor::Full,
+            ScaleFactor::Half,
+            ScaleFactor::Quarter,
+            ScaleFactor::Eighth,
+        ] {
+            let idct = IDCT::new(
+                SamplePrecision::EightBit,
+                IdctMethod::Separable,
+                scale_factor,
+            );
+            let n = scale_factor.block_size();
+
+            let output = idct.perform_idct(coefficients);
+            for sample in &output[..n * n] {
+                assert!((sample - 138.0).abs() < 1e-3);
+            }
+        }
+    }
 }
@@ -0,0 +1,238 @@
+use anyhow::{anyhow, Result};
+
+/// The units of the pixel densities of a JFIF segment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DensityUnits {
+    /// The densities only give the aspect ratio of a pixel.
+    AspectRatio,
+    DotsPerInch,
+    DotsPerCentimeter,
+}
+
+/// A thumbnail embedded in a JFIF or JFXX segment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Thumbnail {
+    /// Interleaved 8-bit RGB samples, row by row.
+    RGB {
+        width: usize,
+        height: usize,
+        data: Vec<u8>,
+    },
+
+    /// One byte per pixel, indexing a palette of 256 RGB colors.
+    Palette {
+        width: usize,
+        height: usize,
+        palette: Vec<[u8; 3]>,
+        indices: Vec<u8>,
+    },
+
+    /// A complete JPEG stream, coded with the baseline process.
+    JPEG(Vec<u8>),
+}
+
+impl Thumbnail {
+    /// The pixels of an uncompressed thumbnail as interleaved RGB samples, along with its width
+    /// and height. A JPEG thumbnail has to be decoded on its own.
+    pub fn to_rgb(&self) -> Option<(usize, usize, Vec<u8>)> {
+        match self {
+            Thumbnail::RGB {
+                width,
+                height,
+                data,
+            } => Some((*width, *height, data.clone())),
+            Thumbnail::Palette {
+                width,
+                height,
+                palette,
+                indices,
+            } => Some((
+                *width,
+                *height,
+                indices
+                    .iter()
+                    .flat_map(|idx| palette[*idx as usize])
+                    .collect(),
+            )),
+            Thumbnail::JPEG(_) => None,
+        }
+    }
+}
+
+/// The JFIF APP0 segment, along with the thumbnails of any JFXX extension segments after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JFIF {
+    /// (major, minor), such as (1, 2) for version 1.02.
+    pub version: (u8, u8),
+    pub density_units: DensityUnits,
+    pub x_density: u16,
+    pub y_density: u16,
+    pub thumbnail: Option<Thumbnail>,
+    pub extension_thumbnails: Vec<Thumbnail>,
+}
+
+impl JFIF {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"JFIF\0";
+    pub(crate) const EXTENSION_IDENTIFIER: &'static [u8] = b"JFXX\0";
+
+    /// Parses the payload of an APP0 segment that starts with the JFIF identifier.
+    pub(crate) fn parse(segment: &[u8]) -> Result<Self> {
+        let data = segment
+            .strip_prefix(Self::IDENTIFIER)
+            .ok_or(anyhow!("expected a JFIF identifier"))?;
+
+        if data.len() < 9 {
+            return Err(anyhow!(format!(
+                "expected at least 9 bytes in the JFIF segment. Got {}",
+                data.len()
+            )));
+        }
+
+        let density_units = match data[2] {
+            0 => DensityUnits::AspectRatio,
+            1 => DensityUnits::DotsPerInch,
+            2 => DensityUnits::DotsPerCentimeter,
+            units => return Err(anyhow!(format!("unknown JFIF density units {units}"))),
+        };
+
+        let (width, height) = (data[7] as usize, data[8] as usize);
+        let thumbnail = match width * height {
+            0 => None,
+            _ => Some(Self::parse_rgb(width, height, &data[9..])?),
+        };
+
+        Ok(JFIF {
+            version: (data[0], data[1]),
+            density_units,
+            x_density: u16::from_be_bytes([data[3], data[4]]),
+            y_density: u16::from_be_bytes([data[5], data[6]]),
+            thumbnail,
+            extension_thumbnails: vec![],
+        })
+    }
+
+    /// Parses the thumbnail of an APP0 segment that starts with the JFXX identifier.
+    pub(crate) fn parse_extension(segment: &[u8]) -> Result<Thumbnail> {
+        let data = segment
+            .strip_prefix(Self::EXTENSION_IDENTIFIER)
+            .ok_or(anyhow!("expected a JFXX identifier"))?;
+
+        let (&extension_code, data) = data
+            .split_first()
+            .ok_or(anyhow!("expected a JFXX extension code"))?;
+
+        if extension_code == 0x10 {
+            return Ok(Thumbnail::JPEG(data.to_vec()));
+        }
+
+        let [width, height, data @ ..] = data else {
+            return Err(anyhow!("expected the dimensions of the JFXX thumbnail"));
+        };
+        let (width, height) = (*width as usize, *height as usize);
+
+        match extension_code {
+            0x11 => {
+                if data.len() < 768 + width * height {
+                    return Err(anyhow!("not enough data for the JFXX palette thumbnail"));
+                }
+
+                Ok(Thumbnail::Palette {
+                    width,
+                    height,
+                    palette: data[..768]
+                        .chunks_exact(3)
+                        .map(|color| [color[0], color[1], color[2]])
+                        .collect(),
+                    indices: data[768..768 + width * height].to_vec(),
+                })
+            }
+            0x13 => Self::parse_rgb(width, height, data),
+            _ => Err(anyhow!(format!(
+                "unknown JFXX extension code {extension_code:#04X}"
+            ))),
+        }
+    }
+
+    fn parse_rgb(width: usize, height: usize, data: &[u8]) -> Result<Thumbnail> {
+        if data.len() < 3 * width * height {
+            return Err(anyhow!(format!(
+                "expected {} bytes for a {width}x{height} RGB thumbnail. Got {}",
+                3 * width * height,
+                data.len()
+            )));
+        }
+
+        Ok(Thumbnail::RGB {
+            width,
+            height,
+            data: data[..3 * width * height].to_vec(),
+        })
+    }
+
+    /// The horizontal and vertical pixel densities in dots per inch, when the units are known.
+    pub fn dots_per_inch(&self) -> Option<(f32, f32)> {
+        let (x, y) = (self.x_density as f32, self.y_density as f32);
+
+        match self.density_units {
+            DensityUnits::AspectRatio => None,
+            DensityUnits::DotsPerInch => Some((x, y)),
+            DensityUnits::DotsPerCentimeter => Some((x * 2.54, y * 2.54)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_jfif() -> Result<()> {
+        let mut segment = b"JFIF\0".to_vec();
+        segment.extend([1, 2, 2, 0, 118, 0, 118, 2, 1]);
+        segment.extend([255, 0, 0, 0, 0, 255]);
+
+        let jfif = JFIF::parse(&segment)?;
+        assert_eq!(jfif.version, (1, 2));
+        assert_eq!(jfif.density_units, DensityUnits::DotsPerCentimeter);
+        assert_eq!((jfif.x_density, jfif.y_density), (118, 118));
+
+        let (x, y) = jfif.dots_per_inch().unwrap();
+        assert!((x - 299.72).abs() < 1e-3 && (y - 299.72).abs() < 1e-3);
+
+        assert_eq!(
+            jfif.thumbnail,
+            Some(Thumbnail::RGB {
+                width: 2,
+                height: 1,
+                data: vec![255, 0, 0, 0, 0, 255],
+            })
+        );
+
+        // the thumbnail is cut short.
+        assert!(JFIF::parse(&segment[..segment.len() - 1]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_extension() -> Result<()> {
+        let mut segment = b"JFXX\0\x11".to_vec();
+        segment.extend([2, 1]);
+        segment.extend((0..=255u8).flat_map(|idx| [idx, 0, 255 - idx]));
+        segment.extend([0, 255]);
+
+        let thumbnail = JFIF::parse_extension(&segment)?;
+        assert_eq!(thumbnail.to_rgb(), Some((2, 1, vec![0, 0, 255, 255, 0, 0])));
+
+        let thumbnail = JFIF::parse_extension(b"JFXX\0\x10\xFF\xD8\xFF\xD9")?;
+        assert_eq!(thumbnail, Thumbnail::JPEG(vec![0xFF, 0xD8, 0xFF, 0xD9]));
+        assert_eq!(thumbnail.to_rgb(), None);
+
+        let thumbnail = JFIF::parse_extension(b"JFXX\0\x13\x01\x01\x01\x02\x03")?;
+        assert_eq!(thumbnail.to_rgb(), Some((1, 1, vec![1, 2, 3])));
+
+        assert!(JFIF::parse_extension(b"JFXX\0\x12\x01\x01").is_err());
+
+        Ok(())
+    }
+}
@@ -1,24 +1,70 @@
 #![feature(portable_simd)]
+// Markers and stages are named after the abbreviations used throughout ITU-T.81.
+#![allow(clippy::upper_case_acronyms)]
 
 extern crate core;
 
-/// Features
-mod grayscale;
+/// Converts RGB pixels of any layout into grayscale.
+pub mod grayscale;
 
 /// The decoder takes as input compressed image data and table specifications, and by means of a
 /// specific set of procedures generates as output `digital reconstructed image data`.
 pub mod decoder;
 
+/// Choices that control how the decoder reconstructs and writes out an image.
+pub mod options;
+
+/// The EXIF orientations, and how to lay out a raster for display.
+pub mod orientation;
+
+/// The Adobe APP14 segment and the color transform it records.
+pub mod adobe;
 mod bitreader;
+
+/// Converts decoded RGB between matrix/TRC ICC profiles, such as from Display P3 to sRGB.
+pub mod cms;
 mod coding;
-mod color_spaces;
+
+/// Conversions between the color spaces components are coded in and written out in.
+pub mod color_spaces;
 mod dequantizer;
 mod entropy_decoder;
+
+/// The EXIF APP1 segment and the IFDs of its TIFF structure.
+pub mod exif;
 pub(crate) mod frame_header;
 pub(crate) mod huffman_tree;
+
+/// ICC profiles, reassembled from the APP2 segments they are split across.
+pub mod icc;
 mod idct;
+
+/// The JFIF APP0 segment and its JFXX extensions.
+pub mod jfif;
 pub(crate) mod marker;
+
+/// The MP index of multi-picture files, such as MPO stereo pairs.
+pub mod mpf;
 pub(crate) mod parser;
+
+/// Photoshop APP13 image resources and their IPTC-IIM records.
+pub mod photoshop;
+
+/// Components at the resolution they were coded at, and packers for video formats.
+pub mod planar;
+
 pub(crate) mod quantization_table;
+/// Palette quantization of decoded RGB into 8-bit indexed images.
+pub mod quantize;
 pub(crate) mod sample_precision;
 pub(crate) mod scan_header;
+
+/// Decodes an image into caller-provided buffers, one MCU row at a time.
+pub mod scanline_reader;
+
+/// Normalized f32 tensors of decoded samples, in HWC or CHW layout.
+pub mod tensor;
+mod upsampler;
+
+/// XMP packets, including extended XMP split across APP1 segments.
+pub mod xmp;
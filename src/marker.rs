@@ -1,5 +1,7 @@
 use std::collections::HashSet;
 
+use anyhow::{anyhow, Result};
+
 use crate::coding::{CodingProcess, EntropyCoding};
 
 pub(crate) enum MarkerType {
@@ -401,16 +403,6 @@ impl Marker {
         ])
     }
 
-    pub fn singleton(&self) -> bool {
-        match self {
-            Marker::DHT => false,
-            Marker::DAC => false,
-            Marker::DQT => false,
-            Marker::EXP => false,
-            _ => true,
-        }
-    }
-
     /// Some markers stand alone, that is, which is not the start of a markery segment.
     pub fn is_segment(&self) -> MarkerType {
         match self {
@@ -429,14 +421,81 @@ impl Marker {
         }
     }
 
-    pub fn encoding_process(&self) -> (CodingProcess, EntropyCoding) {
+    pub fn is_start_of_frame(&self) -> bool {
+        matches!(
+            self,
+            Marker::SOF0
+                | Marker::SOF1
+                | Marker::SOF2
+                | Marker::SOF3
+                | Marker::SOF5
+                | Marker::SOF6
+                | Marker::SOF7
+                | Marker::SOF9
+                | Marker::SOF10
+                | Marker::SOF11
+                | Marker::SOF13
+                | Marker::SOF14
+                | Marker::SOF15
+        )
+    }
+
+    /// The coding process of a frame is identified by the marker that starts it.
+    pub fn encoding_process(&self) -> Result<(CodingProcess, EntropyCoding)> {
         match self {
-            Marker::SOF0 => (CodingProcess::BaselineDCT, EntropyCoding::Huffman(vec![])),
-            Marker::SOF1 => (
+            Marker::SOF0 => Ok((CodingProcess::BaselineDCT, EntropyCoding::Huffman(vec![]))),
+            Marker::SOF1 => Ok((
                 CodingProcess::ExtendedSequentialDCT,
                 EntropyCoding::Huffman(vec![]),
-            ),
-            _ => unreachable!(),
+            )),
+            Marker::SOF2 => Ok((
+                CodingProcess::ProgressiveDCT,
+                EntropyCoding::Huffman(vec![]),
+            )),
+            Marker::SOF3 => Ok((CodingProcess::Lossless, EntropyCoding::Huffman(vec![]))),
+            Marker::SOF9 => Ok((
+                CodingProcess::ExtendedSequentialDCT,
+                EntropyCoding::Arithmetic,
+            )),
+            Marker::SOF10 => Ok((CodingProcess::ProgressiveDCT, EntropyCoding::Arithmetic)),
+            Marker::SOF11 => Ok((CodingProcess::Lossless, EntropyCoding::Arithmetic)),
+            Marker::SOF5
+            | Marker::SOF6
+            | Marker::SOF7
+            | Marker::SOF13
+            | Marker::SOF14
+            | Marker::SOF15 => Err(anyhow!(
+                "hierarchical coding processes are not supported. Got {:?}",
+                self
+            )),
+            _ => Err(anyhow!("{:?} does not start a frame", self)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_process() -> Result<()> {
+        assert!(matches!(
+            Marker::SOF0.encoding_process()?,
+            (CodingProcess::BaselineDCT, EntropyCoding::Huffman(_))
+        ));
+        assert!(matches!(
+            Marker::SOF2.encoding_process()?,
+            (CodingProcess::ProgressiveDCT, EntropyCoding::Huffman(_))
+        ));
+        assert!(matches!(
+            Marker::SOF11.encoding_process()?,
+            (CodingProcess::Lossless, EntropyCoding::Arithmetic)
+        ));
+
+        // hierarchical frames are not supported, and other markers do not start a frame.
+        assert!(Marker::SOF5.encoding_process().is_err());
+        assert!(Marker::DHT.encoding_process().is_err());
+
+        Ok(())
+    }
+}
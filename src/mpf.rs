@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+
+use crate::exif::{Exif, IfdKind, Value};
+
+/// (CIPA DC-007 5.2.3.3.1) What an individual image holds, from the type code of its entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageType {
+    BaselinePrimary,
+    LargeThumbnailVGA,
+    LargeThumbnailFullHD,
+    Panorama,
+    /// One view of a stereo pair.
+    Disparity,
+    MultiAngle,
+    Undefined,
+    /// A type code outside the standard, such as those of depth and gain maps.
+    Other(u32),
+}
+
+impl ImageType {
+    fn from(type_code: u32) -> Self {
+        match type_code {
+            0x030000 => ImageType::BaselinePrimary,
+            0x010001 => ImageType::LargeThumbnailVGA,
+            0x010002 => ImageType::LargeThumbnailFullHD,
+            0x020001 => ImageType::Panorama,
+            0x020002 => ImageType::Disparity,
+            0x020003 => ImageType::MultiAngle,
+            0x000000 => ImageType::Undefined,
+            _ => ImageType::Other(type_code),
+        }
+    }
+}
+
+/// (CIPA DC-007 5.2.3.3) An entry of the MP index, locating one individual image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MpEntry {
+    pub image_type: ImageType,
+    pub dependent_parent: bool,
+    pub dependent_child: bool,
+    pub representative: bool,
+
+    /// The length of the image, from its SOI marker to its EOI marker.
+    pub size: usize,
+
+    /// The offset of the image from the start of the file. The first image is at 0.
+    pub offset: usize,
+
+    /// The entries, counting from 1, of the images that depend on this one, or 0.
+    pub dependent_images: (u16, u16),
+}
+
+/// (CIPA DC-007 5.2.3) The MP index of the APP2 MPF segment of the first image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MpIndex {
+    pub version: String,
+    pub entries: Vec<MpEntry>,
+}
+
+impl MpIndex {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"MPF\0";
+
+    const VERSION: u16 = 0xB000;
+    const NUMBER_OF_IMAGES: u16 = 0xB001;
+    const MP_ENTRY: u16 = 0xB002;
+
+    /// Parses the payload of an APP2 segment that starts with the MPF identifier. The offsets of
+    /// the entries are relative to the TIFF header that follows the identifier, which starts at
+    /// `tiff_offset` within the file.
+    pub(crate) fn parse(segment: &[u8], tiff_offset: usize) -> Result<Self> {
+        let tiff = segment
+            .strip_prefix(Self::IDENTIFIER)
+            .ok_or(anyhow!("expected an MPF identifier"))?;
+
+        // the MP index IFD is laid out as IFD0 of a TIFF structure.
+        let (index, _) = Exif::parse_tiff(tiff, &mut HashSet::new())?;
+        let field = |tag| index.get(IfdKind::Primary, tag);
+
+        let version = match field(Self::VERSION) {
+            Some(Value::Undefined(version)) => String::from_utf8_lossy(version).to_string(),
+            _ => return Err(anyhow!("expected the MPF version")),
+        };
+
+        let num_images = field(Self::NUMBER_OF_IMAGES)
+            .and_then(Value::as_u32)
+            .ok_or(anyhow!("expected the number of images in the MP index"))?
+            as usize;
+
+        let data = match field(Self::MP_ENTRY) {
+            Some(Value::Undefined(data)) if data.len() >= 16 * num_images => data,
+            _ => {
+                return Err(anyhow!(format!(
+                    "expected {num_images} entries in the MP index"
+                )))
+            }
+        };
+
+        let u32_at = |entry: &[u8], offset: usize| {
+            index
+                .byte_order
+                .u32(entry[offset..offset + 4].try_into().unwrap())
+        };
+        let u16_at = |entry: &[u8], offset: usize| {
+            index
+                .byte_order
+                .u16(entry[offset..offset + 2].try_into().unwrap())
+        };
+
+        let entries = data
+            .chunks_exact(16)
+            .take(num_images)
+            .map(|entry| {
+                let attributes = u32_at(entry, 0);
+                let offset = u32_at(entry, 8) as usize;
+
+                MpEntry {
+                    image_type: ImageType::from(attributes & 0xFFFFFF),
+                    dependent_parent: attributes & (1 << 31) != 0,
+                    dependent_child: attributes & (1 << 30) != 0,
+                    representative: attributes & (1 << 29) != 0,
+                    size: u32_at(entry, 4) as usize,
+                    offset: match offset {
+                        0 => 0,
+                        _ => tiff_offset + offset,
+                    },
+                    dependent_images: (u16_at(entry, 12), u16_at(entry, 14)),
+                }
+            })
+            .collect();
+
+        Ok(MpIndex { version, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mp_index() -> Result<()> {
+        let mut segment = b"MPF\0II\x2A\0\x08\0\0\0".to_vec();
+        segment.extend([3, 0]);
+        segment.extend([0x00, 0xB0, 7, 0, 4, 0, 0, 0]);
+        segment.extend(b"0100");
+        segment.extend([0x01, 0xB0, 4, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+        segment.extend([0x02, 0xB0, 7, 0, 32, 0, 0, 0, 50, 0, 0, 0]);
+        segment.extend([0, 0, 0, 0]);
+
+        segment.extend(0x20030000u32.to_le_bytes());
+        segment.extend(1000u32.to_le_bytes());
+        segment.extend(0u32.to_le_bytes());
+        segment.extend([2, 0, 0, 0]);
+
+        segment.extend(0x00020002u32.to_le_bytes());
+        segment.extend(500u32.to_le_bytes());
+        segment.extend(980u32.to_le_bytes());
+        segment.extend([0, 0, 0, 0]);
+
+        let index = MpIndex::parse(&segment, 24)?;
+        assert_eq!(index.version, "0100");
+        assert_eq!(
+            index.entries,
+            vec![
+                MpEntry {
+                    image_type: ImageType::BaselinePrimary,
+                    dependent_parent: false,
+                    dependent_child: false,
+                    representative: true,
+                    size: 1000,
+                    offset: 0,
+                    dependent_images: (2, 0),
+                },
+                MpEntry {
+                    image_type: ImageType::Disparity,
+                    dependent_parent: false,
+                    dependent_child: false,
+                    representative: false,
+                    size: 500,
+                    offset: 1004,
+                    dependent_images: (0, 0),
+                },
+            ]
+        );
+
+        // the entries are cut short.
+        segment.truncate(segment.len() - 16);
+        assert!(MpIndex::parse(&segment, 24).is_err());
+
+        Ok(())
+    }
+}
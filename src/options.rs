@@ -0,0 +1,277 @@
+/// The color space of the samples the decoder writes out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputColorSpace {
+    /// Three interleaved samples per pixel, converted from the decoded YCbCr components.
+    RGB,
+
+    /// Three interleaved samples per pixel, exactly as reconstructed from the image data.
+    YCbCr,
+
+    /// A single luminance sample per pixel, the Y component as decoded. The chroma of YCbCr
+    /// images is entropy decoded, but never dequantized, transformed, upsampled or converted.
+    Grayscale,
+}
+
+impl OutputColorSpace {
+    pub fn num_channels(&self) -> usize {
+        match self {
+            OutputColorSpace::RGB | OutputColorSpace::YCbCr => 3,
+            OutputColorSpace::Grayscale => 1,
+        }
+    }
+}
+
+/// The color space the components of an image are coded in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CodedColorSpace {
+    Grayscale,
+    YCbCr,
+
+    /// Red, green and blue coded as is, without a conversion to YCbCr.
+    RGB,
+    CMYK,
+
+    /// CMYK with the CMY converted to YCbCr, and K coded as is.
+    YCCK,
+}
+
+impl CodedColorSpace {
+    pub fn num_components(&self) -> usize {
+        match self {
+            CodedColorSpace::Grayscale => 1,
+            CodedColorSpace::YCbCr | CodedColorSpace::RGB => 3,
+            CodedColorSpace::CMYK | CodedColorSpace::YCCK => 4,
+        }
+    }
+}
+
+/// The algorithm used to perform the inverse discrete cosine transform.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdctMethod {
+    /// Evaluates the two-dimensional sum of A.3.3 for every output sample. Slow, but a
+    /// straightforward transcription of the specification.
+    Direct,
+
+    /// Performs the one-dimensional transform on the columns and then on the rows of a block.
+    Separable,
+}
+
+/// The algorithm used to bring subsampled components up to the resolution of the image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpsamplingMethod {
+    /// Replicates every component sample across the output samples it covers.
+    NearestNeighbor,
+
+    /// (libjpeg "fancy upsampling") Interpolates chroma sited between the luma samples with a
+    /// triangle filter, reading the rows of the neighbouring MCU rows. Only chroma at half the
+    /// horizontal resolution, and at full or half the vertical one, is filtered. Other chroma
+    /// is replicated.
+    Fancy,
+}
+
+/// (ITU-T T.871, BT.709, BT.2020) The weights of the red and blue primaries in the luma of the
+/// YCbCr samples, from which the rest of the conversion to RGB follows.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum YCbCrMatrix {
+    /// The JFIF matrix, used by almost every still image.
+    BT601,
+
+    /// HDTV, used by frames grabbed from most video.
+    BT709,
+
+    /// UHDTV, non-constant luminance.
+    BT2020,
+
+    /// Any other weights, each in (0, 1) and together less than 1.
+    Custom { kr: f32, kb: f32 },
+}
+
+impl YCbCrMatrix {
+    /// The weights of red and blue in the luma.
+    pub fn coefficients(&self) -> (f32, f32) {
+        match self {
+            YCbCrMatrix::BT601 => (0.299, 0.114),
+            YCbCrMatrix::BT709 => (0.2126, 0.0722),
+            YCbCrMatrix::BT2020 => (0.2627, 0.0593),
+            YCbCrMatrix::Custom { kr, kb } => (*kr, *kb),
+        }
+    }
+}
+
+/// The levels the YCbCr samples span.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum YCbCrRange {
+    /// Every sample spans [0, 255], as JFIF requires.
+    Full,
+
+    /// Luma spans [16, 235] and chroma [16, 240], as in studio video.
+    Limited,
+}
+
+/// Reduces the output dimensions by decoding each 8x8 block into a smaller block.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScaleFactor {
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+
+impl ScaleFactor {
+    pub fn denominator(&self) -> usize {
+        match self {
+            ScaleFactor::Full => 1,
+            ScaleFactor::Half => 2,
+            ScaleFactor::Quarter => 4,
+            ScaleFactor::Eighth => 8,
+        }
+    }
+
+    /// The width and height of a block once it has been transformed.
+    pub(crate) fn block_size(&self) -> usize {
+        8 / self.denominator()
+    }
+
+    pub(crate) fn scale(&self, dimension: usize) -> usize {
+        dimension.div_ceil(self.denominator())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DecoderOptions {
+    pub(crate) output_color_space: OutputColorSpace,
+    pub(crate) idct_method: IdctMethod,
+    pub(crate) upsampling_method: UpsamplingMethod,
+    pub(crate) scale_factor: ScaleFactor,
+    pub(crate) ycbcr_matrix: YCbCrMatrix,
+    pub(crate) ycbcr_range: YCbCrRange,
+
+    /// The color space the components are interpreted in. `None` detects it from the markers
+    /// and component IDs of the image.
+    pub(crate) coded_color_space: Option<CodedColorSpace>,
+
+    /// When set, malformed but recoverable streams are rejected instead of being decoded on a
+    /// best-effort basis.
+    pub(crate) strict: bool,
+
+    /// The number of worker threads. `None` uses the global rayon pool.
+    pub(crate) num_threads: Option<usize>,
+
+    /// When set, `decode` lays the image out for display according to its EXIF orientation.
+    pub(crate) auto_orient: bool,
+
+    /// When set, RGB output is converted from the embedded ICC profile to sRGB.
+    pub(crate) color_management: bool,
+}
+
+impl Default for DecoderOptions {
+    fn default() -> Self {
+        DecoderOptions {
+            output_color_space: OutputColorSpace::RGB,
+            idct_method: IdctMethod::Separable,
+            upsampling_method: UpsamplingMethod::NearestNeighbor,
+            scale_factor: ScaleFactor::Full,
+            ycbcr_matrix: YCbCrMatrix::BT601,
+            ycbcr_range: YCbCrRange::Full,
+            coded_color_space: None,
+            strict: false,
+            num_threads: None,
+            auto_orient: false,
+            color_management: false,
+        }
+    }
+}
+
+impl DecoderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn output_color_space(mut self, output_color_space: OutputColorSpace) -> Self {
+        self.output_color_space = output_color_space;
+        self
+    }
+
+    pub fn idct_method(mut self, idct_method: IdctMethod) -> Self {
+        self.idct_method = idct_method;
+        self
+    }
+
+    pub fn upsampling_method(mut self, upsampling_method: UpsamplingMethod) -> Self {
+        self.upsampling_method = upsampling_method;
+        self
+    }
+
+    pub fn scale_factor(mut self, scale_factor: ScaleFactor) -> Self {
+        self.scale_factor = scale_factor;
+        self
+    }
+
+    pub fn ycbcr_matrix(mut self, ycbcr_matrix: YCbCrMatrix) -> Self {
+        self.ycbcr_matrix = ycbcr_matrix;
+        self
+    }
+
+    pub fn ycbcr_range(mut self, ycbcr_range: YCbCrRange) -> Self {
+        self.ycbcr_range = ycbcr_range;
+        self
+    }
+
+    pub fn coded_color_space(mut self, coded_color_space: CodedColorSpace) -> Self {
+        self.coded_color_space = Some(coded_color_space);
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    pub fn auto_orient(mut self, auto_orient: bool) -> Self {
+        self.auto_orient = auto_orient;
+        self
+    }
+
+    pub fn color_management(mut self, color_management: bool) -> Self {
+        self.color_management = color_management;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let options = DecoderOptions::new()
+            .output_color_space(OutputColorSpace::Grayscale)
+            .idct_method(IdctMethod::Direct)
+            .scale_factor(ScaleFactor::Quarter)
+            .ycbcr_matrix(YCbCrMatrix::BT709)
+            .ycbcr_range(YCbCrRange::Limited)
+            .coded_color_space(CodedColorSpace::RGB)
+            .strict(true)
+            .num_threads(2)
+            .auto_orient(true)
+            .color_management(true);
+
+        assert_eq!(options.output_color_space, OutputColorSpace::Grayscale);
+        assert_eq!(options.idct_method, IdctMethod::Direct);
+        assert_eq!(options.upsampling_method, UpsamplingMethod::NearestNeighbor);
+        assert_eq!(options.scale_factor.block_size(), 2);
+        assert_eq!(options.scale_factor.scale(763), 191);
+        assert_eq!(options.ycbcr_matrix.coefficients(), (0.2126, 0.0722));
+        assert_eq!(options.ycbcr_range, YCbCrRange::Limited);
+        assert_eq!(options.coded_color_space, Some(CodedColorSpace::RGB));
+        assert!(options.strict);
+        assert_eq!(options.num_threads, Some(2));
+        assert!(options.auto_orient);
+        assert!(options.color_management);
+    }
+}
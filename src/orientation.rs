@@ -0,0 +1,190 @@
+/// (TIFF 6.0, Orientation) How the stored rows and columns are to be laid out for display.
+/// Every variant names what has to be done to the stored raster to display it upright.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Orientation {
+    /// 1: row 0 at the top, column 0 at the left.
+    Normal,
+    /// 2: row 0 at the top, column 0 at the right.
+    FlipHorizontal,
+    /// 3: row 0 at the bottom, column 0 at the right.
+    Rotate180,
+    /// 4: row 0 at the bottom, column 0 at the left.
+    FlipVertical,
+    /// 5: row 0 at the left, column 0 at the top.
+    Transpose,
+    /// 6: row 0 at the right, column 0 at the top.
+    Rotate90,
+    /// 7: row 0 at the right, column 0 at the bottom.
+    Transverse,
+    /// 8: row 0 at the left, column 0 at the bottom.
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn from(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match self {
+            Orientation::Normal => 1,
+            Orientation::FlipHorizontal => 2,
+            Orientation::Rotate180 => 3,
+            Orientation::FlipVertical => 4,
+            Orientation::Transpose => 5,
+            Orientation::Rotate90 => 6,
+            Orientation::Transverse => 7,
+            Orientation::Rotate270 => 8,
+        }
+    }
+
+    /// Whether the displayed image is as wide as the stored one is tall.
+    pub fn swaps_dimensions(&self) -> bool {
+        matches!(
+            self,
+            Orientation::Transpose
+                | Orientation::Rotate90
+                | Orientation::Transverse
+                | Orientation::Rotate270
+        )
+    }
+
+    /// Lays out the interleaved samples of a `width` x `height` raster for display. Returns the
+    /// displayed width and height along with the samples.
+    pub fn apply<T: Copy>(
+        &self,
+        width: usize,
+        height: usize,
+        num_channels: usize,
+        data: &[T],
+    ) -> (usize, usize, Vec<T>) {
+        if *self == Orientation::Normal {
+            return (width, height, data.to_vec());
+        }
+
+        let (output_width, output_height) = match self.swaps_dimensions() {
+            true => (height, width),
+            false => (width, height),
+        };
+
+        let mut output = Vec::with_capacity(data.len());
+
+        for y in 0..output_height {
+            for x in 0..output_width {
+                let (source_x, source_y) = self.source(width, height, x, y);
+
+                let offset = (source_y * width + source_x) * num_channels;
+                output.extend_from_slice(&data[offset..offset + num_channels]);
+            }
+        }
+
+        (output_width, output_height, output)
+    }
+
+    /// The stored pixel of a `width` x `height` raster that is displayed at (x, y).
+    fn source(&self, width: usize, height: usize, x: usize, y: usize) -> (usize, usize) {
+        match self {
+            Orientation::Normal => (x, y),
+            Orientation::FlipHorizontal => (width - 1 - x, y),
+            Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Orientation::FlipVertical => (x, height - 1 - y),
+            Orientation::Transpose => (y, x),
+            Orientation::Rotate90 => (y, height - 1 - x),
+            Orientation::Transverse => (width - 1 - y, height - 1 - x),
+            Orientation::Rotate270 => (width - 1 - y, x),
+        }
+    }
+
+    /// The stored rectangle (x, y, width, height) of a `width` x `height` raster that is
+    /// displayed as `region`, which has to fit in the displayed raster. Laying the stored
+    /// rectangle out with `apply` gives the displayed one.
+    pub(crate) fn source_region(
+        &self,
+        width: usize,
+        height: usize,
+        (x, y, region_width, region_height): (usize, usize, usize, usize),
+    ) -> (usize, usize, usize, usize) {
+        let (x0, y0) = self.source(width, height, x, y);
+        let (x1, y1) = self.source(width, height, x + region_width - 1, y + region_height - 1);
+
+        (
+            x0.min(x1),
+            y0.min(y1),
+            x0.abs_diff(x1) + 1,
+            y0.abs_diff(y1) + 1,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        // 1 2 3
+        // 4 5 6
+        let data = [1, 2, 3, 4, 5, 6];
+
+        let expected: [(u16, (usize, usize), [u8; 6]); 8] = [
+            (1, (3, 2), [1, 2, 3, 4, 5, 6]),
+            (2, (3, 2), [3, 2, 1, 6, 5, 4]),
+            (3, (3, 2), [6, 5, 4, 3, 2, 1]),
+            (4, (3, 2), [4, 5, 6, 1, 2, 3]),
+            (5, (2, 3), [1, 4, 2, 5, 3, 6]),
+            (6, (2, 3), [4, 1, 5, 2, 6, 3]),
+            (7, (2, 3), [6, 3, 5, 2, 4, 1]),
+            (8, (2, 3), [3, 6, 2, 5, 1, 4]),
+        ];
+
+        for (value, dimensions, samples) in expected {
+            let orientation = Orientation::from(value).unwrap();
+            assert_eq!(orientation.value(), value);
+
+            let (width, height, output) = orientation.apply(3, 2, 1, &data);
+            assert_eq!((width, height), dimensions, "orientation {value}");
+            assert_eq!(output, samples, "orientation {value}");
+        }
+
+        assert_eq!(Orientation::from(9), None);
+
+        // the channels of a pixel stay together.
+        let (_, _, output) = Orientation::Rotate180.apply(2, 1, 2, &[1, 2, 3, 4]);
+        assert_eq!(output, vec![3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn test_source_region() {
+        // a 5x4 raster, so that every displayed region can be compared with the oriented one.
+        let data: Vec<u8> = (0..20).collect();
+
+        for value in 1..=8 {
+            let orientation = Orientation::from(value).unwrap();
+            let (display_width, display_height, display) = orientation.apply(5, 4, 1, &data);
+
+            let region = (1, 2, 2, 1);
+            let (x, y, width, height) = orientation.source_region(5, 4, region);
+
+            let stored: Vec<u8> = (y..y + height)
+                .flat_map(|y| data[y * 5 + x..y * 5 + x + width].to_vec())
+                .collect();
+            let (_, _, output) = orientation.apply(width, height, 1, &stored);
+
+            let expected: Vec<u8> = (region.1..region.1 + region.3)
+                .flat_map(|y| display[y * display_width + region.0..][..region.2].to_vec())
+                .collect();
+            assert!(region.1 + region.3 <= display_height);
+            assert_eq!(output, expected, "orientation {value}");
+        }
+    }
+}
@@ -4,13 +4,14 @@ use std::simd::prelude::*;
 
 use anyhow::{anyhow, Result};
 
-use crate::coding::CodingProcess;
+use crate::coding::{CodingProcess, EntropyCoding};
+use crate::decoder::SharedBuffer;
 use crate::frame_header::{Component, ComponentType, FrameHeader};
 use crate::huffman_tree::HuffmanTree;
 use crate::marker::Marker;
 use crate::quantization_table::QuantizationTable;
 use crate::sample_precision::SamplePrecision;
-use crate::scan_header::{EncodingOrder, ScanComponentSelector, ScanHeader};
+use crate::scan_header::{ScanComponentSelector, ScanHeader};
 
 pub const QUANTIZATION_TABLE_BYTES: usize = 64;
 
@@ -18,190 +19,214 @@ pub(crate) type Marlen = (usize, usize); // offset, length
 pub(crate) type MarlenMap = HashMap<Marker, Vec<Marlen>>;
 
 pub(crate) struct Parser {
-    buffer: Vec<u8>,
+    buffer: SharedBuffer,
     marlen_map: MarlenMap,
+    pub(crate) encoding: CodingProcess,
+    pub(crate) entropy_coding: EntropyCoding,
 }
 
 impl Parser {
-    pub fn new(buffer: Vec<u8>, marlen_map: MarlenMap, encoding: CodingProcess) -> Self {
-        Parser { buffer, marlen_map }
-    }
-
-    fn parse_huffman_information(&self) -> Result<([u8; 4], [u8; 4])> {
-        let huffman_marlen = self.get_marker_segment(&Marker::DHT)?;
-
-        let ht_informations: Simd<u8, 4> = Simd::from_slice(
-            &huffman_marlen
-                .iter()
-                .map(|(o, _)| self.buffer[*o])
-                .collect::<Vec<u8>>(),
-        );
-
-        // extract ht information
-        let ht_number_mask = Simd::splat(0b1111);
-        let ht_numbers = ht_informations & ht_number_mask;
-
-        // extract ht type (bit 4)
-        let ht_type_mask = Simd::splat(0b11110000);
-        let ht_types = (ht_informations & ht_type_mask) >> 4;
-
-        let ht_numbers = ht_numbers.to_array();
-        let ht_types = ht_types.to_array();
-
-        Ok((ht_types, ht_numbers))
-    }
-
-    fn parse_quant_table_information(&self) -> Result<([u8; 2], [u8; 2])> {
-        let qt_marlens = self.get_marker_segment(&Marker::DQT)?;
-        debug_assert_eq!(qt_marlens.len(), 2);
-
-        let qt_informations: Simd<u8, 2> = Simd::from_slice(
-            &qt_marlens
-                .iter()
-                .map(|(o, _)| self.buffer[*o])
-                .collect::<Vec<u8>>(),
-        );
-
-        // extract ht information
-        let qt_precisions_mask = Simd::splat(0b11110000);
-        let qt_precisions = (qt_informations & qt_precisions_mask) >> 4;
-
-        let qt_ids_mask = Simd::splat(0b1111);
-        let qt_ids = qt_informations & qt_ids_mask;
-
-        let qt_precisions = qt_precisions.to_array();
-        let qt_ids = qt_ids.to_array();
-
-        Ok((qt_ids, qt_precisions))
+    pub fn new(
+        buffer: SharedBuffer,
+        marlen_map: MarlenMap,
+        encoding: CodingProcess,
+        entropy_coding: EntropyCoding,
+    ) -> Self {
+        Parser {
+            buffer,
+            marlen_map,
+            encoding,
+            entropy_coding,
+        }
     }
 
+    /// A DQT segment may define more than one table. Each table is preceded by a byte holding its
+    /// precision (Pq) in the high nibble and its destination (Tq) in the low nibble.
     pub(crate) fn parse_quant_table(&self) -> Result<Vec<QuantizationTable>> {
         let mut tables = vec![];
 
-        let (qt_ids, qt_precisions) = self.parse_quant_table_information()?;
-
         let qt_marlens = self.get_marker_segment(&Marker::DQT)?;
-        for (idx, (offset, _)) in qt_marlens.iter().enumerate() {
-            let current_offset = offset + Marker::SIZE;
-            debug_assert!(self.buffer.len() > current_offset + QUANTIZATION_TABLE_BYTES);
+        for marlen in qt_marlens {
+            let segment = self.segment(*marlen);
+            let mut current_offset = 0;
 
-            let qt_data: Simd<u8, QUANTIZATION_TABLE_BYTES> = Simd::from_slice(
-                &self.buffer[current_offset..current_offset + QUANTIZATION_TABLE_BYTES],
-            );
+            while current_offset < segment.len() {
+                let qt_information = segment[current_offset];
+                let (qt_precision, qt_id) = (qt_information >> 4, qt_information & 0b1111);
+                current_offset += 1;
 
-            let (qt_id, qt_precision) = (qt_ids[idx], qt_precisions[idx]);
-            tables.push(QuantizationTable::from(qt_id, qt_precision, qt_data))
+                let precision = SamplePrecision::decode(qt_precision)?;
+
+                let element_bytes = QUANTIZATION_TABLE_BYTES * (qt_precision as usize + 1);
+                if segment.len() < current_offset + element_bytes {
+                    return Err(anyhow!("Not enough data to extract quantization table"));
+                }
+
+                let qt_data: Simd<u16, QUANTIZATION_TABLE_BYTES> = match qt_precision {
+                    0 => Simd::<u8, QUANTIZATION_TABLE_BYTES>::from_slice(
+                        &segment[current_offset..current_offset + element_bytes],
+                    )
+                    .cast(),
+                    _ => Simd::from_array(std::array::from_fn(|idx| {
+                        u16::from_be_bytes([
+                            segment[current_offset + 2 * idx],
+                            segment[current_offset + 2 * idx + 1],
+                        ])
+                    })),
+                };
+                current_offset += element_bytes;
+
+                tables.push(QuantizationTable::from(qt_id, precision, qt_data))
+            }
         }
 
         Ok(tables)
     }
 
+    /// The bytes of a segment, cut off at the end of the buffer when its length runs past it.
+    fn segment(&self, (offset, length): Marlen) -> &[u8] {
+        let end = offset.saturating_add(length).min(self.buffer.len());
+        &self.buffer[offset.min(end)..end]
+    }
+
     fn get_marker_segment(&self, marker: &Marker) -> Result<&Vec<(usize, usize)>> {
-        Ok(self
-            .marlen_map
+        self.marlen_map
             .get(marker)
-            .ok_or(anyhow!("failed to get marker"))?)
+            .ok_or(anyhow!("failed to get marker"))
     }
 
     pub(crate) fn parse_huffman_trees(&self) -> Result<Vec<HuffmanTree>> {
+        self.parse_huffman_trees_before(usize::MAX)
+    }
+
+    /// A DHT segment may define more than one table. Each table is preceded by a byte holding its
+    /// class (Tc) in the high nibble and its destination (Th) in the low nibble. Only the segments
+    /// before `end` are parsed, in order, so a table redefined later in the stream comes last.
+    pub(crate) fn parse_huffman_trees_before(&self, end: usize) -> Result<Vec<HuffmanTree>> {
         let huffman_marlens = self.get_marker_segment(&Marker::DHT)?;
-        debug_assert_eq!(huffman_marlens.len(), 4);
 
         let mut trees = vec![];
 
-        let (ht_types, ht_numbers) = self.parse_huffman_information()?;
-
-        for (idx, (offset, length)) in huffman_marlens.iter().enumerate() {
-            let mut current_offset = offset + 1;
+        for marlen in huffman_marlens.iter().filter(|(offset, _)| *offset < end) {
+            let segment = self.segment(*marlen);
+            let mut current_offset = 0;
 
-            if self.buffer.len() < current_offset + 16 {
-                return Err(anyhow!("Not enough data to extract symbol table"));
-            }
+            while current_offset < segment.len() {
+                let ht_information = segment[current_offset];
+                let (ht_type, ht_number) = (ht_information >> 4, ht_information & 0b1111);
+                current_offset += 1;
 
-            let sym_table = &self.buffer[current_offset..current_offset + 16];
+                if ht_type > 1 || ht_number > 3 {
+                    return Err(anyhow!(
+                        "expected a huffman table class of 0 or 1 and a destination of 0 to 3. Got {ht_type} and {ht_number}"
+                    ));
+                }
 
-            let mut flat_lengths = vec![];
+                if segment.len() < current_offset + 16 {
+                    return Err(anyhow!("Not enough data to extract symbol table"));
+                }
 
-            for (idx, mult) in sym_table.iter().enumerate() {
-                flat_lengths.extend(iter::repeat(idx + 1).take(*mult as usize));
-            }
+                let sym_table = &segment[current_offset..current_offset + 16];
 
-            current_offset += 16;
+                let mut flat_lengths = vec![];
 
-            let code_len = (offset + length) - current_offset;
-            debug_assert_eq!(current_offset + code_len, offset + length);
+                for (idx, mult) in sym_table.iter().enumerate() {
+                    flat_lengths.extend(iter::repeat_n(idx + 1, *mult as usize));
+                }
 
-            let code_freq = self.buffer[current_offset..current_offset + code_len]
-                .iter()
-                .zip(flat_lengths.iter())
-                .map(|(&code, &freq)| (code, freq))
-                .collect::<Vec<_>>();
+                current_offset += 16;
 
-            let tree = HuffmanTree::from(ht_types[idx], ht_numbers[idx], code_freq);
-            trees.push(tree);
-        }
+                let code_len = flat_lengths.len();
+                if segment.len() < current_offset + code_len {
+                    return Err(anyhow!("Not enough data to extract huffman values"));
+                }
 
-        Ok(trees)
-    }
+                // (F.1.2.1, F.1.2.2) a DC value is the number of additional bits of a difference,
+                // at most 11 for 8-bit samples and 15 for 12-bit ones. The low nibble of an AC
+                // value is the number of additional bits of a coefficient, so it never exceeds 15.
+                let values = &segment[current_offset..current_offset + code_len];
+                if let Some(value) = values.iter().find(|&&value| ht_type == 0 && value > 15) {
+                    return Err(anyhow!(
+                        "huffman value {value:#04x} codes more than 15 additional bits"
+                    ));
+                }
 
-    pub(crate) fn parse_start_of_scan(&self) -> Result<(ScanHeader, usize)> {
-        let sos_marlens = self.get_marker_segment(&Marker::SOS)?;
-        debug_assert_eq!(sos_marlens.len(), 1);
+                let code_lengths = values
+                    .iter()
+                    .zip(flat_lengths.iter())
+                    .map(|(&code, &length)| (code, length))
+                    .collect::<Vec<_>>();
 
-        let (offset, _) = sos_marlens[0];
+                current_offset += code_len;
 
-        let mut current_offset = offset;
+                let tree = HuffmanTree::from(ht_type, ht_number, code_lengths);
+                trees.push(tree);
+            }
+        }
 
-        let (component_type, encoding_order) = ComponentType::from(self.buffer[current_offset]);
-        current_offset += 1;
+        Ok(trees)
+    }
 
-        debug_assert_eq!(
-            component_type,
-            ComponentType::Color,
-            "as of now assume only dealing with color components is 3"
-        );
+    pub(crate) fn parse_restart_interval(&self) -> Result<usize> {
+        self.parse_restart_interval_before(usize::MAX)
+    }
 
-        let mut scan_component_selectors = vec![];
+    /// Ri: the number of MCUs in a restart interval, or zero when restart markers are disabled.
+    /// The last DRI segment before `end` is the one in effect.
+    pub(crate) fn parse_restart_interval_before(&self, end: usize) -> Result<usize> {
+        let dri_marlen = self
+            .marlen_map
+            .get(&Marker::DRI)
+            .and_then(|dri_marlens| dri_marlens.iter().rev().find(|(offset, _)| *offset < end));
 
-        let component_ids = Simd::from([
-            self.buffer[current_offset],
-            self.buffer[current_offset + 2],
-            self.buffer[current_offset + (2 * 2)],
-            0,
-        ]);
+        match dri_marlen.map(|marlen| self.segment(*marlen)) {
+            Some([high, low, ..]) => Ok(u16::from_be_bytes([*high, *low]) as usize),
+            Some(_) => Err(anyhow!("Not enough data to extract restart interval")),
+            None => Ok(0),
+        }
+    }
 
-        current_offset += 1;
+    /// The header of the first scan, along with the index its entropy-coded data starts at.
+    pub(crate) fn parse_start_of_scan(&self) -> Result<(ScanHeader, usize)> {
+        let marlen = self.get_marker_segment(&Marker::SOS)?[0];
+        self.parse_scan_header(marlen)
+    }
 
-        let huffman_table_ids = Simd::from([
-            self.buffer[current_offset],
-            self.buffer[current_offset + 2],
-            self.buffer[current_offset + (2 * 2)],
-            0,
-        ]);
+    /// The header of every scan in the order they appear, along with the index the entropy-coded
+    /// data of each starts at.
+    pub(crate) fn parse_start_of_scans(&self) -> Result<Vec<(ScanHeader, usize)>> {
+        self.get_marker_segment(&Marker::SOS)?
+            .iter()
+            .map(|marlen| self.parse_scan_header(*marlen))
+            .collect()
+    }
 
-        current_offset -= 1;
+    fn parse_scan_header(&self, marlen: Marlen) -> Result<(ScanHeader, usize)> {
+        let segment = self.segment(marlen);
 
-        let dc_huffman_table_ids = huffman_table_ids >> 4;
-        let ac_huffman_table_ids = huffman_table_ids & Simd::splat(0b1111);
+        let Some(&num_components) = segment.first() else {
+            return Err(anyhow!("Not enough data to extract scan header"));
+        };
+        let (component_type, encoding_order) = ComponentType::parse(num_components)?;
 
-        for i in 0..3 {
-            scan_component_selectors.push(ScanComponentSelector::from(
-                component_ids[i],
-                dc_huffman_table_ids[i],
-                ac_huffman_table_ids[i],
-            ));
+        // Ns, then Cs and Td/Ta of every component, then Ss, Se and Ah/Al.
+        let header_len = 1 + 2 * num_components as usize + 3;
+        if segment.len() < header_len {
+            return Err(anyhow!("Not enough data to extract scan header"));
         }
 
-        current_offset += 2 * (component_type as usize);
-
-        let start_of_spectral = self.buffer[current_offset];
-        current_offset += 1;
-
-        let end_of_spectral = self.buffer[current_offset];
-        current_offset += 1;
-
-        let approx_bit_chunk = self.buffer[current_offset];
-        current_offset += 1;
+        let scan_component_selectors = segment[1..header_len - 3]
+            .chunks_exact(2)
+            .map(|selector| {
+                ScanComponentSelector::from(selector[0], selector[1] >> 4, selector[1] & 0b1111)
+            })
+            .collect();
+
+        let (start_of_spectral, end_of_spectral, approx_bit_chunk) = (
+            segment[header_len - 3],
+            segment[header_len - 2],
+            segment[header_len - 1],
+        );
 
         let (successive_approx_bit_position_high, point_transform) =
             (approx_bit_chunk >> 4, approx_bit_chunk & 0b1111);
@@ -216,173 +241,184 @@ impl Parser {
                 successive_approx_bit_position_high,
                 point_transform,
             },
-            current_offset,
+            marlen.0 + header_len,
         ))
     }
 
+    /// The first frame marker in the stream, which identifies the coding process.
+    pub(crate) fn start_of_frame_marker(marlen_map: &MarlenMap) -> Result<Marker> {
+        marlen_map
+            .iter()
+            .filter(|(marker, _)| marker.is_start_of_frame())
+            .min_by_key(|(_, marlens)| marlens[0].0)
+            .map(|(marker, _)| *marker)
+            .ok_or(anyhow!("failed to find a start of frame marker"))
+    }
+
     pub(crate) fn parse_start_of_frame(&self) -> Result<FrameHeader> {
-        let sof_marlens = self.get_marker_segment(&Marker::SOF0)?;
+        let sof_marker = Parser::start_of_frame_marker(&self.marlen_map)?;
+        let sof_marlens = self.get_marker_segment(&sof_marker)?;
         debug_assert_eq!(sof_marlens.len(), 1);
 
-        let (offset, _) = sof_marlens[0];
-        let mut current_offset = offset;
+        let segment = self.segment(sof_marlens[0]);
 
-        let precision = SamplePrecision::parse(self.buffer[current_offset]);
-        current_offset += 1;
+        // P, Y, X and Nf, then Ci, Hi/Vi and Tqi of every component.
+        if segment.len() < 6 {
+            return Err(anyhow!("Not enough data to extract frame header"));
+        }
 
-        let image_dim: Simd<u8, 4> =
-            Simd::from_slice(&self.buffer[current_offset..current_offset + 4]);
+        let precision = SamplePrecision::parse(segment[0])?;
         let (image_height, image_width) = (
-            (((image_dim[0] as u16) << 8) | (image_dim[1] as u16)) as usize,
-            (((image_dim[2] as u16) << 8) | (image_dim[3] as u16)) as usize,
+            u16::from_be_bytes([segment[1], segment[2]]) as usize,
+            u16::from_be_bytes([segment[3], segment[4]]) as usize,
         );
 
-        current_offset += 4;
+        let num_components = segment[5] as usize;
+        let component_type = ComponentType::parse(segment[5])?;
 
-        let component_type = ComponentType::from(self.buffer[current_offset]);
-        current_offset += 1;
-
-        let mut components = vec![];
-
-        match component_type.1 {
-            EncodingOrder::NonInterleaved => {
-                // naive solution
-                let component_id = self.buffer[current_offset];
-                current_offset += 1;
-                let sampling_factor = self.buffer[current_offset];
-                let (horizontal_factor, vertical_factor) =
-                    (sampling_factor >> 4, sampling_factor & 0b1111);
-                current_offset += 1;
-                let qt_table_id = self.buffer[current_offset];
-
-                components.push(Component::from(
-                    component_id,
-                    horizontal_factor,
-                    vertical_factor,
-                    qt_table_id,
-                ))
-            }
-            EncodingOrder::Interleaved => {
-                let component_ids = Simd::from([
-                    self.buffer[current_offset],
-                    self.buffer[current_offset + 3],
-                    self.buffer[current_offset + 2 * 3],
-                    0,
-                ]);
-                current_offset += 1;
-
-                let sampling_factors = Simd::from([
-                    self.buffer[current_offset],
-                    self.buffer[current_offset + 3],
-                    self.buffer[current_offset + 2 * 3],
-                    0,
-                ]);
-                current_offset += 1;
-
-                let qt_table_ids = Simd::from([
-                    self.buffer[current_offset],
-                    self.buffer[current_offset + 3],
-                    self.buffer[current_offset + 2 * 3],
-                    0,
-                ]);
-
-                let horizontal_factors = sampling_factors >> 4;
-                let vertical_factors = sampling_factors & Simd::splat(0b1111);
-
-                for i in 0..3 {
-                    let component = Component::from(
-                        component_ids[i],
-                        horizontal_factors[i],
-                        vertical_factors[i],
-                        qt_table_ids[i],
-                    );
-                    components.push(component);
-                }
-            }
+        if segment.len() < 6 + 3 * num_components {
+            return Err(anyhow!("Not enough data to extract frame header"));
         }
 
-        Ok(FrameHeader {
+        let components: Vec<_> = segment[6..6 + 3 * num_components]
+            .chunks_exact(3)
+            .map(|component| {
+                Component::from(
+                    component[0],
+                    component[1] >> 4,
+                    component[1] & 0b1111,
+                    component[2],
+                )
+            })
+            .collect();
+
+        let frame_header = FrameHeader {
             precision,
             image_height,
             image_width,
             component_type: component_type.0,
             components,
-        })
+        };
+
+        // (B.2.2) every sampling factor is 1 to 4, and the upsampling ratios Hmax / Hi and
+        // Vmax / Vi are whole numbers.
+        let (h_max, v_max) = frame_header.max_sampling_factors();
+        for component in &frame_header.components {
+            let (h, v) = (
+                component.horizontal_scaling_factor as usize,
+                component.vertical_scaling_factor as usize,
+            );
+
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+                return Err(anyhow!(
+                    "expected sampling factors of 1 to 4. Got {h}x{v} for component {}",
+                    component.component_id
+                ));
+            }
+
+            if h_max % h != 0 || v_max % v != 0 {
+                return Err(anyhow!(
+                    "the sampling factors {h}x{v} of component {} do not divide {h_max}x{v_max}",
+                    component.component_id
+                ));
+            }
+        }
+
+        Ok(frame_header)
     }
 
-    pub(crate) fn parse_image_data(&self, start_of_image_data_index: usize) -> Result<Vec<u8>> {
-        let end_of_image_data_index = self.buffer.len() - Marker::SIZE - 1;
-        let image_length = end_of_image_data_index - start_of_image_data_index;
+    /// The entropy-coded data of a scan (B.1.1.5), which runs up to the first marker other than a
+    /// restart marker. The data is shared rather than copied, so the stuffed zero bytes are left
+    /// for the `BitReader` to skip. Also returns the offset of every restart marker within the
+    /// data, in order.
+    pub(crate) fn entropy_coded_data(
+        &self,
+        start_of_image_data_index: usize,
+    ) -> Result<(SharedBuffer, Vec<usize>)> {
+        let end_of_image_data_index = self.buffer.len();
 
         let mut current_index = start_of_image_data_index;
         const LANE_COUNT: usize = 64;
 
-        let mut temp_chunk = [0u8; LANE_COUNT];
-        let mut result = Vec::with_capacity(image_length);
+        let mut restart_offsets = vec![];
 
-        while current_index < self.buffer.len() - Marker::SIZE {
-            let end = (current_index + LANE_COUNT).min(self.buffer.len() - Marker::SIZE);
+        while current_index < end_of_image_data_index {
+            let end = (current_index + LANE_COUNT).min(end_of_image_data_index);
             let len = end - current_index;
 
+            let mut temp_chunk = [0u8; LANE_COUNT];
             temp_chunk[..len].copy_from_slice(&self.buffer[current_index..end]);
 
             let image_chunk: Simd<u8, LANE_COUNT> = Simd::from_slice(&temp_chunk);
-            // suppose i just had [0xFF, 0x00, 0xFF, 0x00]
-
             let ff_mask = image_chunk.simd_eq(Simd::splat(0xFF));
-            // [true, false, true, false]
 
             let shift_image_chunk = image_chunk.rotate_elements_left::<1>();
-            // [0x00, 0xFF, 0x00, 0x00]
             let zero_mask = shift_image_chunk.simd_eq(Simd::splat(0x00));
-            // [true, false, true, true]
 
-            let zero_after_ff_mask = ff_mask & zero_mask;
-            // [ true, false, true, false]
+            let restart_mask = shift_image_chunk.simd_ge(Simd::splat(Marker::RST0 as u8))
+                & shift_image_chunk.simd_le(Simd::splat(Marker::RST7 as u8));
+            let restart_after_ff_mask = ff_mask & restart_mask;
+
+            // fill bytes of 0xFF may precede a marker, the last of them starts the marker itself.
+            let fill_mask = shift_image_chunk.simd_eq(Simd::splat(0xFF));
+            let marker_after_ff_mask = ff_mask & !zero_mask & !restart_mask & !fill_mask;
+
+            // The last lane wraps around to the first when rotated, so a trailing 0xFF is left
+            // for the next chunk to pair with the byte that follows it.
+            let limit = match len == LANE_COUNT && ff_mask.test(LANE_COUNT - 1) {
+                true => LANE_COUNT - 1,
+                false => len,
+            };
 
-            let mut chunk_result = Vec::with_capacity(LANE_COUNT);
             let mut i = 0;
 
-            while i < len {
-                if zero_after_ff_mask.test(i) {
-                    chunk_result.push(temp_chunk[i]);
-                    i += 2;
-                    continue;
+            while i < limit {
+                if marker_after_ff_mask.test(i) {
+                    break;
+                }
+
+                if restart_after_ff_mask.test(i) {
+                    restart_offsets.push(current_index + i - start_of_image_data_index);
+                }
+
+                // a stuffed zero byte is never the first byte of a marker.
+                match ff_mask.test(i) && zero_mask.test(i) {
+                    true => i += 2,
+                    false => i += 1,
                 }
-                chunk_result.push(temp_chunk[i]);
-                i += 1;
             }
 
-            result.extend(chunk_result);
-            current_index += LANE_COUNT;
+            current_index += i;
+
+            if i < limit {
+                break;
+            }
         }
 
-        Ok(result)
+        Ok((
+            self.buffer
+                .slice(start_of_image_data_index..current_index.min(end_of_image_data_index)),
+            restart_offsets,
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
     use std::fs::{File, OpenOptions};
     use std::io::Write;
     use std::sync::Once;
 
-    use memmap::Mmap;
-
+    use crate::bitreader::BitReader;
     use crate::decoder::Decoder;
     use crate::huffman_tree::HuffmanClass;
 
     use super::*;
 
     fn mike_parser() -> Result<Parser> {
-        let mut decoder = Decoder {
-            mmap: unsafe { Mmap::map(&File::open("mike.jpg")?)? },
-            cursor: 0,
-            encoding: CodingProcess::BaselineDCT,
-        };
+        let mut decoder = Decoder::from_file_path("mike.jpg")?;
 
-        Ok(decoder.setup()?)
+        decoder.setup()
     }
 
     #[test]
@@ -426,13 +462,13 @@ mod tests {
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x08, 0x38, 0x88, 0xB6, // 25
                 0xFF, 0xC4, // HUFFMAN 3 89
                 0x00, 0x15, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x0Aa, // 21
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x0A, // 21
                 0xFF, 0xC4, // HUFFMAN 4 112
                 0x00, 0x1C, 0x11, 0x00, 0x01, 0x03, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x07, 0xB8, 0x09, 0x38, 0x39, 0x76,
                 0x78, // 28
                 0xFF, 0xDA, // START OF SCAN
-                0x00, 0x08, 0x03, 0x01, 0x10, 0x01, 0x3F,
+                0x00, 0x08, 0x01, 0x01, 0x10, 0x01, 0x3F,
                 0x10, // three bytes that we skip in sos
                 0xFF, // this should be the start of image data
                 0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0x02, 0x04, b'h', 0x02, 0xFF, 0xD9, // EOI
@@ -454,13 +490,8 @@ mod tests {
         setup();
 
         let file = File::open("../../mock_jpeg_decode.bin")?;
-        let mmap = unsafe { Mmap::map(&file)? };
 
-        let mut decoder = Decoder {
-            mmap,
-            cursor: 0,
-            encoding: CodingProcess::BaselineDCT,
-        };
+        let mut decoder = Decoder::from_file(file)?;
         let parser = decoder.setup()?;
 
         let FrameHeader {
@@ -529,11 +560,147 @@ mod tests {
         assert_eq!(scan_header.end_of_spectral, 63);
         assert_eq!(scan_header.successive_approx_bit_position_high, 1);
         assert_eq!(scan_header.point_transform, 0);
+        let (data, restart_offsets) = parser.entropy_coded_data(s_idx)?;
+        assert_eq!(data.len(), 11);
+        assert!(restart_offsets.is_empty());
+
+        let mut reader = BitReader::new(data);
+        for byte in [0xFF, 0x00, 0xFF, 0xFF, 0x02, 0x04, b'h', 0x02] {
+            assert_eq!(reader.read_bits(8)?, byte as u32);
+        }
+        assert!(reader.read_bit().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_tables_per_segment() -> Result<()> {
+        // one DQT segment holding an 8-bit and a 16-bit table, and one DHT segment holding a DC
+        // and an AC table.
+        let dqt = [
+            [vec![0x00], vec![1; 64]].concat(),
+            [vec![0x11], [0x01, 0x00].repeat(64)].concat(),
+        ]
+        .concat();
+        let dht = [
+            [vec![0x00, 0, 1], vec![0; 14], vec![7]].concat(),
+            [vec![0x11, 1], vec![0; 15], vec![9]].concat(),
+        ]
+        .concat();
+
+        let buffer = [dqt.clone(), dht.clone()].concat();
+        let marlen_map = HashMap::from([
+            (Marker::DQT, vec![(0, dqt.len())]),
+            (Marker::DHT, vec![(dqt.len(), dht.len())]),
+        ]);
+        let parser = Parser::new(
+            SharedBuffer::new(buffer),
+            marlen_map,
+            CodingProcess::ExtendedSequentialDCT,
+            EntropyCoding::Huffman(vec![]),
+        );
+
+        let tables = parser.parse_quant_table()?;
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].quantization_table_element[63], 1);
+        assert_eq!(
+            (tables[1].table_id, tables[1].quantization_table_element[0]),
+            (1, 256)
+        );
+
+        let trees = parser.parse_huffman_trees()?;
+        assert_eq!(trees.len(), 2);
+        assert_eq!(
+            (trees[0].class, trees[0].destination_id),
+            (HuffmanClass::DC, 0)
+        );
+        assert_eq!(
+            (trees[1].class, trees[1].destination_id),
+            (HuffmanClass::AC, 1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_segments() -> Result<()> {
+        let parser = |marker: Marker, segment: Vec<u8>, length: usize| {
+            Parser::new(
+                SharedBuffer::new(segment),
+                HashMap::from([(marker, vec![(0, length)])]),
+                CodingProcess::BaselineDCT,
+                EntropyCoding::Huffman(vec![]),
+            )
+        };
+
+        // a quantization table precision of 2.
+        let dqt = [vec![0x20], vec![1; 128]].concat();
+        assert!(parser(Marker::DQT, dqt.clone(), dqt.len())
+            .parse_quant_table()
+            .is_err());
+
+        // a huffman table class of 2, a destination of 4, and a DC value of 16 bits.
+        for information in [0x20, 0x04] {
+            let dht = [vec![information, 1], vec![0; 15], vec![7]].concat();
+            assert!(parser(Marker::DHT, dht.clone(), dht.len())
+                .parse_huffman_trees()
+                .is_err());
+        }
+        let dht = [vec![0x00, 1], vec![0; 15], vec![16]].concat();
+        assert!(parser(Marker::DHT, dht.clone(), dht.len())
+            .parse_huffman_trees()
+            .is_err());
+
+        // a DHT length running past the end of the buffer stops at the end of it.
+        let dht = [vec![0x00, 1], vec![0; 15], vec![7]].concat();
         assert_eq!(
-            parser.parse_image_data(s_idx)?,
-            [0xFF, 0x00, 0xFF, 0xFF, 0x02, 0x04, b'h', 0x02,].to_vec()
+            parser(Marker::DHT, dht.clone(), dht.len() + 64)
+                .parse_huffman_trees()?
+                .len(),
+            1
         );
 
+        // scan, restart interval and frame headers cut off within the buffer.
+        assert!(parser(Marker::SOS, vec![0x03, 0x01, 0x00], 3)
+            .parse_start_of_scan()
+            .is_err());
+        assert!(parser(Marker::DRI, vec![0x00], 2)
+            .parse_restart_interval()
+            .is_err());
+        assert!(parser(
+            Marker::SOF0,
+            vec![0x08, 0x00, 0x10, 0x00, 0x10, 0x03, 0x01],
+            17
+        )
+        .parse_start_of_frame()
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sampling_factors() -> Result<()> {
+        let parser = |sampling_factors: [u8; 2]| {
+            let sof = [
+                vec![0x08, 0x00, 0x10, 0x00, 0x10, 0x02],
+                vec![0x01, sampling_factors[0], 0x00],
+                vec![0x02, sampling_factors[1], 0x00],
+            ]
+            .concat();
+
+            Parser::new(
+                SharedBuffer::new(sof),
+                HashMap::from([(Marker::SOF0, vec![(0, 12)])]),
+                CodingProcess::BaselineDCT,
+                EntropyCoding::Huffman(vec![]),
+            )
+        };
+
+        assert!(parser([0x42, 0x21]).parse_start_of_frame().is_ok());
+        assert!(parser([0x22, 0x01]).parse_start_of_frame().is_err());
+        assert!(parser([0x51, 0x11]).parse_start_of_frame().is_err());
+        assert!(parser([0x31, 0x21]).parse_start_of_frame().is_err());
+
         Ok(())
     }
 }
@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Result};
+
+/// A Photoshop image resource block, with its data as stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageResource {
+    pub id: u16,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// An IPTC-IIM dataset. The text of the datasets of the application record (2) that describe
+/// the image is decoded, while every other dataset is kept as stored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IptcRecord {
+    /// 2:05
+    ObjectName(String),
+    /// 2:25, repeated for every keyword.
+    Keyword(String),
+    /// 2:40
+    SpecialInstructions(String),
+    /// 2:55, as CCYYMMDD.
+    DateCreated(String),
+    /// 2:80, repeated for every creator.
+    ByLine(String),
+    /// 2:85
+    ByLineTitle(String),
+    /// 2:90
+    City(String),
+    /// 2:95
+    ProvinceState(String),
+    /// 2:101
+    Country(String),
+    /// 2:105
+    Headline(String),
+    /// 2:110
+    Credit(String),
+    /// 2:115
+    Source(String),
+    /// 2:116
+    CopyrightNotice(String),
+    /// 2:120
+    Caption(String),
+    /// 2:122
+    Writer(String),
+    Other {
+        record: u8,
+        dataset: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl IptcRecord {
+    fn from(record: u8, dataset: u8, data: &[u8]) -> Self {
+        // writers that do not declare UTF-8 (1:90) almost always use Latin-1.
+        let text = || match std::str::from_utf8(data) {
+            Ok(text) => text.to_string(),
+            Err(_) => data.iter().map(|b| *b as char).collect(),
+        };
+
+        match (record, dataset) {
+            (2, 5) => IptcRecord::ObjectName(text()),
+            (2, 25) => IptcRecord::Keyword(text()),
+            (2, 40) => IptcRecord::SpecialInstructions(text()),
+            (2, 55) => IptcRecord::DateCreated(text()),
+            (2, 80) => IptcRecord::ByLine(text()),
+            (2, 85) => IptcRecord::ByLineTitle(text()),
+            (2, 90) => IptcRecord::City(text()),
+            (2, 95) => IptcRecord::ProvinceState(text()),
+            (2, 101) => IptcRecord::Country(text()),
+            (2, 105) => IptcRecord::Headline(text()),
+            (2, 110) => IptcRecord::Credit(text()),
+            (2, 115) => IptcRecord::Source(text()),
+            (2, 116) => IptcRecord::CopyrightNotice(text()),
+            (2, 120) => IptcRecord::Caption(text()),
+            (2, 122) => IptcRecord::Writer(text()),
+            _ => IptcRecord::Other {
+                record,
+                dataset,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+/// The image resources of the APP13 segments, along with the IPTC-IIM records of resource
+/// 0x0404.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Photoshop {
+    pub resources: Vec<ImageResource>,
+    pub iptc: Vec<IptcRecord>,
+}
+
+impl Photoshop {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"Photoshop 3.0\0";
+    const IPTC_RESOURCE_ID: u16 = 0x0404;
+
+    /// Parses the resource blocks of the APP13 segments that start with the Photoshop identifier.
+    /// A block can be split across segments, so their payloads are concatenated first.
+    pub(crate) fn parse(segments: &[&[u8]]) -> Result<Self> {
+        let mut data = vec![];
+        for segment in segments {
+            data.extend(
+                segment
+                    .strip_prefix(Self::IDENTIFIER)
+                    .ok_or(anyhow!("expected a Photoshop identifier"))?,
+            );
+        }
+
+        let resources = Self::parse_resources(&data)?;
+        let iptc = match resources.iter().find(|r| r.id == Self::IPTC_RESOURCE_ID) {
+            Some(resource) => Self::parse_iptc(&resource.data)?,
+            None => vec![],
+        };
+
+        Ok(Photoshop { resources, iptc })
+    }
+
+    /// Reads every resource block: a signature, an ID, a Pascal string name and the data, with
+    /// the name and the data each padded to an even length.
+    fn parse_resources(mut data: &[u8]) -> Result<Vec<ImageResource>> {
+        let mut resources = vec![];
+
+        // trailing padding is left by some writers.
+        while data.len() >= 4 {
+            if !matches!(&data[..4], b"8BIM" | b"PHUT" | b"AgHg" | b"DCSR") {
+                return Err(anyhow!(format!(
+                    "unknown image resource signature {:?}",
+                    &data[..4]
+                )));
+            }
+
+            let truncated = || anyhow!("the image resource block is cut short");
+
+            let id = u16::from_be_bytes(data.get(4..6).ok_or_else(truncated)?.try_into()?);
+            let name_length = *data.get(6).ok_or_else(truncated)? as usize;
+            let name = data.get(7..7 + name_length).ok_or_else(truncated)?;
+
+            let size_offset = 6 + (1 + name_length).next_multiple_of(2);
+            let size = data
+                .get(size_offset..size_offset + 4)
+                .ok_or_else(truncated)?;
+            let size = u32::from_be_bytes(size.try_into()?) as usize;
+
+            let data_offset = size_offset + 4;
+            let resource_data = data
+                .get(data_offset..data_offset + size)
+                .ok_or_else(truncated)?;
+
+            resources.push(ImageResource {
+                id,
+                name: String::from_utf8_lossy(name).to_string(),
+                data: resource_data.to_vec(),
+            });
+
+            data = data
+                .get(data_offset + size.next_multiple_of(2)..)
+                .unwrap_or_default();
+        }
+
+        Ok(resources)
+    }
+
+    /// (IPTC-IIM 4.1.5) Reads every dataset: a tag marker, record and dataset numbers, and the
+    /// length of the data, which is extended when its high bit is set.
+    fn parse_iptc(mut data: &[u8]) -> Result<Vec<IptcRecord>> {
+        let mut records = vec![];
+
+        while let [0x1C, record, dataset, high, low, rest @ ..] = data {
+            let mut length = u16::from_be_bytes([*high, *low]) as usize;
+            let mut rest = rest;
+
+            if length & 0x8000 != 0 {
+                let num_bytes = length & 0x7FFF;
+                if num_bytes > 4 || rest.len() < num_bytes {
+                    return Err(anyhow!("unsupported IPTC extended dataset length"));
+                }
+
+                length = rest[..num_bytes]
+                    .iter()
+                    .fold(0, |length, byte| (length << 8) | *byte as usize);
+                rest = &rest[num_bytes..];
+            }
+
+            let value = rest.get(..length).ok_or(anyhow!(format!(
+                "the IPTC dataset {record}:{dataset} is cut short"
+            )))?;
+            records.push(IptcRecord::from(*record, *dataset, value));
+
+            data = &rest[length..];
+        }
+
+        Ok(records)
+    }
+
+    pub fn resource(&self, id: u16) -> Option<&ImageResource> {
+        self.resources.iter().find(|resource| resource.id == id)
+    }
+
+    pub fn caption(&self) -> Option<&str> {
+        self.iptc.iter().find_map(|record| match record {
+            IptcRecord::Caption(caption) => Some(caption.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn by_lines(&self) -> Vec<&str> {
+        self.iptc
+            .iter()
+            .filter_map(|record| match record {
+                IptcRecord::ByLine(by_line) => Some(by_line.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn keywords(&self) -> Vec<&str> {
+        self.iptc
+            .iter()
+            .filter_map(|record| match record {
+                IptcRecord::Keyword(keyword) => Some(keyword.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn copyright(&self) -> Option<&str> {
+        self.iptc.iter().find_map(|record| match record {
+            IptcRecord::CopyrightNotice(copyright) => Some(copyright.as_str()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(id: u16, name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut block = b"8BIM".to_vec();
+        block.extend(id.to_be_bytes());
+        block.push(name.len() as u8);
+        block.extend(name);
+        if block.len() % 2 == 1 {
+            block.push(0);
+        }
+
+        block.extend((data.len() as u32).to_be_bytes());
+        block.extend(data);
+        if block.len() % 2 == 1 {
+            block.push(0);
+        }
+
+        block
+    }
+
+    fn dataset(record: u8, dataset: u8, data: &[u8]) -> Vec<u8> {
+        [
+            &[0x1C, record, dataset],
+            &(data.len() as u16).to_be_bytes()[..],
+            data,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_parse_photoshop() -> Result<()> {
+        let mut iptc = dataset(1, 90, b"\x1B%G");
+        iptc.extend(dataset(2, 120, "Caf\u{e9} at dawn".as_bytes()));
+        iptc.extend(dataset(2, 80, b"Jane Doe"));
+        iptc.extend(dataset(2, 80, b"John Roe"));
+        iptc.extend(dataset(2, 25, b"coffee"));
+        iptc.extend(dataset(2, 25, b"morning"));
+        iptc.extend(dataset(2, 116, b"\xA9 2024 Jane Doe"));
+
+        // the headline has an extended length.
+        let mut headline = vec![0x1C, 2, 105, 0x80, 0x04];
+        headline.extend(4u32.to_be_bytes());
+        headline.extend(b"Dawn");
+        iptc.extend(headline);
+
+        let mut data = [Photoshop::IDENTIFIER, &resource(0x03ED, b"", &[1, 2, 3])].concat();
+        data.extend(resource(0x0404, b"IPTC", &iptc));
+
+        // the blocks are split across two segments.
+        let (first, second) = data.split_at(30);
+        let second = [Photoshop::IDENTIFIER, second].concat();
+        let photoshop = Photoshop::parse(&[first, &second])?;
+
+        assert_eq!(photoshop.resources.len(), 2);
+        assert_eq!(photoshop.resource(0x03ED).unwrap().data, vec![1, 2, 3]);
+        assert_eq!(photoshop.resource(0x0404).unwrap().name, "IPTC");
+        assert_eq!(photoshop.resource(0x0404).unwrap().data, iptc);
+
+        assert_eq!(photoshop.caption(), Some("Caf\u{e9} at dawn"));
+        assert_eq!(photoshop.by_lines(), vec!["Jane Doe", "John Roe"]);
+        assert_eq!(photoshop.keywords(), vec!["coffee", "morning"]);
+        assert_eq!(photoshop.copyright(), Some("\u{a9} 2024 Jane Doe"));
+        assert_eq!(
+            photoshop.iptc[0],
+            IptcRecord::Other {
+                record: 1,
+                dataset: 90,
+                data: b"\x1B%G".to_vec()
+            }
+        );
+        assert_eq!(
+            photoshop.iptc.last(),
+            Some(&IptcRecord::Headline("Dawn".to_string()))
+        );
+
+        // the resource data is cut short.
+        assert!(Photoshop::parse(&[&data[..data.len() - 4]]).is_err());
+
+        Ok(())
+    }
+}
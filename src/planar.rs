@@ -0,0 +1,202 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+
+/// The samples of one component at the resolution it was coded at, row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plane {
+    pub component_id: u8,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Plane {
+    /// Resamples the plane to `width` x `height`. Every output sample is the average of the
+    /// samples it covers, or the nearest sample when it covers less than one.
+    pub fn resample(&self, width: usize, height: usize) -> Vec<u8> {
+        if (width, height) == (self.width, self.height) {
+            return self.data.clone();
+        }
+
+        let mut output = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            let rows = Self::span(y, height, self.height);
+
+            for x in 0..width {
+                let columns = Self::span(x, width, self.width);
+                let count = (rows.len() * columns.len()) as u32;
+
+                let sum: u32 = rows
+                    .clone()
+                    .flat_map(|row| &self.data[row * self.width..][columns.clone()])
+                    .map(|sample| *sample as u32)
+                    .sum();
+
+                output.push(((sum + count / 2) / count) as u8);
+            }
+        }
+
+        output
+    }
+
+    /// The source samples covered by output sample `idx` when `from` samples become `to`.
+    fn span(idx: usize, to: usize, from: usize) -> Range<usize> {
+        let start = (idx * from / to).min(from - 1);
+        let end = ((idx + 1) * from).div_ceil(to).clamp(start + 1, from);
+
+        start..end
+    }
+}
+
+/// The planes of every component of an image, before any upsampling or color conversion.
+#[derive(Debug)]
+pub struct Planes {
+    /// The dimensions of the image, which the plane of a component with the largest sampling
+    /// factors spans.
+    pub width: usize,
+    pub height: usize,
+    pub planes: Vec<Plane>,
+}
+
+impl Planes {
+    /// The Y plane at the full resolution of the image.
+    fn luma(&self) -> Vec<u8> {
+        self.planes[0].resample(self.width, self.height)
+    }
+
+    /// The Cb and Cr planes resampled to `width` x `height`. A grayscale image gets neutral
+    /// chroma.
+    fn chroma(&self, width: usize, height: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self.planes.as_slice() {
+            [_] => Ok((vec![128; width * height], vec![128; width * height])),
+            [_, cb, cr] => Ok((cb.resample(width, height), cr.resample(width, height))),
+            planes => Err(anyhow!(format!(
+                "expected Y, Cb and Cr planes. Got {} planes",
+                planes.len()
+            ))),
+        }
+    }
+
+    /// The dimensions of a chroma plane subsampled by two in both directions.
+    fn chroma_420(&self) -> (usize, usize) {
+        (self.width.div_ceil(2), self.height.div_ceil(2))
+    }
+
+    /// 4:2:0 with the full Y plane, followed by the U and then the V plane.
+    pub fn to_i420(&self) -> Result<Vec<u8>> {
+        let (width, height) = self.chroma_420();
+        let (cb, cr) = self.chroma(width, height)?;
+
+        let mut output = self.luma();
+        output.extend(cb);
+        output.extend(cr);
+
+        Ok(output)
+    }
+
+    /// 4:2:0 with the full Y plane, followed by a single plane of interleaved U and V samples.
+    pub fn to_nv12(&self) -> Result<Vec<u8>> {
+        let (width, height) = self.chroma_420();
+        let (cb, cr) = self.chroma(width, height)?;
+
+        let mut output = self.luma();
+        output.extend(cb.iter().zip(&cr).flat_map(|(cb, cr)| [*cb, *cr]));
+
+        Ok(output)
+    }
+
+    /// 4:2:2 packed as Y0 U Y1 V for every pair of pixels. The last Y sample of a row with an odd
+    /// number of pixels is repeated.
+    pub fn to_yuy2(&self) -> Result<Vec<u8>> {
+        let (width, height) = (self.width.div_ceil(2), self.height);
+        let (cb, cr) = self.chroma(width, height)?;
+        let luma = self.luma();
+
+        let mut output = Vec::with_capacity(width * height * 4);
+
+        for y in 0..height {
+            let row = &luma[y * self.width..(y + 1) * self.width];
+
+            for x in 0..width {
+                let (y0, y1) = (row[2 * x], row[(2 * x + 1).min(self.width - 1)]);
+                output.extend([y0, cb[y * width + x], y1, cr[y * width + x]]);
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn planes() -> Planes {
+        Planes {
+            width: 3,
+            height: 2,
+            planes: vec![
+                Plane {
+                    component_id: 1,
+                    width: 3,
+                    height: 2,
+                    data: vec![10, 20, 30, 40, 50, 60],
+                },
+                Plane {
+                    component_id: 2,
+                    width: 2,
+                    height: 1,
+                    data: vec![100, 110],
+                },
+                Plane {
+                    component_id: 3,
+                    width: 2,
+                    height: 1,
+                    data: vec![200, 210],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_resample() {
+        let plane = Plane {
+            component_id: 1,
+            width: 4,
+            height: 2,
+            data: vec![0, 2, 4, 6, 8, 10, 12, 14],
+        };
+
+        assert_eq!(plane.resample(2, 1), vec![5, 9]);
+        assert_eq!(plane.resample(8, 2)[..8], [0, 0, 2, 2, 4, 4, 6, 6]);
+        assert_eq!(plane.resample(4, 2), plane.data);
+    }
+
+    #[test]
+    fn test_packers() -> Result<()> {
+        let planes = planes();
+
+        assert_eq!(
+            planes.to_i420()?,
+            vec![10, 20, 30, 40, 50, 60, 100, 110, 200, 210]
+        );
+        assert_eq!(
+            planes.to_nv12()?,
+            vec![10, 20, 30, 40, 50, 60, 100, 200, 110, 210]
+        );
+        assert_eq!(
+            planes.to_yuy2()?,
+            vec![10, 100, 20, 200, 30, 110, 30, 210, 40, 100, 50, 200, 60, 110, 60, 210]
+        );
+
+        let grayscale = Planes {
+            planes: planes.planes[..1].to_vec(),
+            ..planes
+        };
+        assert_eq!(grayscale.to_i420()?[6..], [128; 4]);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::simd::Simd;
 
+use crate::entropy_decoder::EntropyDecoder;
 use crate::sample_precision::SamplePrecision;
 
 /// The set of 64 quantization values used to quantize the DCT coefficients
@@ -15,15 +16,20 @@ pub struct QuantizationTable {
 
     /// Specifies the kth element out of 64 elements, where k is the index in the zig-zag ordering
     /// of the DCT coefficients. The quantization elements shall be specified in zig-zag scan order.
-    pub(crate) quantization_table_element: Simd<u8, 64>,
+    /// They are stored here in natural (row-major) order, matching the decoded coefficients.
+    pub(crate) quantization_table_element: Simd<u16, 64>,
 }
 
 impl QuantizationTable {
-    pub(crate) fn from(qt_id: u8, qt_precision: u8, qt_data: Simd<u8, 64>) -> Self {
+    pub(crate) fn from(qt_id: u8, precision: SamplePrecision, qt_data: Simd<u16, 64>) -> Self {
+        let zigzag = qt_data.to_array();
+        let natural: [u16; 64] =
+            std::array::from_fn(|idx| zigzag[EntropyDecoder::ZIGZAG_TABLE[idx]]);
+
         QuantizationTable {
             table_id: qt_id,
-            precision: SamplePrecision::decode(qt_precision),
-            quantization_table_element: qt_data,
+            precision,
+            quantization_table_element: Simd::from_array(natural),
         }
     }
 }
@@ -0,0 +1,446 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+
+/// The number of bits of every channel the histogram of median cut keeps.
+const HISTOGRAM_BITS: usize = 5;
+
+/// The number of bits of every channel that the nearest colors of a palette are cached by.
+const CACHE_BITS: usize = 6;
+
+/// (Bayer) The thresholds of ordered dithering, out of 64.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// How the palette is chosen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PaletteMethod {
+    /// (libjpeg one-pass) A fixed cube with the given number of levels of red, green and blue,
+    /// evenly spaced. The image is read once, and every pixel mapped as it is read.
+    Cube([usize; 3]),
+
+    /// (libjpeg two-pass) At most the given number of colors, chosen by splitting a histogram of
+    /// the image at the median of its longest side until there are enough boxes.
+    MedianCut(usize),
+}
+
+/// How the error of mapping a pixel to the palette is spread.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dithering {
+    None,
+
+    /// Carries the error over to the neighbouring pixels to the right and below, in a
+    /// serpentine order.
+    FloydSteinberg,
+
+    /// Offsets every pixel by the threshold of an 8x8 Bayer matrix before mapping it.
+    Ordered,
+}
+
+/// An image of 8-bit indices into a palette of RGB colors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedImage {
+    pub width: usize,
+    pub height: usize,
+    pub palette: Vec<[u8; 3]>,
+    pub indices: Vec<u8>,
+}
+
+impl IndexedImage {
+    /// Looks every index up in the palette, returning interleaved RGB samples.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.indices
+            .iter()
+            .flat_map(|index| self.palette[*index as usize])
+            .collect()
+    }
+}
+
+/// Maps decoded RGB pixels to a palette of at most 256 colors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Quantizer {
+    method: PaletteMethod,
+    dithering: Dithering,
+}
+
+impl Quantizer {
+    pub fn new(method: PaletteMethod) -> Self {
+        Quantizer {
+            method,
+            dithering: Dithering::None,
+        }
+    }
+
+    pub fn dithering(mut self, dithering: Dithering) -> Self {
+        self.dithering = dithering;
+        self
+    }
+
+    /// Quantizes the interleaved RGB samples of a `width` x `height` image.
+    pub fn quantize(&self, width: usize, height: usize, rgb: &[u8]) -> Result<IndexedImage> {
+        if rgb.len() != width * height * 3 {
+            return Err(anyhow!(format!(
+                "expected {} samples in a {width}x{height} RGB image. Got {}",
+                width * height * 3,
+                rgb.len()
+            )));
+        }
+
+        let mut palette = match self.method {
+            PaletteMethod::Cube(levels) => {
+                if levels.iter().any(|n| *n < 2) || levels.iter().product::<usize>() > 256 {
+                    return Err(anyhow!(format!(
+                        "a color cube of {levels:?} levels does not fit in 256 colors"
+                    )));
+                }
+
+                Palette::cube(levels)
+            }
+            PaletteMethod::MedianCut(num_colors) => {
+                if !(1..=256).contains(&num_colors) {
+                    return Err(anyhow!(format!(
+                        "a palette of {num_colors} colors does not fit in 8-bit indices"
+                    )));
+                }
+
+                Palette::median_cut(rgb, num_colors)
+            }
+        };
+
+        let indices = match self.dithering {
+            Dithering::None => rgb
+                .chunks_exact(3)
+                .map(|pixel| palette.nearest([pixel[0], pixel[1], pixel[2]].map(i32::from)))
+                .collect(),
+            Dithering::Ordered => palette.ordered(width, rgb),
+            Dithering::FloydSteinberg => palette.floyd_steinberg(width, height, rgb),
+        };
+
+        Ok(IndexedImage {
+            width,
+            height,
+            palette: palette.colors,
+            indices,
+        })
+    }
+}
+
+struct Palette {
+    colors: Vec<[u8; 3]>,
+
+    /// The levels of every channel of a color cube, whose nearest color is found directly.
+    cube: Option<[usize; 3]>,
+
+    /// The nearest color of every cell of `CACHE_BITS` per channel, once it has been searched.
+    cache: Vec<Option<u8>>,
+}
+
+impl Palette {
+    /// Every combination of the levels, with red varying slowest.
+    fn cube(levels: [usize; 3]) -> Self {
+        let level = |channel: usize, i: usize| (i * 255 / (levels[channel] - 1)) as u8;
+
+        let mut colors = Vec::with_capacity(levels.iter().product());
+        for r in 0..levels[0] {
+            for g in 0..levels[1] {
+                for b in 0..levels[2] {
+                    colors.push([level(0, r), level(1, g), level(2, b)]);
+                }
+            }
+        }
+
+        Palette {
+            colors,
+            cube: Some(levels),
+            cache: vec![],
+        }
+    }
+
+    /// (Heckbert) Splits the box with the most pixels across the widest range until there are
+    /// `num_colors` boxes, or no box spans more than one cell of the histogram. Every color is
+    /// the mean of the pixels in its box.
+    fn median_cut(rgb: &[u8], num_colors: usize) -> Self {
+        let shift = 8 - HISTOGRAM_BITS;
+        let side = 1 << HISTOGRAM_BITS;
+        let cell = |[r, g, b]: [usize; 3]| (r * side + g) * side + b;
+
+        // the number of pixels in every cell, along with the sums of their samples.
+        let mut histogram = vec![(0u64, [0u64; 3]); side * side * side];
+        for pixel in rgb.chunks_exact(3) {
+            let entry = &mut histogram[cell([0, 1, 2].map(|c| pixel[c] as usize >> shift))];
+            entry.0 += 1;
+            (0..3).for_each(|c| entry.1[c] += pixel[c] as u64);
+        }
+
+        // the occupied cells, which the boxes hold ranges of once sorted along an axis.
+        let mut cells: Vec<[usize; 3]> = (0..side * side * side)
+            .filter(|index| histogram[*index].0 > 0)
+            .map(|index| [index / (side * side), index / side % side, index % side])
+            .collect();
+
+        let count =
+            |cells: &[[usize; 3]]| -> u64 { cells.iter().map(|c| histogram[cell(*c)].0).sum() };
+        let widest = |cells: &[[usize; 3]]| -> (usize, usize) {
+            (0..3)
+                .map(|axis| {
+                    let (min, max) = cells.iter().fold((side, 0), |(min, max), c| {
+                        (min.min(c[axis]), max.max(c[axis]))
+                    });
+                    (max - min, axis)
+                })
+                .max()
+                .unwrap()
+        };
+
+        let mut boxes: Vec<Range<usize>> = Vec::with_capacity(num_colors);
+        if !cells.is_empty() {
+            boxes.push(0..cells.len());
+        }
+
+        while boxes.len() < num_colors {
+            let splittable = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, range)| widest(&cells[(*range).clone()]).0 > 0)
+                .max_by_key(|(_, range)| count(&cells[(*range).clone()]));
+
+            let Some((index, _)) = splittable else {
+                break;
+            };
+
+            let range = boxes.swap_remove(index);
+            let (_, axis) = widest(&cells[range.clone()]);
+            cells[range.clone()].sort_by_key(|c| c[axis]);
+
+            // the first cell past half of the pixels, keeping both halves non-empty.
+            let half = count(&cells[range.clone()]).div_ceil(2);
+            let mut total = 0;
+            let mut median = range.start + 1;
+            for (i, c) in cells[range.clone()].iter().enumerate() {
+                total += histogram[cell(*c)].0;
+                if total >= half {
+                    median = range.start + i + 1;
+                    break;
+                }
+            }
+            let median = median.min(range.end - 1);
+
+            boxes.push(range.start..median);
+            boxes.push(median..range.end);
+        }
+
+        let colors = boxes
+            .iter()
+            .map(|range| {
+                let (count, sums) =
+                    cells[range.clone()]
+                        .iter()
+                        .fold((0, [0; 3]), |(count, sums), c| {
+                            let (n, s) = histogram[cell(*c)];
+                            (count + n, [0, 1, 2].map(|k| sums[k] + s[k]))
+                        });
+
+                sums.map(|sum| ((sum + count / 2) / count.max(1)) as u8)
+            })
+            .collect();
+
+        Palette {
+            colors,
+            cube: None,
+            cache: vec![None; 1 << (3 * CACHE_BITS)],
+        }
+    }
+
+    /// The index of the color closest to `pixel`, whose samples may lie outside [0, 255].
+    fn nearest(&mut self, pixel: [i32; 3]) -> u8 {
+        if let Some(levels) = self.cube {
+            let level = |c: usize| {
+                let steps = levels[c] as i32 - 1;
+                ((pixel[c].clamp(0, 255) * steps + 127) / 255) as usize
+            };
+
+            return ((level(0) * levels[1] + level(1)) * levels[2] + level(2)) as u8;
+        }
+
+        // (libjpeg jquant2.c) pixels that fall in the same cell share the color closest to the
+        // center of the cell, which is searched for the first time one is seen.
+        let shift = 8 - CACHE_BITS;
+        let [r, g, b] = pixel.map(|sample| sample.clamp(0, 255) as usize >> shift);
+        let key = (r << (2 * CACHE_BITS)) | (g << CACHE_BITS) | b;
+
+        if let Some(index) = self.cache[key] {
+            return index;
+        }
+
+        let center = [r, g, b].map(|cell| ((cell << shift) + (1 << shift >> 1)) as i32);
+        let index = self
+            .colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, color)| {
+                (0..3)
+                    .map(|c| (center[c] - color[c] as i32).pow(2))
+                    .sum::<i32>()
+            })
+            .map(|(index, _)| index as u8)
+            .unwrap_or(0);
+
+        self.cache[key] = Some(index);
+        index
+    }
+
+    /// The distance between neighbouring colors along every channel, which ordered dithering
+    /// spreads its thresholds over.
+    fn spacing(&self) -> [i32; 3] {
+        match self.cube {
+            Some(levels) => levels.map(|n| 255 / (n as i32 - 1)),
+            None => [(255.0 / (self.colors.len() as f64).cbrt()) as i32; 3],
+        }
+    }
+
+    fn ordered(&mut self, width: usize, rgb: &[u8]) -> Vec<u8> {
+        let spacing = self.spacing();
+
+        rgb.chunks_exact(3)
+            .enumerate()
+            .map(|(i, pixel)| {
+                let threshold = BAYER[(i / width) % 8][(i % width) % 8] as i32;
+                self.nearest(
+                    [0, 1, 2].map(|c| pixel[c] as i32 + (2 * threshold - 63) * spacing[c] / 128),
+                )
+            })
+            .collect()
+    }
+
+    fn floyd_steinberg(&mut self, width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+        let mut indices = vec![0; width * height];
+
+        // the errors carried into the current and next rows, with a pixel of margin either side.
+        let mut current = vec![[0i32; 3]; width + 2];
+        let mut next = vec![[0i32; 3]; width + 2];
+
+        for y in 0..height {
+            let columns: Vec<usize> = match y % 2 {
+                0 => (0..width).collect(),
+                _ => (0..width).rev().collect(),
+            };
+            let forward: isize = if y % 2 == 0 { 1 } else { -1 };
+
+            for x in columns {
+                let i = y * width + x;
+                let e = x + 1;
+
+                let pixel = [0, 1, 2].map(|c| {
+                    (rgb[i * 3 + c] as i32 + (current[e][c] + 8).div_euclid(16)).clamp(0, 255)
+                });
+                let index = self.nearest(pixel);
+                indices[i] = index;
+
+                let color = self.colors[index as usize];
+                let ahead = (e as isize + forward) as usize;
+                let behind = (e as isize - forward) as usize;
+
+                for c in 0..3 {
+                    let error = pixel[c] - color[c] as i32;
+                    current[ahead][c] += error * 7;
+                    next[behind][c] += error * 3;
+                    next[e][c] += error * 5;
+                    next[ahead][c] += error;
+                }
+            }
+
+            std::mem::swap(&mut current, &mut next);
+            next.iter_mut().for_each(|error| *error = [0; 3]);
+        }
+
+        indices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cube() -> Result<()> {
+        let quantizer = Quantizer::new(PaletteMethod::Cube([6, 7, 6]));
+
+        // the colors of the cube map onto themselves.
+        let rgb = [0, 0, 0, 255, 255, 255, 51, 85, 204, 102, 170, 153];
+        let image = quantizer.quantize(4, 1, &rgb)?;
+        assert_eq!(image.palette.len(), 252);
+        assert_eq!(image.to_rgb(), rgb);
+
+        assert_eq!(image.indices[0], 0);
+        assert_eq!(image.indices[1], 251);
+
+        assert!(Quantizer::new(PaletteMethod::Cube([7, 7, 7]))
+            .quantize(4, 1, &rgb)
+            .is_err());
+        assert!(quantizer.quantize(3, 1, &rgb).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_median_cut() -> Result<()> {
+        let colors = [[200, 30, 30], [30, 200, 30], [30, 30, 200], [250, 250, 250]];
+        let rgb: Vec<u8> = (0..64)
+            .flat_map(|i| colors[i % 4 * (i % 3).min(1)])
+            .collect();
+
+        // fewer colors than the palette allows are kept exactly.
+        let image = Quantizer::new(PaletteMethod::MedianCut(16)).quantize(8, 8, &rgb)?;
+        assert_eq!(image.palette.len(), 4);
+        assert_eq!(image.to_rgb(), rgb);
+
+        // with two colors, every pixel maps to the closer of them.
+        let image = Quantizer::new(PaletteMethod::MedianCut(2)).quantize(8, 8, &rgb)?;
+        assert_eq!(image.palette.len(), 2);
+
+        let distance = |pixel: &[u8], color: [u8; 3]| -> i32 {
+            (0..3)
+                .map(|c| (pixel[c] as i32 - color[c] as i32).pow(2))
+                .sum()
+        };
+        for (pixel, index) in rgb.chunks_exact(3).zip(&image.indices) {
+            let other = image.palette[1 - *index as usize];
+            assert!(distance(pixel, image.palette[*index as usize]) <= distance(pixel, other));
+        }
+
+        assert!(Quantizer::new(PaletteMethod::MedianCut(257))
+            .quantize(8, 8, &rgb)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dithering() -> Result<()> {
+        // a flat gray between black and white comes out as a mix of the two.
+        let rgb = vec![96; 16 * 16 * 3];
+        let cube = Quantizer::new(PaletteMethod::Cube([2, 2, 2]));
+
+        let plain = cube.quantize(16, 16, &rgb)?;
+        assert!(plain.indices.iter().all(|index| *index == 0));
+
+        for dithering in [Dithering::FloydSteinberg, Dithering::Ordered] {
+            let image = cube.dithering(dithering).quantize(16, 16, &rgb)?;
+            let white = image.indices.iter().filter(|index| **index == 7).count();
+            let black = image.indices.iter().filter(|index| **index == 0).count();
+
+            assert_eq!(white + black, 256, "{dithering:?}");
+            assert!((white as i32 - 96).abs() <= 8, "{dithering:?}: {white}");
+        }
+
+        Ok(())
+    }
+}
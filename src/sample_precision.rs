@@ -1,23 +1,65 @@
+use anyhow::{anyhow, Result};
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub(crate) enum SamplePrecision {
     EightBit,
+    TwelveBit,
     SixteenBit,
+
+    /// (H.1.2) The lossless process codes samples of any precision from 2 to 16 bits.
+    Other(u8),
 }
 
 impl SamplePrecision {
-    pub(crate) fn decode(b: u8) -> Self {
+    /// The precision of the Pq nibble of a quantization table, 0 for 8-bit and 1 for 16-bit values.
+    pub(crate) fn decode(b: u8) -> Result<Self> {
         match b {
-            0 => SamplePrecision::EightBit,
-            1 => SamplePrecision::SixteenBit,
-            _ => unreachable!(),
+            0 => Ok(SamplePrecision::EightBit),
+            1 => Ok(SamplePrecision::SixteenBit),
+            _ => Err(anyhow!(format!(
+                "expected a quantization table precision of 0 or 1. Got {b}"
+            ))),
         }
     }
 
-    pub(crate) fn parse(number_of_bits: u8) -> Self {
+    pub(crate) fn parse(number_of_bits: u8) -> Result<Self> {
         match number_of_bits {
-            8 => SamplePrecision::EightBit,
-            16 => SamplePrecision::SixteenBit,
-            _ => unreachable!(),
+            8 => Ok(SamplePrecision::EightBit),
+            12 => Ok(SamplePrecision::TwelveBit),
+            16 => Ok(SamplePrecision::SixteenBit),
+            2..=16 => Ok(SamplePrecision::Other(number_of_bits)),
+            _ => Err(anyhow!(format!(
+                "a sample precision of {number_of_bits} bits is outside of 2 to 16 bits"
+            ))),
+        }
+    }
+
+    /// The number of bits in every sample.
+    pub(crate) fn bits(&self) -> u8 {
+        match self {
+            SamplePrecision::EightBit => 8,
+            SamplePrecision::TwelveBit => 12,
+            SamplePrecision::SixteenBit => 16,
+            SamplePrecision::Other(bits) => *bits,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(SamplePrecision::parse(12)?, SamplePrecision::TwelveBit);
+        assert_eq!(SamplePrecision::parse(10)?.bits(), 10);
+        assert_eq!(SamplePrecision::parse(16)?.bits(), 16);
+        assert!(SamplePrecision::parse(1).is_err());
+        assert!(SamplePrecision::parse(17).is_err());
+
+        assert_eq!(SamplePrecision::decode(1)?, SamplePrecision::SixteenBit);
+        assert!(SamplePrecision::decode(2).is_err());
+
+        Ok(())
+    }
+}
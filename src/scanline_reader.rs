@@ -0,0 +1,713 @@
+use std::simd::prelude::*;
+
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::color_spaces::{self, ConversionMatrix, Sample};
+use crate::dequantizer::Dequantizer;
+use crate::entropy_decoder::{ComponentBlocks, EntropyDecoder};
+use crate::frame_header::{Component, FrameHeader};
+use crate::idct::IDCT;
+use crate::options::{CodedColorSpace, DecoderOptions, OutputColorSpace};
+use crate::planar::{Plane, Planes};
+use crate::upsampler::{Band, Upsampler};
+
+/// The width of a band of component samples, and the band itself.
+type ComponentBand = (usize, Vec<f32>);
+
+/// Hands out the rows of an image as they are decoded. Only the coefficients and samples of a
+/// single MCU row are held at a time, so memory use follows the width of the image rather than
+/// its area.
+pub struct ScanlineReader {
+    frame_header: FrameHeader,
+    entropy_decoder: EntropyDecoder,
+    dequantizer: Dequantizer,
+    idct: IDCT,
+    upsamplers: Vec<Upsampler>,
+    coded_color_space: CodedColorSpace,
+    output_color_space: OutputColorSpace,
+    conversion_matrix: ConversionMatrix,
+    thread_pool: Option<ThreadPool>,
+
+    /// The dimensions of the whole image once scaled.
+    image_width: usize,
+    image_height: usize,
+
+    /// The window of the image handed out, in scaled image coordinates.
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+
+    /// The MCU columns covering the window, and the first column of the window within them.
+    mcu_columns: Range<usize>,
+    x_offset: usize,
+
+    /// The output rows of the most recently decoded MCU row, clamped to the range of the
+    /// precision of the image but not yet rounded, and the image row of the first.
+    band: Vec<f32>,
+    band_start: usize,
+    band_rows: usize,
+
+    /// One upsampled row of every component across the MCU columns, reused from row to row.
+    rows: Vec<Vec<f32>>,
+
+    /// The number of components, counting from the first, that are dequantized, transformed and
+    /// upsampled. Grayscale output only needs the luminance, so the chroma of YCbCr images is
+    /// only entropy decoded. RGB images need every component.
+    reconstructed_components: usize,
+
+    /// Whether an upsampler reads the rows of the neighbouring MCU rows. If so, the last row of
+    /// every component in the previous MCU row is kept, and the next MCU row is transformed
+    /// ahead of time, along with its index.
+    needs_context: bool,
+    above_rows: Vec<Vec<f32>>,
+    lookahead: Option<(usize, Vec<ComponentBand>)>,
+
+    next_row: usize,
+    next_mcu_row: usize,
+}
+
+impl ScanlineReader {
+    pub(crate) fn new(
+        frame_header: FrameHeader,
+        entropy_decoder: EntropyDecoder,
+        dequantizer: Dequantizer,
+        coded_color_space: CodedColorSpace,
+        options: DecoderOptions,
+    ) -> Result<Self> {
+        let DecoderOptions {
+            output_color_space,
+            idct_method,
+            upsampling_method,
+            scale_factor,
+            ycbcr_matrix,
+            ycbcr_range,
+            num_threads,
+            ..
+        } = options;
+
+        if let CodedColorSpace::CMYK | CodedColorSpace::YCCK = coded_color_space {
+            return Err(anyhow!(format!(
+                "decoding {coded_color_space:?} images is not supported"
+            )));
+        }
+
+        let conversion_matrix =
+            ConversionMatrix::new(ycbcr_matrix, ycbcr_range, frame_header.precision.bits())?;
+
+        let thread_pool = match num_threads {
+            Some(num_threads) => Some(ThreadPoolBuilder::new().num_threads(num_threads).build()?),
+            None => None,
+        };
+
+        let idct = IDCT::new(frame_header.precision, idct_method, scale_factor);
+
+        let (width, height) = (
+            scale_factor.scale(frame_header.image_width),
+            scale_factor.scale(frame_header.image_height),
+        );
+
+        let (h_max, v_max) = frame_header.max_sampling_factors();
+
+        let upsamplers: Vec<_> = frame_header
+            .components
+            .iter()
+            .map(|c| {
+                Upsampler::new(
+                    upsampling_method,
+                    (h_max, v_max),
+                    (
+                        c.horizontal_scaling_factor as usize,
+                        c.vertical_scaling_factor as usize,
+                    ),
+                )
+            })
+            .collect();
+
+        let num_components = frame_header.components.len();
+        let reconstructed_components = match (coded_color_space, output_color_space) {
+            (CodedColorSpace::YCbCr, OutputColorSpace::Grayscale) => 1,
+            _ => num_components,
+        };
+        let needs_context = upsamplers[..reconstructed_components]
+            .iter()
+            .any(Upsampler::needs_context);
+
+        ScanlineReader {
+            frame_header,
+            entropy_decoder,
+            dequantizer,
+            idct,
+            upsamplers,
+            coded_color_space,
+            output_color_space,
+            conversion_matrix,
+            thread_pool,
+            image_width: width,
+            image_height: height,
+            x: 0,
+            y: 0,
+            width,
+            height,
+            mcu_columns: 0..0,
+            x_offset: 0,
+            band: vec![],
+            band_start: 0,
+            band_rows: 0,
+            rows: vec![vec![]; reconstructed_components],
+            reconstructed_components,
+            needs_context,
+            above_rows: vec![vec![]; num_components],
+            lookahead: None,
+            next_row: 0,
+            next_mcu_row: 0,
+        }
+        .crop(0, 0, width, height)
+    }
+
+    /// Restricts the rows handed out to a window of the image. Only the MCUs covering the window
+    /// are reconstructed, and the ones above it and to its sides are only entropy decoded as far
+    /// as needed to reach it.
+    pub(crate) fn crop(mut self, x: usize, y: usize, width: usize, height: usize) -> Result<Self> {
+        if width == 0
+            || height == 0
+            || x + width > self.image_width
+            || y + height > self.image_height
+        {
+            return Err(anyhow!(
+                "the region {width}x{height} at ({x}, {y}) does not fit in a {}x{} image",
+                self.image_width,
+                self.image_height
+            ));
+        }
+
+        // an MCU on every side of the window is reconstructed when the upsamplers filter across
+        // it, so that the window matches the same pixels of the whole image.
+        let context = self.needs_context as usize;
+
+        let (mcu_width, mcu_height) = self.mcu_dimensions();
+        let mcu_columns = (x / mcu_width).saturating_sub(context)
+            ..((x + width).div_ceil(mcu_width) + context).min(self.frame_header.mcus_per_line());
+        let covered_width =
+            (mcu_columns.len() * mcu_width).min(self.image_width - mcu_columns.start * mcu_width);
+
+        self.x_offset = x - mcu_columns.start * mcu_width;
+        self.mcu_columns = mcu_columns;
+        self.rows
+            .iter_mut()
+            .for_each(|row| row.resize(covered_width, 0.0));
+
+        (self.x, self.y, self.width, self.height) = (x, y, width, height);
+        self.next_mcu_row = (y / mcu_height).saturating_sub(context);
+
+        Ok(self)
+    }
+
+    /// The width and height of an MCU in scaled image samples.
+    fn mcu_dimensions(&self) -> (usize, usize) {
+        let (h_max, v_max) = self.frame_header.max_sampling_factors();
+        (h_max * self.idct.block_size, v_max * self.idct.block_size)
+    }
+
+    /// The width and height of a component in scaled samples, leaving out the padding of the
+    /// blocks around it.
+    fn component_dimensions(&self, component: &Component) -> (usize, usize) {
+        let n = self.idct.block_size;
+        let (width, height) = component.dimensions(&self.frame_header);
+        (width.div_ceil(8 / n), height.div_ceil(8 / n))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn color_space(&self) -> OutputColorSpace {
+        self.output_color_space
+    }
+
+    /// The nominal number of bits in every sample, which is the precision the image was coded
+    /// at. Samples read by `read_scanlines_u16` range from 0 to 2^bit_depth - 1.
+    pub fn bit_depth(&self) -> u8 {
+        self.frame_header.precision.bits()
+    }
+
+    /// The number of samples in one row of output, which is the number of bytes in a row of
+    /// `read_scanlines`.
+    pub fn row_bytes(&self) -> usize {
+        self.width * self.output_color_space.num_channels()
+    }
+
+    /// The index of the next row `read_scanlines` will write.
+    pub fn next_scanline(&self) -> usize {
+        self.next_row
+    }
+
+    /// Writes as many of the remaining rows as fit into `buffer`, each starting `stride` bytes
+    /// after the previous one. Returns the number of rows written, which is zero once every row
+    /// of the image has been read. Samples of more than 8 bits are scaled down to 8 bits.
+    pub fn read_scanlines(&mut self, buffer: &mut [u8], stride: usize) -> Result<usize> {
+        let row_bytes = self.row_bytes();
+        if stride < row_bytes {
+            return Err(anyhow!(
+                "a stride of {stride} bytes cannot hold a row of {row_bytes} bytes"
+            ));
+        }
+
+        let scale = 255.0 / self.conversion_matrix.max();
+        self.read_rows(buffer, stride, |output, band| {
+            color_spaces::scale_samples(band, scale, 255.0, output)
+        })
+    }
+
+    /// Writes rows as in `read_scanlines`, keeping every sample at the precision of the image,
+    /// as reported by `bit_depth`. Each row starts `stride` samples after the previous one.
+    pub fn read_scanlines_u16(&mut self, buffer: &mut [u16], stride: usize) -> Result<usize> {
+        let row_samples = self.row_bytes();
+        if stride < row_samples {
+            return Err(anyhow!(
+                "a stride of {stride} samples cannot hold a row of {row_samples} samples"
+            ));
+        }
+
+        let max = self.conversion_matrix.max();
+        self.read_rows(buffer, stride, |output, band| {
+            color_spaces::scale_samples(band, 1.0, max, output)
+        })
+    }
+
+    /// Writes rows as in `read_scanlines`, with every sample scaled to [0, 1] without being
+    /// rounded. Each row starts `stride` samples after the previous one.
+    pub fn read_scanlines_f32(&mut self, buffer: &mut [f32], stride: usize) -> Result<usize> {
+        let row_samples = self.row_bytes();
+        if stride < row_samples {
+            return Err(anyhow!(
+                "a stride of {stride} samples cannot hold a row of {row_samples} samples"
+            ));
+        }
+
+        let scale = 1.0 / self.conversion_matrix.max();
+        self.read_rows(buffer, stride, |output, band| {
+            color_spaces::scale_samples(band, scale, 1.0, output)
+        })
+    }
+
+    /// Decodes rows until `buffer` is full or the window is exhausted, handing the band samples
+    /// of each row to `write` along with its place in `buffer`.
+    fn read_rows<T>(
+        &mut self,
+        buffer: &mut [T],
+        stride: usize,
+        write: impl Fn(&mut [T], &[f32]),
+    ) -> Result<usize> {
+        let row_samples = self.row_bytes();
+        let mut rows_read = 0;
+
+        while self.next_row < self.height {
+            let offset = rows_read * stride;
+            if offset + row_samples > buffer.len() {
+                break;
+            }
+
+            let image_row = self.y + self.next_row;
+            while image_row >= self.band_start + self.band_rows {
+                self.decode_mcu_row()?;
+            }
+
+            let band_offset = (image_row - self.band_start) * row_samples;
+            write(
+                &mut buffer[offset..offset + row_samples],
+                &self.band[band_offset..band_offset + row_samples],
+            );
+
+            self.next_row += 1;
+            rows_read += 1;
+        }
+
+        Ok(rows_read)
+    }
+
+    /// Decodes every component at its own resolution, without upsampling or color converting
+    /// it. The reader must not have been cropped or read from before, and the components must be
+    /// YCbCr or grayscale.
+    pub(crate) fn read_planes(&mut self) -> Result<Planes> {
+        let whole_image = (0, 0, self.image_width, self.image_height);
+        if self.next_row > 0
+            || self.next_mcu_row > 0
+            || (self.x, self.y, self.width, self.height) != whole_image
+        {
+            return Err(anyhow!(
+                "the planes are only decoded from a reader that was not cropped or read from"
+            ));
+        }
+
+        // the packers of `Planes` take three planes to be Y, Cb and Cr.
+        if !matches!(
+            self.coded_color_space,
+            CodedColorSpace::YCbCr | CodedColorSpace::Grayscale
+        ) {
+            return Err(anyhow!(format!(
+                "planes are only decoded from YCbCr or grayscale images. Got {:?}",
+                self.coded_color_space
+            )));
+        }
+
+        let n = self.idct.block_size;
+        let scale = 255.0 / self.conversion_matrix.max();
+
+        let mut planes: Vec<_> = self
+            .frame_header
+            .components
+            .iter()
+            .map(|component| {
+                let (width, height) = self.component_dimensions(component);
+
+                Plane {
+                    component_id: component.component_id,
+                    width,
+                    height,
+                    data: vec![0; width * height],
+                }
+            })
+            .collect();
+
+        while self.next_mcu_row < self.frame_header.mcu_rows() {
+            let mcu_row = self.next_mcu_row;
+            let num_components = self.frame_header.components.len();
+            let bands = self.transform_mcu_row(num_components)?;
+
+            for ((plane, (band_width, band)), component) in planes
+                .iter_mut()
+                .zip(&bands)
+                .zip(&self.frame_header.components)
+            {
+                let band_height = component.vertical_scaling_factor as usize * n;
+                let first_row = mcu_row * band_height;
+
+                for y in first_row..(first_row + band_height).min(plane.height) {
+                    let row = &band[(y - first_row) * band_width..][..plane.width];
+
+                    plane.data[y * plane.width..(y + 1) * plane.width]
+                        .iter_mut()
+                        .zip(row)
+                        .for_each(|(sample, value)| *sample = u8::clamp_from(value * scale, 255.0));
+                }
+            }
+        }
+
+        Ok(Planes {
+            width: self.image_width,
+            height: self.image_height,
+            planes,
+        })
+    }
+
+    /// Dequantizes and transforms the window of the next MCU row, for the first `num_components`
+    /// components. Returns the band of samples of each, along with its width. Every component is
+    /// entropy decoded regardless.
+    fn transform_mcu_row(&mut self, num_components: usize) -> Result<Vec<ComponentBand>> {
+        let mcu_row = self.next_mcu_row;
+        let blocks = self.entropy_decoder.decode_mcu_row(
+            &self.frame_header,
+            mcu_row,
+            self.mcu_columns.clone(),
+        )?;
+
+        let (components, dequantizer, idct) =
+            (&self.frame_header.components, &self.dequantizer, &self.idct);
+
+        let transform = || {
+            components
+                .iter()
+                .zip(&blocks)
+                .take(num_components)
+                .map(|(component, blocks)| transform_band(component, blocks, dequantizer, idct))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let bands = match &self.thread_pool {
+            Some(thread_pool) => thread_pool.install(transform),
+            None => transform(),
+        }?;
+
+        self.next_mcu_row += 1;
+
+        Ok(bands)
+    }
+
+    /// Dequantizes, transforms, upsamples and color converts the window of the next MCU row.
+    fn decode_mcu_row(&mut self) -> Result<()> {
+        let (mcu_row, bands) = match self.lookahead.take() {
+            Some(lookahead) => lookahead,
+            None => {
+                let mcu_row = self.next_mcu_row;
+                (
+                    mcu_row,
+                    self.transform_mcu_row(self.reconstructed_components)?,
+                )
+            }
+        };
+
+        // the first rows of the next MCU row are needed to upsample the last rows of this one.
+        if self.needs_context && mcu_row + 1 < self.frame_header.mcu_rows() {
+            let next_mcu_row = self.next_mcu_row;
+            let bands = self.transform_mcu_row(self.reconstructed_components)?;
+            self.lookahead = Some((next_mcu_row, bands));
+        }
+
+        let (_, mcu_height) = self.mcu_dimensions();
+
+        self.band_start = mcu_row * mcu_height;
+        self.band_rows = mcu_height.min(self.image_height - self.band_start);
+
+        let row_samples = self.row_bytes();
+        self.band.resize(self.band_rows * row_samples, 0.0);
+
+        // the columns and rows of every band that fall inside the component rather than in the
+        // padding of its blocks, which the upsamplers never read.
+        let n = self.idct.block_size;
+        let extents: Vec<_> = self.frame_header.components[..bands.len()]
+            .iter()
+            .zip(&bands)
+            .map(|(component, (width, samples))| {
+                let (component_width, component_height) = self.component_dimensions(component);
+                let first_column =
+                    self.mcu_columns.start * component.horizontal_scaling_factor as usize * n;
+                let first_row = mcu_row * component.vertical_scaling_factor as usize * n;
+
+                (
+                    (component_width - first_column).min(*width),
+                    (component_height - first_row).min(samples.len() / width),
+                )
+            })
+            .collect();
+
+        let component_bands: Vec<_> = bands
+            .iter()
+            .zip(&extents)
+            .enumerate()
+            .map(|(c, ((width, samples), &(columns, rows)))| {
+                let last_row = &samples[(rows - 1) * width..][..*width];
+
+                Band {
+                    samples,
+                    width: *width,
+                    columns,
+                    rows,
+                    above: match self.above_rows[c].is_empty() {
+                        true => &samples[..*width],
+                        false => &self.above_rows[c],
+                    },
+                    below: match &self.lookahead {
+                        Some((_, next)) => &next[c].1[..*width],
+                        None => last_row,
+                    },
+                }
+            })
+            .collect();
+
+        for y in 0..self.band_rows {
+            for (c, band) in component_bands.iter().enumerate() {
+                self.upsamplers[c].upsample_row(band, y, &mut self.rows[c]);
+            }
+
+            let rows: Vec<_> = self
+                .rows
+                .iter()
+                .map(|row| &row[self.x_offset..self.x_offset + self.width])
+                .collect();
+
+            let output = &mut self.band[y * row_samples..(y + 1) * row_samples];
+            write_row(
+                &rows,
+                self.coded_color_space,
+                self.output_color_space,
+                &self.conversion_matrix,
+                output,
+            )?;
+        }
+
+        if self.needs_context {
+            for (above_row, (width, samples)) in self.above_rows.iter_mut().zip(&bands) {
+                above_row.clear();
+                above_row.extend_from_slice(&samples[samples.len() - width..]);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Dequantizes and transforms the blocks of a component that fall in one MCU row. Returns the
+/// width of the band in samples, along with the band itself.
+fn transform_band(
+    component: &Component,
+    blocks: &ComponentBlocks,
+    dequantizer: &Dequantizer,
+    idct: &IDCT,
+) -> Result<ComponentBand> {
+    let n = idct.block_size;
+    let band_width = blocks.blocks_per_line * n;
+
+    let transformed = (0..blocks.blocks_per_column * blocks.blocks_per_line)
+        .into_par_iter()
+        .map(|idx| {
+            let (row, column) = (idx / blocks.blocks_per_line, idx % blocks.blocks_per_line);
+            let coefficients =
+                dequantizer.dequantize(component.component_id, blocks.block(row, column))?;
+
+            Ok(idct.perform_idct(coefficients.cast::<f32>()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut band = vec![0f32; band_width * blocks.blocks_per_column * n];
+
+    for (idx, samples) in transformed.iter().enumerate() {
+        let (row, column) = (idx / blocks.blocks_per_line, idx % blocks.blocks_per_line);
+
+        for y in 0..n {
+            let start = (row * n + y) * band_width + column * n;
+            band[start..start + n].copy_from_slice(&samples[y * n..(y + 1) * n]);
+        }
+    }
+
+    Ok((band_width, band))
+}
+
+/// Writes one row of upsampled components from the color space they were coded in into the
+/// output color space.
+fn write_row<T: Sample>(
+    rows: &[&[f32]],
+    coded_color_space: CodedColorSpace,
+    output_color_space: OutputColorSpace,
+    matrix: &ConversionMatrix,
+    output: &mut [T],
+) -> Result<()> {
+    let max = matrix.max();
+
+    match (coded_color_space, output_color_space) {
+        (CodedColorSpace::Grayscale | CodedColorSpace::YCbCr, OutputColorSpace::Grayscale) => {
+            output
+                .iter_mut()
+                .zip(rows[0])
+                .for_each(|(sample, y)| *sample = T::clamp_from(matrix.luma(*y), max));
+        }
+        (CodedColorSpace::Grayscale, OutputColorSpace::RGB) => {
+            output
+                .chunks_exact_mut(3)
+                .zip(rows[0])
+                .for_each(|(pixel, y)| {
+                    pixel.fill(T::clamp_from(matrix.luma(*y), max));
+                });
+        }
+        (CodedColorSpace::Grayscale, OutputColorSpace::YCbCr) => {
+            let center = T::clamp_from(matrix.center(), max);
+            output
+                .chunks_exact_mut(3)
+                .zip(rows[0])
+                .for_each(|(pixel, y)| {
+                    pixel.copy_from_slice(&[T::clamp_from(*y, max), center, center]);
+                });
+        }
+        (CodedColorSpace::YCbCr, OutputColorSpace::RGB) => {
+            color_spaces::convert_ycbcr_to_rgb(rows[0], rows[1], rows[2], matrix, output);
+        }
+        (CodedColorSpace::YCbCr, OutputColorSpace::YCbCr)
+        | (CodedColorSpace::RGB, OutputColorSpace::RGB) => {
+            output
+                .chunks_exact_mut(3)
+                .enumerate()
+                .for_each(|(x, pixel)| {
+                    for (c, sample) in pixel.iter_mut().enumerate() {
+                        *sample = T::clamp_from(rows[c][x], max);
+                    }
+                });
+        }
+        (CodedColorSpace::RGB, OutputColorSpace::YCbCr) => {
+            output
+                .chunks_exact_mut(3)
+                .enumerate()
+                .for_each(|(x, pixel)| {
+                    let ycbcr = matrix.rgb_to_ycbcr(rows[0][x], rows[1][x], rows[2][x]);
+                    for (sample, value) in pixel.iter_mut().zip(ycbcr) {
+                        *sample = T::clamp_from(value, max);
+                    }
+                });
+        }
+        (CodedColorSpace::RGB, OutputColorSpace::Grayscale) => {
+            output.iter_mut().enumerate().for_each(|(x, sample)| {
+                *sample = T::clamp_from(matrix.rgb_luma(rows[0][x], rows[1][x], rows[2][x]), max)
+            });
+        }
+        (coded_color_space, _) => {
+            return Err(anyhow!(format!(
+                "decoding {coded_color_space:?} images is not supported"
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::Decoder;
+
+    use super::*;
+
+    #[test]
+    fn test_read_scanlines_with_stride() -> Result<()> {
+        let mut decoder = Decoder::from_file_path("mike.jpg")?;
+        let image = decoder.decode()?;
+
+        let mut scanline_reader = decoder.scanlines()?;
+        let row_bytes = scanline_reader.row_bytes();
+        let stride = row_bytes + 5;
+
+        // a buffer that holds two rows and a bit, so only two rows are written at a time.
+        let mut buffer = vec![0u8; 2 * stride + row_bytes - 1];
+
+        let mut y = 0;
+        loop {
+            let rows_read = scanline_reader.read_scanlines(&mut buffer, stride)?;
+            if rows_read == 0 {
+                break;
+            }
+
+            assert!(rows_read <= 2);
+            for row in 0..rows_read {
+                assert_eq!(
+                    &buffer[row * stride..row * stride + row_bytes],
+                    &image.data[(y + row) * row_bytes..(y + row + 1) * row_bytes]
+                );
+            }
+
+            y += rows_read;
+            assert_eq!(scanline_reader.next_scanline(), y);
+        }
+
+        assert_eq!(y, 763);
+        assert!(scanline_reader
+            .read_scanlines(&mut buffer, row_bytes - 1)
+            .is_err());
+
+        // the planes cover the whole image, so they are not decoded halfway through.
+        assert!(scanline_reader.read_planes().is_err());
+        assert!(decoder
+            .scanlines()?
+            .crop(8, 8, 16, 16)?
+            .read_planes()
+            .is_err());
+        assert_eq!(decoder.scanlines()?.read_planes()?.planes.len(), 3);
+
+        Ok(())
+    }
+}
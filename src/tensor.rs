@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+
+use crate::orientation::Orientation;
+
+/// The order the dimensions of a tensor are laid out in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TensorLayout {
+    /// Height, width and channels: the channels of every pixel are interleaved.
+    HWC,
+
+    /// Channels, height and width: every channel is a plane of its own.
+    CHW,
+}
+
+/// How `Decoder::decode_tensor` lays out and normalizes samples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorOptions {
+    pub(crate) layout: TensorLayout,
+
+    /// The mean and standard deviation of every channel, in units of [0, 1] samples.
+    pub(crate) normalization: Option<(Vec<f32>, Vec<f32>)>,
+
+    /// The rectangle (x, y, width, height) to decode, as in `Decoder::decode_region`.
+    pub(crate) crop: Option<(usize, usize, usize, usize)>,
+}
+
+impl Default for TensorOptions {
+    fn default() -> Self {
+        TensorOptions {
+            layout: TensorLayout::HWC,
+            normalization: None,
+            crop: None,
+        }
+    }
+}
+
+impl TensorOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn layout(mut self, layout: TensorLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Maps every sample `s` of channel `c`, scaled to [0, 1], to `(s - mean[c]) / std[c]`.
+    pub fn normalize(mut self, mean: &[f32], std: &[f32]) -> Self {
+        self.normalization = Some((mean.to_vec(), std.to_vec()));
+        self
+    }
+
+    pub fn crop(mut self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        self.crop = Some((x, y, width, height));
+        self
+    }
+
+    /// The multiplier and offset that normalize every channel, so that each sample only takes a
+    /// multiplication and a subtraction.
+    pub(crate) fn scales(&self, num_channels: usize) -> Result<Vec<(f32, f32)>> {
+        let Some((mean, std)) = &self.normalization else {
+            return Ok(vec![(1.0, 0.0); num_channels]);
+        };
+
+        if mean.len() != num_channels || std.len() != num_channels {
+            return Err(anyhow!(format!(
+                "a mean of {} and a standard deviation of {} channels do not normalize {num_channels} channels",
+                mean.len(),
+                std.len()
+            )));
+        }
+
+        if let Some(std) = std.iter().find(|std| std.is_nan() || **std <= 0.0) {
+            return Err(anyhow!(format!(
+                "a standard deviation of {std} does not normalize a channel"
+            )));
+        }
+
+        Ok(mean
+            .iter()
+            .zip(std)
+            .map(|(mean, std)| (1.0 / std, mean / std))
+            .collect())
+    }
+
+    /// Normalizes interleaved samples in [0, 1] and lays them out as a tensor.
+    pub(crate) fn tensor(
+        &self,
+        (width, height, num_channels): (usize, usize, usize),
+        samples: &[f32],
+        orientation: Orientation,
+    ) -> Result<Tensor> {
+        let scales = self.scales(num_channels)?;
+        let mut data = vec![0f32; samples.len()];
+
+        match self.layout {
+            TensorLayout::HWC => {
+                for (pixel, output) in samples
+                    .chunks_exact(num_channels)
+                    .zip(data.chunks_exact_mut(num_channels))
+                {
+                    for ((output, sample), (scale, offset)) in
+                        output.iter_mut().zip(pixel).zip(&scales)
+                    {
+                        *output = sample * scale - offset;
+                    }
+                }
+            }
+            TensorLayout::CHW => {
+                for (c, (plane, (scale, offset))) in data
+                    .chunks_exact_mut(width * height)
+                    .zip(&scales)
+                    .enumerate()
+                {
+                    for (output, sample) in plane
+                        .iter_mut()
+                        .zip(samples.iter().skip(c).step_by(num_channels))
+                    {
+                        *output = sample * scale - offset;
+                    }
+                }
+            }
+        }
+
+        let shape = match self.layout {
+            TensorLayout::HWC => [height, width, num_channels],
+            TensorLayout::CHW => [num_channels, height, width],
+        };
+
+        Ok(Tensor {
+            layout: self.layout,
+            shape,
+            data,
+            orientation,
+        })
+    }
+}
+
+/// Normalized f32 samples, ready to be handed to a model.
+#[derive(Debug)]
+pub struct Tensor {
+    pub layout: TensorLayout,
+
+    /// The size of every dimension, in the order of the layout.
+    pub shape: [usize; 3],
+    pub data: Vec<f32>,
+
+    /// The EXIF orientation that was applied to the samples, as in `Image::orientation`.
+    pub orientation: Orientation,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tensor() -> Result<()> {
+        // a 2x1 image of two RGB pixels.
+        let samples = [0.0, 0.5, 1.0, 0.25, 0.75, 0.5];
+
+        let hwc = TensorOptions::new().tensor((2, 1, 3), &samples, Orientation::Normal)?;
+        assert_eq!(hwc.shape, [1, 2, 3]);
+        assert_eq!(hwc.data, samples);
+
+        let chw = TensorOptions::new()
+            .layout(TensorLayout::CHW)
+            .normalize(&[0.5, 0.5, 0.5], &[0.5, 0.25, 2.0])
+            .tensor((2, 1, 3), &samples, Orientation::Normal)?;
+        assert_eq!(chw.shape, [3, 1, 2]);
+        assert_eq!(chw.data, [-1.0, -0.5, 0.0, 1.0, 0.25, 0.0]);
+
+        let options = TensorOptions::new().normalize(&[0.5; 3], &[0.5, 0.0, 1.0]);
+        assert!(options
+            .tensor((2, 1, 3), &samples, Orientation::Normal)
+            .is_err());
+        let options = TensorOptions::new().normalize(&[0.5; 3], &[0.5; 3]);
+        assert!(options
+            .tensor((6, 1, 1), &samples, Orientation::Normal)
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use crate::options::UpsamplingMethod;
+
+/// The rows of a component that fall in one MCU row, along with the rows just outside of it.
+pub(crate) struct Band<'a> {
+    pub(crate) samples: &'a [f32],
+    pub(crate) width: usize,
+
+    /// The number of samples in every row, and of rows, that fall inside the component. The rest
+    /// pad the band out to whole blocks, and are never read (libjpeg `downsampled_width`).
+    pub(crate) columns: usize,
+    pub(crate) rows: usize,
+
+    /// The last row of the MCU row above, or the first row of this one at the top of the image.
+    pub(crate) above: &'a [f32],
+
+    /// The first row of the MCU row below, or the last row inside the component at the bottom.
+    pub(crate) below: &'a [f32],
+}
+
+impl Band<'_> {
+    /// Row `y` of the band, reaching into the rows outside of it by one row either way.
+    fn row(&self, y: isize) -> &[f32] {
+        match y {
+            -1 => self.above,
+            y if y >= self.rows as isize => self.below,
+            y => &self.samples[y as usize * self.width..][..self.width],
+        }
+    }
+}
+
+/// Brings the samples of a subsampled component up to the sampling grid of the image.
+pub(crate) struct Upsampler {
+    method: UpsamplingMethod,
+
+    /// (Hmax / Hi, Vmax / Vi) of the component being upsampled.
+    horizontal_ratio: usize,
+    vertical_ratio: usize,
+}
+
+impl Upsampler {
+    pub(crate) fn new(
+        method: UpsamplingMethod,
+        (h_max, v_max): (usize, usize),
+        (h, v): (usize, usize),
+    ) -> Self {
+        let (horizontal_ratio, vertical_ratio) = (h_max / h, v_max / v);
+
+        // the triangle filter only covers chroma at half the horizontal resolution.
+        let method = match (method, horizontal_ratio, vertical_ratio) {
+            (UpsamplingMethod::Fancy, 2, 1 | 2) => UpsamplingMethod::Fancy,
+            _ => UpsamplingMethod::NearestNeighbor,
+        };
+
+        Upsampler {
+            method,
+            horizontal_ratio,
+            vertical_ratio,
+        }
+    }
+
+    /// Whether the rows of the neighbouring MCU rows are read.
+    pub(crate) fn needs_context(&self) -> bool {
+        self.method == UpsamplingMethod::Fancy && self.vertical_ratio == 2
+    }
+
+    /// Writes row `y` of the upsampled component into `output`, where `y` is relative to the
+    /// first row of the band.
+    pub(crate) fn upsample_row(&self, band: &Band, y: usize, output: &mut [f32]) {
+        let last = band.columns - 1;
+
+        match self.method {
+            UpsamplingMethod::NearestNeighbor => {
+                let row = band.row((y / self.vertical_ratio) as isize);
+
+                output.iter_mut().enumerate().for_each(|(x, sample)| {
+                    *sample = row[(x / self.horizontal_ratio).min(last)];
+                });
+            }
+            UpsamplingMethod::Fancy => {
+                // (libjpeg jdsample.c) Every output sample is 3/4 of the nearest input sample
+                // and 1/4 of the next nearest, in each direction it is upsampled in. Past the
+                // last column or row of the component, the last one is repeated.
+                let nearest = (y / self.vertical_ratio) as isize;
+                let next = match (self.vertical_ratio, y % 2) {
+                    (2, 0) => nearest - 1,
+                    (2, _) => nearest + 1,
+                    _ => nearest,
+                };
+                let (nearest, next) = (band.row(nearest), band.row(next));
+                let column_sum = |i: usize| (3.0 * nearest[i] + next[i]) / 4.0;
+
+                output.iter_mut().enumerate().for_each(|(x, sample)| {
+                    let i = (x / 2).min(last);
+                    let j = match x % 2 {
+                        0 => i.saturating_sub(1),
+                        _ => (i + 1).min(last),
+                    };
+
+                    *sample = (3.0 * column_sum(i) + column_sum(j)) / 4.0;
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn band<'a>(samples: &'a [f32], width: usize, above: &'a [f32], below: &'a [f32]) -> Band<'a> {
+        Band {
+            samples,
+            width,
+            columns: width,
+            rows: samples.len() / width,
+            above,
+            below,
+        }
+    }
+
+    #[test]
+    fn test_nearest_neighbor() {
+        let upsampler = Upsampler::new(UpsamplingMethod::NearestNeighbor, (2, 2), (1, 1));
+        let samples = [1.0, 2.0, 3.0, 4.0];
+        let band = band(&samples, 2, &samples[..2], &samples[2..]);
+
+        let mut output = [0.0; 4];
+        upsampler.upsample_row(&band, 1, &mut output);
+        assert_eq!(output, [1.0, 1.0, 2.0, 2.0]);
+
+        upsampler.upsample_row(&band, 2, &mut output);
+        assert_eq!(output, [3.0, 3.0, 4.0, 4.0]);
+    }
+
+    #[test]
+    fn test_fancy() {
+        let h2v1 = Upsampler::new(UpsamplingMethod::Fancy, (2, 1), (1, 1));
+        assert!(!h2v1.needs_context());
+
+        let samples = [0.0, 40.0, 80.0];
+        let row = band(&samples, 3, &samples, &samples);
+
+        let mut output = [0.0; 6];
+        h2v1.upsample_row(&row, 0, &mut output);
+        assert_eq!(output, [0.0, 10.0, 30.0, 50.0, 70.0, 80.0]);
+
+        // the rows above and below come from the neighbouring MCU rows.
+        let h2v2 = Upsampler::new(UpsamplingMethod::Fancy, (2, 2), (1, 1));
+        assert!(h2v2.needs_context());
+
+        let samples = [16.0, 16.0, 32.0, 32.0];
+        let band = band(&samples, 2, &[0.0, 0.0], &[64.0, 64.0]);
+
+        let rows: Vec<[f32; 4]> = (0..4)
+            .map(|y| {
+                let mut output = [0.0; 4];
+                h2v2.upsample_row(&band, y, &mut output);
+                output
+            })
+            .collect();
+        assert_eq!(rows, vec![[12.0; 4], [20.0; 4], [28.0; 4], [40.0; 4]]);
+
+        // chroma at other ratios is replicated.
+        let h4v1 = Upsampler::new(UpsamplingMethod::Fancy, (4, 1), (1, 1));
+        let mut output = [0.0; 12];
+        h4v1.upsample_row(&row, 0, &mut output);
+        assert_eq!(output[..5], [0.0, 0.0, 0.0, 0.0, 40.0]);
+    }
+
+    #[test]
+    fn test_edges() {
+        // a 3x1 component padded out to 4x2, at the bottom of the image.
+        let samples = [0.0, 40.0, 80.0, 255.0, 255.0, 255.0, 255.0, 255.0];
+        let band = Band {
+            samples: &samples,
+            width: 4,
+            columns: 3,
+            rows: 1,
+            above: &samples[..4],
+            below: &samples[..4],
+        };
+
+        let h2v2 = Upsampler::new(UpsamplingMethod::Fancy, (2, 2), (1, 1));
+        for y in 0..2 {
+            let mut output = [0.0; 8];
+            h2v2.upsample_row(&band, y, &mut output);
+            assert_eq!(output[..6], [0.0, 10.0, 30.0, 50.0, 70.0, 80.0]);
+        }
+
+        let nearest = Upsampler::new(UpsamplingMethod::NearestNeighbor, (2, 2), (1, 1));
+        let mut output = [0.0; 8];
+        nearest.upsample_row(&band, 1, &mut output);
+        assert_eq!(output, [0.0, 0.0, 40.0, 40.0, 80.0, 80.0, 80.0, 80.0]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+/// The XMP packets of an image. Writers move what does not fit in one APP1 segment into the
+/// extended packet, which the standard packet refers to by its GUID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Xmp {
+    pub standard: String,
+    pub extended: Option<String>,
+}
+
+impl Xmp {
+    pub(crate) const IDENTIFIER: &'static [u8] = b"http://ns.adobe.com/xap/1.0/\0";
+    pub(crate) const EXTENSION_IDENTIFIER: &'static [u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+    /// Reads the standard packet from the payload of an APP1 segment that starts with the XMP
+    /// namespace.
+    pub(crate) fn parse(segment: &[u8]) -> Result<Self> {
+        let packet = segment
+            .strip_prefix(Self::IDENTIFIER)
+            .ok_or(anyhow!("expected an XMP namespace"))?;
+
+        let standard = String::from_utf8(packet.to_vec())
+            .map_err(|e| anyhow!(format!("the XMP packet is not UTF-8: {e}")))?;
+
+        Ok(Xmp {
+            standard,
+            extended: None,
+        })
+    }
+
+    /// (XMP Part 3 1.1.3.1) The GUID of the extended packet, from the `xmpNote:HasExtendedXMP`
+    /// property of the standard packet.
+    pub fn extended_guid(&self) -> Option<&str> {
+        let (_, rest) = self.standard.split_once("xmpNote:HasExtendedXMP")?;
+        let start = rest.find(|c: char| c.is_ascii_hexdigit())?;
+        let guid = rest.get(start..start + 32)?;
+
+        guid.chars().all(|c| c.is_ascii_hexdigit()).then_some(guid)
+    }
+
+    /// (XMP Part 3 1.1.3.1) Reassembles the extended packet from the chunks of the APP1 segments
+    /// that start with the extension namespace. Each chunk carries the GUID of its packet, the
+    /// full length of the packet and its own offset within it. Only the chunks with the GUID
+    /// named by the standard packet are used, and that GUID has to be the MD5 digest of the
+    /// reassembled packet.
+    pub(crate) fn assemble_extended(&mut self, segments: &[&[u8]]) -> Result<()> {
+        let Some(guid) = self.extended_guid().map(str::to_ascii_uppercase) else {
+            return Ok(());
+        };
+
+        let mut length = None;
+        let mut chunks = BTreeMap::new();
+
+        for segment in segments {
+            let data = segment
+                .strip_prefix(Self::EXTENSION_IDENTIFIER)
+                .ok_or(anyhow!("expected an extended XMP namespace"))?;
+
+            if data.len() < 40 {
+                return Err(anyhow!("expected the header of an extended XMP chunk"));
+            }
+
+            if !data[..32].eq_ignore_ascii_case(guid.as_bytes()) {
+                continue;
+            }
+
+            let full_length = u32::from_be_bytes(data[32..36].try_into().unwrap()) as usize;
+            let offset = u32::from_be_bytes(data[36..40].try_into().unwrap()) as usize;
+
+            if *length.get_or_insert(full_length) != full_length {
+                return Err(anyhow!("extended XMP chunks disagree on the packet length"));
+            }
+
+            chunks.insert(offset, &data[40..]);
+        }
+
+        let length = length.ok_or(anyhow!(format!("expected the extended XMP packet {guid}")))?;
+
+        let mut packet = Vec::with_capacity(length);
+        for (offset, chunk) in chunks {
+            if offset != packet.len() || offset + chunk.len() > length {
+                return Err(anyhow!(format!(
+                    "the extended XMP chunk at offset {offset} does not continue the packet"
+                )));
+            }
+
+            packet.extend(chunk);
+        }
+
+        if packet.len() != length {
+            return Err(anyhow!(format!(
+                "expected {length} bytes in the extended XMP packet. Got {}",
+                packet.len()
+            )));
+        }
+
+        let digest: String = md5(&packet).iter().map(|b| format!("{b:02X}")).collect();
+        if digest != guid {
+            return Err(anyhow!(format!(
+                "the MD5 digest {digest} of the extended XMP packet does not match its GUID {guid}"
+            )));
+        }
+
+        self.extended = Some(
+            String::from_utf8(packet)
+                .map_err(|e| anyhow!(format!("the extended XMP packet is not UTF-8: {e}")))?,
+        );
+
+        Ok(())
+    }
+}
+
+/// (RFC 1321) The MD5 digest of `data`.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+    let sines: Vec<u32> = (1..=64)
+        .map(|i| ((i as f64).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64).wrapping_mul(8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(sines[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+
+        for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn extension_chunk(guid: &str, length: usize, offset: usize, chunk: &[u8]) -> Vec<u8> {
+        [
+            Xmp::EXTENSION_IDENTIFIER,
+            guid.as_bytes(),
+            &(length as u32).to_be_bytes(),
+            &(offset as u32).to_be_bytes(),
+            chunk,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_md5() {
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            hex(md5(b"The quick brown fox jumps over the lazy dog")),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(hex(md5(&[b'a'; 200])), "887f30b43b2867f4a9accceee7d16e6c");
+    }
+
+    #[test]
+    fn test_assemble_extended() -> Result<()> {
+        let extended = "<x:xmpmeta><rdf:RDF><xmp:Rating>5</xmp:Rating></rdf:RDF></x:xmpmeta>";
+        let guid: String = md5(extended.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect();
+
+        let standard = format!(r#"<rdf:Description xmpNote:HasExtendedXMP="{guid}"/>"#);
+        let segment = [Xmp::IDENTIFIER, standard.as_bytes()].concat();
+
+        let mut xmp = Xmp::parse(&segment)?;
+        assert_eq!(xmp.standard, standard);
+        assert_eq!(xmp.extended_guid(), Some(guid.as_str()));
+
+        let data = extended.as_bytes();
+        let first = extension_chunk(&guid, data.len(), 0, &data[..30]);
+        let second = extension_chunk(&guid, data.len(), 30, &data[30..]);
+        let other = extension_chunk(&"0".repeat(32), 4, 0, b"????");
+
+        // the chunks may come in any order, interleaved with those of other packets.
+        xmp.assemble_extended(&[&second, &other, &first])?;
+        assert_eq!(xmp.extended.as_deref(), Some(extended));
+
+        // a chunk is missing.
+        assert!(xmp.assemble_extended(&[&second]).is_err());
+
+        // the packet does not match its GUID.
+        let mut tampered = second.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(xmp.assemble_extended(&[&first, &tampered]).is_err());
+
+        Ok(())
+    }
+}